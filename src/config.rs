@@ -1,26 +1,76 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
 use std::net::AddrParseError;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use toml;
 
-use std::net::SocketAddr;
+use openssl::error::ErrorStack;
+use openssl::x509::X509;
+
+use resolve::{ClientSubnet, TrustAnchor};
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
 pub type ConfigResult<T> = Result<T, ConfigError>;
 
 lazy_static! {
-    static ref DEFAULT_DNS_SERVERS: Vec<SocketAddr> =
-        vec!["8.8.8.8:53".parse().unwrap(), "8.8.4.4:53".parse().unwrap(),];
+    static ref DEFAULT_DNS_SERVERS: Vec<Upstream> = vec![
+        Upstream::new("8.8.8.8:53".parse().unwrap()),
+        Upstream::new("8.8.4.4:53".parse().unwrap()),
+    ];
     static ref DEFAULT_TIMEOUT_RETRIES: u32 = 10;
     static ref DEFAULT_QPS: u32 = 500;
+    static ref DEFAULT_EXPAND_LIMIT: u64 = 65536;
+    static ref DEFAULT_EDNS_PAYLOAD: u16 = 1500;
+    static ref DEFAULT_MAX_CNAME_CHAIN: usize = 8;
+    // Deduplication is off unless asked for, its memory grows with the input
+    static ref DEFAULT_DEDUP_CAPACITY: u64 = 0;
+    static ref DEFAULT_QPS_FLOOR: u32 = 50;
+    static ref DEFAULT_QPS_CEILING: u32 = 5000;
+    static ref DEFAULT_CONSENSUS_SERVERS: usize = 3;
+    // Names with stable addresses under their operators' control
+    static ref DEFAULT_QUALIFY_NAMES: Vec<(String, Vec<IpAddr>)> = vec![
+        (
+            "dns.google".to_owned(),
+            vec!["8.8.8.8".parse().unwrap(), "8.8.4.4".parse().unwrap()],
+        ),
+        (
+            "one.one.one.one".to_owned(),
+            vec!["1.1.1.1".parse().unwrap(), "1.0.0.1".parse().unwrap()],
+        ),
+        (
+            "a.root-servers.net".to_owned(),
+            vec!["198.41.0.4".parse().unwrap()],
+        ),
+    ];
     pub static ref CONFIG: Arc<RwLock<Config>> = Arc::new(RwLock::new(Config::new()));
 }
 
 #[derive(Debug)]
 pub struct Config {
-    dns_list: Vec<SocketAddr>,
+    dns_list: Vec<Upstream>,
     qps: u32,
     timeout_retries: u32,
+    expand_limit: u64,
+    force_tcp: bool,
+    tls_ca: Option<Vec<u8>>,
+    edns_payload: u16,
+    dnssec_ok: bool,
+    nsid: bool,
+    client_subnet: Option<ClientSubnet>,
+    iterative: bool,
+    validate: bool,
+    trust_anchor: TrustAnchor,
+    cname_chain: bool,
+    max_cname_chain: usize,
+    dedup_capacity: u64,
+    adaptive: Option<AdaptiveRate>,
+    consensus: Option<Consensus>,
+    qualify_names: Vec<(String, Vec<IpAddr>)>,
 }
 
 impl Default for Config {
@@ -29,6 +79,22 @@ impl Default for Config {
             dns_list: DEFAULT_DNS_SERVERS.clone(),
             qps: *DEFAULT_QPS,
             timeout_retries: *DEFAULT_TIMEOUT_RETRIES,
+            expand_limit: *DEFAULT_EXPAND_LIMIT,
+            force_tcp: false,
+            tls_ca: None,
+            edns_payload: *DEFAULT_EDNS_PAYLOAD,
+            dnssec_ok: false,
+            nsid: false,
+            client_subnet: None,
+            iterative: false,
+            validate: false,
+            trust_anchor: TrustAnchor::default(),
+            cname_chain: false,
+            max_cname_chain: *DEFAULT_MAX_CNAME_CHAIN,
+            dedup_capacity: *DEFAULT_DEDUP_CAPACITY,
+            adaptive: None,
+            consensus: None,
+            qualify_names: DEFAULT_QUALIFY_NAMES.clone(),
         }
     }
 }
@@ -46,28 +112,178 @@ impl Config {
         self.qps
     }
 
-    pub fn dns_list(&self) -> &[SocketAddr] {
+    pub fn dns_list(&self) -> &[Upstream] {
         &self.dns_list
     }
 
+    pub fn set_dns_list(&mut self, dns_list: Vec<Upstream>) {
+        self.dns_list = dns_list;
+    }
+
+    pub fn expand_limit(&self) -> u64 {
+        self.expand_limit
+    }
+
+    pub fn force_tcp(&self) -> bool {
+        self.force_tcp
+    }
+
+    /// PEM encoded CA certificates trusted for DNS-over-TLS in addition to the system ones
+    pub fn tls_ca(&self) -> Option<&[u8]> {
+        self.tls_ca.as_ref().map(Vec::as_slice)
+    }
+
+    /// UDP payload size advertised in the EDNS0 OPT record
+    pub fn edns_payload(&self) -> u16 {
+        self.edns_payload
+    }
+
+    /// Whether to set the DNSSEC OK bit in queries
+    pub fn dnssec_ok(&self) -> bool {
+        self.dnssec_ok
+    }
+
+    /// Whether to request the server's NSID (RFC 5001)
+    pub fn nsid(&self) -> bool {
+        self.nsid
+    }
+
+    /// EDNS Client Subnet sent with queries that don't specify their own
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.client_subnet
+    }
+
+    /// Resolve from root hints instead of asking `dns_list` servers
+    pub fn iterative(&self) -> bool {
+        self.iterative
+    }
+
+    pub fn set_iterative(&mut self, iterative: bool) {
+        self.iterative = iterative;
+    }
+
+    /// Validate DNSSEC chain of trust of the answers
+    pub fn validate(&self) -> bool {
+        self.validate
+    }
+
+    pub fn set_validate(&mut self, validate: bool) {
+        self.validate = validate;
+    }
+
+    /// Trust anchor the chain of trust starts from
+    pub fn trust_anchor(&self) -> &TrustAnchor {
+        &self.trust_anchor
+    }
+
+    /// Record CNAME chains in the output and chase the ones upstream left unfinished
+    pub fn cname_chain(&self) -> bool {
+        self.cname_chain
+    }
+
+    pub fn set_cname_chain(&mut self, cname_chain: bool) {
+        self.cname_chain = cname_chain;
+    }
+
+    /// Longest CNAME chain followed before giving up on a name
+    pub fn max_cname_chain(&self) -> usize {
+        self.max_cname_chain
+    }
+
+    /// Number of distinct input names the deduplication filter is sized for, 0 when disabled
+    pub fn dedup_capacity(&self) -> u64 {
+        self.dedup_capacity
+    }
+
+    /// Bounds of per-server rate when it adapts to timeouts and errors
+    pub fn adaptive(&self) -> Option<AdaptiveRate> {
+        self.adaptive
+    }
+
+    /// Number of servers every name is sent to and how many of them must agree on the answer
+    pub fn consensus(&self) -> Option<Consensus> {
+        self.consensus
+    }
+
+    /// Names every server must answer with some of the given addresses and nothing else to qualify
+    pub fn qualify_names(&self) -> &[(String, Vec<IpAddr>)] {
+        &self.qualify_names
+    }
+
     pub fn parse(&mut self, string: &str) -> ConfigResult<()> {
+        // `dns` entry is either an address or a table with limits for the server
+        #[derive(Deserialize, Debug)]
+        #[serde(untagged)]
+        enum Dns {
+            Addr(String),
+            Limited {
+                addr: String,
+                qps: Option<u32>,
+                weight: Option<u32>,
+                max_in_flight: Option<usize>,
+            },
+        }
+
         #[derive(Deserialize, Debug)]
         struct Config {
-            dns: Option<Vec<String>>,
+            dns: Option<Vec<Dns>>,
             retry: Option<u32>,
             queries_per_second: Option<u32>,
+            expand_limit: Option<u64>,
+            force_tcp: Option<bool>,
+            tls_ca: Option<String>,
+            edns_payload: Option<u16>,
+            dnssec_ok: Option<bool>,
+            nsid: Option<bool>,
+            client_subnet: Option<String>,
+            trust_anchor: Option<String>,
+            max_cname_chain: Option<usize>,
+            dedup_capacity: Option<u64>,
+            adaptive: Option<bool>,
+            consensus: Option<bool>,
+            consensus_servers: Option<usize>,
+            consensus_quorum: Option<usize>,
+            qps_floor: Option<u32>,
+            qps_ceiling: Option<u32>,
+            qualify_names: Option<BTreeMap<String, Vec<String>>>,
         }
 
         let mut cfg_fmt: Config = toml::from_str(string)?;
 
-        if let Some(mut dns_fmt_vec) = cfg_fmt.dns.take() {
+        if let Some(dns_fmt_vec) = cfg_fmt.dns.take() {
             let mut dns_servers = Vec::new();
 
-            for dns in &mut dns_fmt_vec {
-                if !dns.contains(':') {
-                    dns.push_str(":53")
-                }
-                dns_servers.push(dns.parse()?);
+            for dns in dns_fmt_vec {
+                let upstream = match dns {
+                    Dns::Addr(addr) => Upstream::new(addr.parse()?),
+                    Dns::Limited {
+                        addr,
+                        qps,
+                        weight,
+                        max_in_flight,
+                    } => {
+                        // Zero would never let a query through
+                        if qps == Some(0) || weight == Some(0) || max_in_flight == Some(0) {
+                            return Err(ConfigError::InvalidValue(format!(
+                                "qps, weight and max_in_flight of {:?} must be positive",
+                                addr
+                            )));
+                        }
+                        Upstream {
+                            server: addr.parse()?,
+                            qps: qps,
+                            weight: weight.unwrap_or(1),
+                            max_in_flight: max_in_flight,
+                        }
+                    }
+                };
+                dns_servers.push(upstream);
+            }
+
+            if dns_servers.is_empty() {
+                return Err(ConfigError::InvalidValue(
+                    "dns list must not be empty".to_owned(),
+                ));
             }
 
             debug!("{:?}", dns_servers);
@@ -83,14 +299,309 @@ impl Config {
             self.qps = qps;
         }
 
+        if let Some(expand_limit) = cfg_fmt.expand_limit {
+            self.expand_limit = expand_limit;
+        }
+
+        if let Some(force_tcp) = cfg_fmt.force_tcp {
+            self.force_tcp = force_tcp;
+        }
+
+        if let Some(tls_ca) = cfg_fmt.tls_ca {
+            let mut pem = Vec::new();
+            File::open(&tls_ca)?.read_to_end(&mut pem)?;
+            // Make sure the file holds certificates before any connection is made
+            X509::stack_from_pem(&pem)?;
+            self.tls_ca = Some(pem);
+        }
+
+        if let Some(edns_payload) = cfg_fmt.edns_payload {
+            // RFC 6891 has servers treat sizes below 512 as 512, such a setting is a mistake
+            if edns_payload < 512 {
+                return Err(ConfigError::InvalidValue(format!(
+                    "edns_payload must be at least 512, got {}",
+                    edns_payload
+                )));
+            }
+            self.edns_payload = edns_payload;
+        }
+
+        if let Some(dnssec_ok) = cfg_fmt.dnssec_ok {
+            self.dnssec_ok = dnssec_ok;
+        }
+
+        if let Some(nsid) = cfg_fmt.nsid {
+            self.nsid = nsid;
+        }
+
+        if let Some(client_subnet) = cfg_fmt.client_subnet {
+            self.client_subnet = Some(client_subnet.parse().map_err(ConfigError::InvalidValue)?);
+        }
+
+        if let Some(trust_anchor) = cfg_fmt.trust_anchor {
+            let mut anchor = String::new();
+            File::open(&trust_anchor)?.read_to_string(&mut anchor)?;
+            self.trust_anchor = TrustAnchor::parse(&anchor).map_err(ConfigError::InvalidValue)?;
+        }
+
+        if let Some(max_cname_chain) = cfg_fmt.max_cname_chain {
+            if max_cname_chain == 0 {
+                return Err(ConfigError::InvalidValue(
+                    "max_cname_chain must be positive".to_owned(),
+                ));
+            }
+            self.max_cname_chain = max_cname_chain;
+        }
+
+        if let Some(dedup_capacity) = cfg_fmt.dedup_capacity {
+            self.dedup_capacity = dedup_capacity;
+        }
+
+        if cfg_fmt.adaptive.unwrap_or(false) {
+            let floor = cfg_fmt.qps_floor.unwrap_or(*DEFAULT_QPS_FLOOR);
+            let ceiling = cfg_fmt.qps_ceiling.unwrap_or(*DEFAULT_QPS_CEILING);
+            if floor == 0 || floor > ceiling {
+                return Err(ConfigError::InvalidValue(format!(
+                    "qps_floor must be positive and not above qps_ceiling, got {} and {}",
+                    floor, ceiling
+                )));
+            }
+            self.adaptive = Some(AdaptiveRate {
+                floor: floor,
+                ceiling: ceiling,
+            });
+        }
+
+        if cfg_fmt.consensus.unwrap_or(false) {
+            let servers = cfg_fmt
+                .consensus_servers
+                .unwrap_or(*DEFAULT_CONSENSUS_SERVERS);
+            // Majority by default
+            let quorum = cfg_fmt.consensus_quorum.unwrap_or(servers / 2 + 1);
+            if servers < 2 || quorum == 0 || quorum > servers {
+                return Err(ConfigError::InvalidValue(format!(
+                    "consensus_servers must be at least 2 and consensus_quorum between 1 and it, got {} and {}",
+                    servers, quorum
+                )));
+            }
+            self.consensus = Some(Consensus {
+                servers: servers,
+                quorum: quorum,
+            });
+        }
+
+        if let Some(qualify_names) = cfg_fmt.qualify_names {
+            let mut names = Vec::new();
+            for (name, addrs) in qualify_names {
+                if addrs.is_empty() {
+                    return Err(ConfigError::InvalidValue(format!(
+                        "qualify_names entry {:?} must list its addresses",
+                        name
+                    )));
+                }
+                let addrs = addrs
+                    .iter()
+                    .map(|addr| addr.parse())
+                    .collect::<Result<Vec<IpAddr>, _>>()?;
+                names.push((name, addrs));
+            }
+            self.qualify_names = names;
+        }
+
         Ok(())
     }
 }
 
+/// HTTP method for DNS-over-HTTPS queries, RFC 8484 allows both
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+/// DNS transport used to reach a nameserver
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    /// DNS-over-TLS with the name to verify server certificate against
+    Tls(String),
+    /// DNS-over-HTTPS endpoint URL, host name for certificate verification and HTTP method
+    Https {
+        url: String,
+        host: String,
+        method: HttpMethod,
+    },
+}
+
+/// Upstream nameserver as configured in the `dns` list:
+/// `8.8.8.8`, `[2001:4860:4860::8888]:53`, `tls://1.1.1.1:853#cloudflare-dns.com`
+/// or `https://dns.google/dns-query#get`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NameServer {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+}
+
+impl NameServer {
+    pub fn udp(addr: SocketAddr) -> Self {
+        NameServer {
+            addr: addr,
+            transport: Transport::Udp,
+        }
+    }
+
+    // Socket address with optional port, IPv6 addresses with port are taken in brackets
+    fn parse_addr(addr: &str, default_port: u16) -> ConfigResult<SocketAddr> {
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Ok(addr),
+            Err(_) => Ok(SocketAddr::new(addr.parse::<IpAddr>()?, default_port)),
+        }
+    }
+
+    // `https://host[:port][/path][#get|#post]`, host is resolved with the system resolver
+    fn parse_https(s: &str) -> ConfigResult<Self> {
+        let mut parts = s["https://".len()..].splitn(2, '#');
+        let url = parts.next().unwrap_or("");
+
+        let method = match parts.next().map(str::to_lowercase) {
+            None => HttpMethod::Post,
+            Some(ref method) if method == "post" => HttpMethod::Post,
+            Some(ref method) if method == "get" => HttpMethod::Get,
+            Some(method) => {
+                return Err(ConfigError::NameServerError(format!(
+                    "unknown DNS-over-HTTPS method {:?} in {:?}, expected get or post",
+                    method, s
+                )))
+            }
+        };
+
+        let (authority, path) = match url.find('/') {
+            Some(idx) => (&url[..idx], &url[idx..]),
+            None => (url, "/dns-query"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority[idx..].contains(']') => {
+                let port = authority[idx + 1..].parse::<u16>().map_err(|_| {
+                    ConfigError::NameServerError(format!("invalid port in {:?}", s))
+                })?;
+                (&authority[..idx], port)
+            }
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+            ConfigError::NameServerError(format!(
+                "failed to resolve DNS-over-HTTPS host {:?}",
+                host
+            ))
+        })?;
+
+        Ok(NameServer {
+            addr: addr,
+            transport: Transport::Https {
+                url: format!("https://{}{}", authority, path),
+                host: host.to_owned(),
+                method: method,
+            },
+        })
+    }
+}
+
+/// Per-server queries per second bounds of the adaptive rate
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AdaptiveRate {
+    pub floor: u32,
+    pub ceiling: u32,
+}
+
+/// Every name is sent to `servers` distinct servers, the answer is taken when `quorum` of them agree
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Consensus {
+    pub servers: usize,
+    pub quorum: usize,
+}
+
+/// `dns` list entry, a nameserver with its share of queries:
+/// `{ addr = "10.0.0.53", qps = 5000, weight = 3, max_in_flight = 1000 }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub server: NameServer,
+    /// Queries per second sent to the server at most, on top of the overall `queries_per_second`
+    pub qps: Option<u32>,
+    /// Share of queries relative to the other servers
+    pub weight: u32,
+    /// Names being resolved with the server at once at most
+    pub max_in_flight: Option<usize>,
+}
+
+impl Upstream {
+    /// Server without limits of its own
+    pub fn new(server: NameServer) -> Self {
+        Upstream {
+            server: server,
+            qps: None,
+            weight: 1,
+            max_in_flight: None,
+        }
+    }
+}
+
+impl FromStr for NameServer {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> ConfigResult<Self> {
+        let s = s.trim();
+
+        if s.starts_with("tls://") {
+            let mut parts = s["tls://".len()..].splitn(2, '#');
+            let addr = Self::parse_addr(parts.next().unwrap_or(""), 853)?;
+            let name = match parts.next() {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => {
+                    return Err(ConfigError::NameServerError(format!(
+                        "TLS nameserver {:?} needs a certificate name, e.g. tls://1.1.1.1:853#cloudflare-dns.com",
+                        s
+                    )))
+                }
+            };
+
+            Ok(NameServer {
+                addr: addr,
+                transport: Transport::Tls(name),
+            })
+        } else if s.starts_with("https://") {
+            Self::parse_https(s)
+        } else {
+            Ok(NameServer::udp(Self::parse_addr(s, 53)?))
+        }
+    }
+}
+
+impl fmt::Display for NameServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.transport {
+            Transport::Udp => write!(f, "{}", self.addr),
+            Transport::Tls(ref name) => write!(f, "tls://{}#{}", self.addr, name),
+            Transport::Https {
+                ref url,
+                method: HttpMethod::Get,
+                ..
+            } => write!(f, "{}#get", url),
+            Transport::Https { ref url, .. } => write!(f, "{}", url),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     AddrParseError(AddrParseError),
     TomlParseError(toml::de::Error),
+    NameServerError(String),
+    InvalidValue(String),
+    IoError(io::Error),
+    TlsError(ErrorStack),
 }
 
 impl Error for ConfigError {
@@ -98,6 +609,10 @@ impl Error for ConfigError {
         match *self {
             ConfigError::AddrParseError(ref err) => err.description(),
             ConfigError::TomlParseError(ref err) => err.description(),
+            ConfigError::NameServerError(ref err) => err,
+            ConfigError::InvalidValue(ref err) => err,
+            ConfigError::IoError(ref err) => err.description(),
+            ConfigError::TlsError(ref err) => err.description(),
         }
     }
 }
@@ -119,3 +634,15 @@ impl From<toml::de::Error> for ConfigError {
         ConfigError::TomlParseError(err)
     }
 }
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::IoError(err)
+    }
+}
+
+impl From<ErrorStack> for ConfigError {
+    fn from(err: ErrorStack) -> Self {
+        ConfigError::TlsError(err)
+    }
+}
@@ -3,6 +3,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
 extern crate toml;
 
 #[macro_use]
@@ -11,22 +12,33 @@ extern crate log;
 extern crate clap;
 extern crate env_logger;
 
+extern crate atty;
+extern crate bytes;
+extern crate chrono;
 extern crate crossbeam;
+extern crate data_encoding;
 extern crate futures;
+extern crate h2;
+extern crate http;
 extern crate indicatif;
 extern crate num_cpus;
+extern crate openssl;
 extern crate tokio_core;
+extern crate tokio_openssl;
 extern crate trust_dns;
 
 mod config;
+mod output;
 mod resolve;
 use config::*;
+use output::*;
+use resolve::qualify::qualify;
 use resolve::*;
 
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{self, Read, Write};
-use std::path::Path;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter;
 use std::thread;
 use std::time::Duration;
 
@@ -40,16 +52,30 @@ use clap::{App, Arg};
 use env_logger::LogBuilder;
 use log::{LogLevelFilter, LogRecord};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{DrawTarget, ProgressBar, ProgressStyle};
+
+// Command line arguments
+struct Args {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    qtypes: Vec<QueryType>,
+    format: Option<OutputFormat>,
+    columns: Vec<Column>,
+    failed: Option<String>,
+    retry_failed: Option<String>,
+    sort: bool,
+    qualify: Option<String>,
+    disagreements: Option<String>,
+}
 
 #[rustfmt::skip]
-fn process_args() -> (Vec<String>, Vec<String>, Vec<QueryType>) {
+fn process_args() -> Args {
     let app = App::new("Batch Resolve")
         .about("Fast asynchronous DNS batch resolver")
         .version(crate_version!())
         .author(crate_authors!())
         .arg(Arg::with_name("inputs")
-            .help("Input file")
+            .help("Input file, - for stdin. Stdin is used when no inputs are given")
             .short("i")
             .long("in")
             .value_name("INPUT")
@@ -57,17 +83,49 @@ fn process_args() -> (Vec<String>, Vec<String>, Vec<QueryType>) {
             .multiple(true)
             .number_of_values(1))
         .arg(Arg::with_name("outputs")
-            .help("Output file")
+            .help("Output file, - for stdout. Stdout is used when no outputs are given")
             .short("o")
             .long("out")
             .value_name("OUTPUT")
             .multiple(true)
             .number_of_values(1))
+        .arg(Arg::with_name("format")
+            .help("Output format: plain, csv, tsv or json. Chosen by output file extension by default")
+            .long("format")
+            .validator(|format| format.parse::<OutputFormat>().map(|_| ()))
+            .value_name("FORMAT")
+            .takes_value(true))
+        .arg(Arg::with_name("columns")
+            .help("Comma separated CSV and TSV columns: name, qtype, value, ttl, rcode, server, latency, attempts, subnet, dnssec, chain")
+            .long("columns")
+            .validator(|columns| Column::parse_list(&columns).map(|_| ()))
+            .value_name("COLUMNS")
+            .takes_value(true))
+        .arg(Arg::with_name("failed")
+            .help("File to list names that failed to resolve with the reasons")
+            .long("failed")
+            .value_name("FAILED")
+            .takes_value(true))
+        .arg(Arg::with_name("disagreements")
+            .help("File to list names the servers did not agree on in consensus mode with the answer of each server")
+            .long("disagreements")
+            .value_name("DISAGREEMENTS")
+            .takes_value(true))
+        .arg(Arg::with_name("retry_failed")
+            .help("Resolve again names from a failures file that failed with timeout or SERVFAIL, merging results into outputs")
+            .long("retry-failed")
+            .value_name("FAILED")
+            .takes_value(true)
+            .requires("failed")
+            .conflicts_with("inputs"))
+        .arg(Arg::with_name("sort")
+            .help("Sort and deduplicate output files when resolving is done")
+            .long("sort"))
         .arg(Arg::with_name("queries")
-            .help("Query type")
+            .help("Query type: IANA mnemonic (A, MX, CAA...) or numeric TYPEnnn code")
             .short("q")
             .long("query")
-            .possible_values(&QueryType::variants())
+            .validator(|qtype| qtype.parse::<QueryType>().map(|_| ()))
             .value_name("QUERY_TYPE")
             .multiple(true)
             .number_of_values(1))
@@ -77,6 +135,21 @@ fn process_args() -> (Vec<String>, Vec<String>, Vec<QueryType>) {
             .long("config")
             .value_name("FILE")
             .takes_value(true))
+        .arg(Arg::with_name("iterative")
+            .help("Resolve iteratively from the root servers instead of using configured DNS servers")
+            .long("iterative"))
+        .arg(Arg::with_name("validate")
+            .help("Validate DNSSEC signatures and report secure, insecure, bogus or indeterminate status")
+            .long("validate"))
+        .arg(Arg::with_name("cname_chain")
+            .help("Record CNAME chains in the output and follow them to the end")
+            .long("cname-chain"))
+        .arg(Arg::with_name("qualify")
+            .help("Check DNS servers with known and nonexistent names first, drop the ones that fail or lie and write the verdicts to REPORT")
+            .long("qualify")
+            .value_name("REPORT")
+            .takes_value(true)
+            .conflicts_with("iterative"))
         .arg(Arg::with_name("verbosity")
             .help("Level of verbosity (-v -vv -vvv)")
             .short("v")
@@ -103,19 +176,91 @@ fn process_args() -> (Vec<String>, Vec<String>, Vec<QueryType>) {
     let inputs  = values_t!(matches.values_of("inputs"),  String).unwrap_or(vec![]);
     let outputs = values_t!(matches.values_of("outputs"), String).unwrap_or(vec![]);
     let qtypes  = values_t!(matches.values_of("queries"), QueryType).unwrap_or(vec![]);
+    let format  = matches.value_of("format").map(|format| format.parse().unwrap());
+    let columns = matches.value_of("columns")
+        .map(|columns| Column::parse_list(columns).unwrap())
+        .unwrap_or_else(|| DEFAULT_COLUMNS.to_vec());
+    let failed  = matches.value_of("failed").map(String::from);
+    let retry_failed = matches.value_of("retry_failed").map(String::from);
+    let sort    = matches.is_present("sort");
+    let qualify = matches.value_of("qualify").map(String::from);
+    let disagreements = matches.value_of("disagreements").map(String::from);
+
+    // Without files names come from stdin and results go to stdout, as in `subfinder | batch_resolve -q A`
+    let inputs = if inputs.is_empty() && retry_failed.is_none() {
+        vec![STDIO.to_owned(); qtypes.len()]
+    } else {
+        inputs
+    };
+    let outputs = if outputs.is_empty() {
+        vec![STDIO.to_owned(); qtypes.len()]
+    } else {
+        outputs
+    };
 
-    // Cardinalities should be the same
-    if inputs.len() != outputs.len() || outputs.len() != qtypes.len() || inputs.is_empty() {
+    // Cardinalities should be the same, names to retry come from the failures file instead of inputs
+    let inputs_match = retry_failed.is_some() || inputs.len() == outputs.len();
+    if !inputs_match || outputs.len() != qtypes.len() || outputs.is_empty() {
         error!("input, output and query arguments number must be the same and non-zero");
-        println!("{}", help_msg);
+        eprintln!("{}", help_msg);
+        std::process::exit(1);
+    }
+
+    // Results, failures and disagreements are written by their own threads, lines would interleave
+    let stdout_sinks = outputs.iter().any(|path| path == STDIO) as usize
+        + failed.iter().chain(disagreements.iter()).filter(|&path| path == STDIO).count();
+    if stdout_sinks > 1 {
+        error!("only one of --out, --failed and --disagreements can write to stdout");
+        std::process::exit(1);
+    }
+
+    // Written lines can't be read back from stdout
+    if sort && outputs.iter().chain(failed.iter()).chain(disagreements.iter()).any(|path| path == STDIO) {
+        error!("--sort can't be used when writing to stdout, pipe the output through `sort -u` instead");
         std::process::exit(1);
     }
 
     // Process config
     process_config(matches.value_of("config"));
 
-    // Return inputs, outputs and query types
-    (inputs, outputs, qtypes)
+    if matches.is_present("iterative") {
+        info!("Iterative resolution from root hints");
+        CONFIG.write().unwrap().set_iterative(true);
+    }
+
+    if matches.is_present("validate") {
+        info!("DNSSEC validation, trust anchor {}", CONFIG.read().unwrap().trust_anchor().zone());
+        CONFIG.write().unwrap().set_validate(true);
+    }
+
+    if matches.is_present("cname_chain") {
+        info!("Following CNAME chains up to {} aliases", CONFIG.read().unwrap().max_cname_chain());
+        CONFIG.write().unwrap().set_cname_chain(true);
+    }
+
+    if let Some(consensus) = CONFIG.read().unwrap().consensus() {
+        if matches.is_present("iterative") {
+            error!("consensus mode asks configured DNS servers and can't be used with --iterative");
+            std::process::exit(1);
+        }
+        info!("Consensus of {} out of {} servers", consensus.quorum, consensus.servers);
+    } else if disagreements.is_some() {
+        error!("--disagreements needs consensus = true in the config");
+        std::process::exit(1);
+    }
+
+    Args {
+        inputs: inputs,
+        outputs: outputs,
+        qtypes: qtypes,
+        format: format,
+        columns: columns,
+        failed: failed,
+        retry_failed: retry_failed,
+        sort: sort,
+        qualify: qualify,
+        disagreements: disagreements,
+    }
 }
 
 fn process_config(arg_path: Option<&str>) {
@@ -166,45 +311,107 @@ fn process_config(arg_path: Option<&str>) {
     let config = CONFIG.read().unwrap();
     info!("Retries on timeout: {:?}", config.timeout_retries());
     info!("Queries Per Second: {:?}", config.qps());
+    info!("Adaptive rate:      {:?}", config.adaptive());
     info!("DNS Servers:        {:?}", config.dns_list());
+    info!("EDNS payload size:  {:?}", config.edns_payload());
+    info!("DNSSEC OK bit:      {:?}", config.dnssec_ok());
+    info!("Request NSID:       {:?}", config.nsid());
+    info!("Client subnet:      {:?}", config.client_subnet());
 }
 
-// mpsc::Receiver of resolve results and output file path
-struct ResolveResult {
-    pub resolved_rx: ResolvedRx,
-    pub out_path: String,
-}
+fn main() {
+    let Args {
+        inputs,
+        outputs,
+        qtypes,
+        format,
+        columns,
+        failed: failed_path,
+        retry_failed,
+        sort,
+        qualify: qualify_report,
+        disagreements: disagreements_path,
+    } = process_args();
+
+    if let Some(path) = qualify_report {
+        qualify_servers(&path);
+    }
 
-impl ResolveResult {
-    pub fn new(resolved_rx: ResolvedRx, out_path: String) -> Self {
-        ResolveResult {
-            resolved_rx,
-            out_path,
+    // Every name is sent to as many distinct servers as the consensus asks for
+    {
+        let config = CONFIG.read().unwrap();
+        if let Some(consensus) = config.consensus() {
+            if consensus.servers > config.dns_list().len() {
+                error!(
+                    "consensus_servers is {}, but there are only {} DNS servers",
+                    consensus.servers,
+                    config.dns_list().len()
+                );
+                std::process::exit(1);
+            }
         }
     }
-}
-
-fn main() {
-    let (inputs, outputs, qtypes) = process_args();
 
-    let mut overall_count = 0;
-    let mut resolve_results = vec![];
+    let mut sinks = HashMap::new();
+    let mut outputs_rx = vec![];
+    // Queries for each input path, inputs given several times, stdin in particular, are read once
+    let mut input_queries: Vec<(String, Vec<Query>)> = vec![];
     let mut batch = Batch::new();
 
-    for (&qtype, (input, output)) in qtypes.iter().zip(inputs.iter().zip(outputs.into_iter())) {
-        let input_data = load_file(input).unwrap_or_else(|err| {
-            error!("failed to open {:?}: {}", input, err);
-            std::process::exit(1);
-        });
+    for (idx, (&qtype, output)) in qtypes.iter().zip(outputs.into_iter()).enumerate() {
+        // Tasks with a common output path share its writer
+        let resolved_tx = sinks
+            .entry(output.clone())
+            .or_insert_with(|| {
+                let (resolved_tx, resolved_rx) = mpsc::channel();
+                outputs_rx.push((output, resolved_rx));
+                resolved_tx
+            })
+            .clone();
+
+        match retry_failed {
+            // Failures of a previous run are read as the names are sent
+            Some(ref path) => {
+                let names = read_failed(path, &qtypes).filter_map(move |line| match line {
+                    FailedLine::Retried(name, failed_qtype) if failed_qtype == qtype => Some(name),
+                    _ => None,
+                });
+                batch.add_task(names, vec![(qtype, resolved_tx)]);
+            }
+            None => match input_queries
+                .iter()
+                .position(|&(ref path, _)| *path == inputs[idx])
+            {
+                Some(pos) => input_queries[pos].1.push((qtype, resolved_tx)),
+                None => input_queries.push((inputs[idx].clone(), vec![(qtype, resolved_tx)])),
+            },
+        }
+    }
 
-        overall_count += input_data.len();
+    for (path, queries) in input_queries {
+        batch.add_task(read_input(&path), queries);
+    }
 
-        let (resolved_tx, resolved_rx) = mpsc::channel();
-        let rresult = ResolveResult::new(resolved_rx, output);
-        batch.add_task(input_data, resolved_tx, qtype);
-        resolve_results.push(rresult);
+    // Writers finish when the batch drops the last sender
+    drop(sinks);
+
+    let dedup_capacity = CONFIG.read().unwrap().dedup_capacity();
+    if dedup_capacity > 0 {
+        batch.deduplicate(dedup_capacity);
     }
 
+    let failed_rx = failed_path.as_ref().map(|_| {
+        let (failed_tx, failed_rx) = mpsc::channel();
+        batch.report_failures(failed_tx);
+        failed_rx
+    });
+
+    let disagreements_rx = disagreements_path.as_ref().map(|_| {
+        let (disagreements_tx, disagreements_rx) = mpsc::channel();
+        batch.report_disagreements(disagreements_tx);
+        disagreements_rx
+    });
+
     // Create status output thread and register status callback
     let status = Arc::new(Mutex::new(Status::default()));
     let callback_status = status.clone();
@@ -213,9 +420,15 @@ fn main() {
         *callback_status.lock().unwrap() = s;
     }));
 
-    thread::spawn(move || {
+    let status_printer = thread::spawn(move || {
         debug!("Starting status printer thread");
-        let pb = ProgressBar::new(overall_count as u64);
+        let pb = ProgressBar::new(0);
+        // Stdout may carry the results, progress is only drawn for a user watching stderr
+        if atty::is(atty::Stream::Stderr) {
+            pb.set_draw_target(DrawTarget::stderr());
+        } else {
+            pb.set_draw_target(DrawTarget::Hidden);
+        }
         pb.set_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) | {msg} {spinner:.green}")
             .progress_chars("#>-"));
@@ -223,10 +436,15 @@ fn main() {
         let mut s;
         while {
             s = status.lock().unwrap().clone();
-            s.done < overall_count as u64
+            !s.finished
         } {
-            let message = format!("{} running | {} failed", s.running, s.fail);
-            pb.set_position(s.done);
+            let message = format!(
+                "{} planned qps | {} running | {} nxdomain | {} nodata | {} servfail | {} refused | {} failed | {} tcp fallbacks | {} duplicates | {} disagreements",
+                s.qps, s.running, s.nxdomain, s.nodata, s.servfail, s.refused, s.fail, s.tcp_fallbacks, s.duplicates, s.disagreements
+            );
+            // Inputs are not read ahead, the total grows as names are queued
+            pb.set_length(s.queued + s.duplicates);
+            pb.set_position(s.done + s.duplicates);
             pb.set_message(&message);
             thread::sleep(Duration::from_millis(30));
         }
@@ -235,61 +453,195 @@ fn main() {
         debug!("Terminating status printer thread");
     });
 
+    // Retried names are appended to the results of the previous run
+    let append = retry_failed.is_some();
+
+    let options = OutputOptions {
+        format: format,
+        columns: columns,
+        append: append,
+    };
+
+    // Results are written to disk as they arrive
+    let mut writers = vec![];
+    for (path, resolved_rx) in outputs_rx {
+        let options = options.clone();
+        writers.push(thread::spawn(move || {
+            stream_file(resolved_rx, &path, &options).unwrap_or_else(|err| {
+                error!("failed to write {:?}: {}", path, err);
+                std::process::exit(1);
+            });
+            path
+        }));
+    }
+
+    let failed_writer = match (failed_path, failed_rx) {
+        (Some(path), Some(failed_rx)) => Some(thread::spawn(move || {
+            let kept: Box<Iterator<Item = String>> = match retry_failed {
+                Some(ref retry_path) => Box::new(read_failed(retry_path, &qtypes).filter_map(
+                    |line| match line {
+                        FailedLine::Kept(line) => Some(line),
+                        FailedLine::Retried(..) => None,
+                    },
+                )),
+                None => Box::new(iter::empty()),
+            };
+            // Old failures may be in the same file, it is replaced once they are all read
+            let written = if retry_failed.is_some() && path != STDIO {
+                let new_path = format!("{}.new", path);
+                stream_failed(failed_rx, kept, &new_path).and_then(|_| fs::rename(&new_path, &path))
+            } else {
+                stream_failed(failed_rx, kept, &path)
+            };
+            written.unwrap_or_else(|err| {
+                error!("failed to write {:?}: {}", path, err);
+                std::process::exit(1);
+            });
+            path
+        })),
+        _ => None,
+    };
+
+    let disagreements_writer = match (disagreements_path, disagreements_rx) {
+        (Some(path), Some(disagreements_rx)) => Some(thread::spawn(move || {
+            stream_disagreements(disagreements_rx, &path).unwrap_or_else(|err| {
+                error!("failed to write {:?}: {}", path, err);
+                std::process::exit(1);
+            });
+            path
+        })),
+        _ => None,
+    };
+
     // Execute batch job
     batch.run();
-
-    // Merge all results with common output pathes
-    let mut data_sinks = HashMap::new();
-    for resolved in resolve_results {
-        let entry = data_sinks
-            .entry(resolved.out_path)
-            .or_insert_with(HashSet::new);
-        (*entry).extend(resolved.resolved_rx);
+    status_printer.join().unwrap();
+
+    // Wait for the writers to drain the channels
+    let mut written = writers
+        .into_iter()
+        .map(|writer| {
+            let path = writer.join().unwrap();
+            let format = options.format_of(&path);
+            // Plain output has values only, a repeated one belongs to another name
+            (path, format, format != OutputFormat::Plain)
+        })
+        .collect::<Vec<_>>();
+    if let Some(writer) = failed_writer {
+        written.push((writer.join().unwrap(), OutputFormat::Plain, true));
+    }
+    if let Some(writer) = disagreements_writer {
+        written.push((writer.join().unwrap(), OutputFormat::Plain, true));
     }
 
-    // Merge data into files
-    for (path, data) in data_sinks {
-        write_file(data, path).unwrap();
+    if sort {
+        for (path, format, dedup) in written {
+            sort_file(&path, format, dedup).unwrap_or_else(|err| {
+                error!("failed to sort {:?}: {}", path, err);
+                std::process::exit(1);
+            });
+        }
     }
 }
 
-fn load_file<P: AsRef<Path>>(path: P) -> io::Result<HashSet<String>> {
-    let mut buffer = String::new();
-    let mut file = File::open(path)?;
-    file.read_to_string(&mut buffer)?;
-    Ok(buffer.lines().map(String::from).collect())
-}
+// Keep only the DNS servers that pass the checks, verdicts of all of them go to the report
+fn qualify_servers(report_path: &str) {
+    let verdicts = qualify();
 
-fn write_file<I: IntoIterator<Item = (String, String)>, P: AsRef<Path>>(
-    data: I,
-    path: P,
-) -> io::Result<()> {
-    // Open file for writing
-    let mut file = File::create(path.as_ref())?;
-
-    // If file's extension is `cvs`, we wanna output pairs, and not just the results
-    let csv_mode = path.as_ref().extension().map_or(false, |ext| ext == "csv");
-
-    // Sort and output data
-    let mut data = data.into_iter().collect::<Vec<_>>();
-
-    if csv_mode {
-        data.sort_by(|a, b| a.0.cmp(&b.0));
-        for (from, to) in &data {
-            file.write_all(from.as_bytes())?;
-            file.write_all(b" ")?;
-            file.write_all(to.as_bytes())?;
-            file.write_all(b"\n")?;
-        }
-    } else {
-        data.sort_by(|a, b| a.1.cmp(&b.1));
-        for (_, to) in &data {
-            file.write_all(to.as_bytes())?;
-            file.write_all(b"\n")?;
+    let write_report = || -> io::Result<()> {
+        let mut report = BufWriter::new(File::create(report_path)?);
+        for &(ref upstream, ref verdict) in &verdicts {
+            writeln!(report, "{}\t{}", upstream.server, verdict)?;
         }
+        report.flush()
+    };
+    write_report().unwrap_or_else(|err| {
+        error!("failed to write {:?}: {}", report_path, err);
+        std::process::exit(1);
+    });
+
+    let total = verdicts.len();
+    let qualified = verdicts
+        .into_iter()
+        .filter(|&(_, ref verdict)| verdict.passed())
+        .map(|(upstream, _)| upstream)
+        .collect::<Vec<_>>();
+    if qualified.is_empty() {
+        error!(
+            "none of {} DNS servers qualified, see {:?}",
+            total, report_path
+        );
+        std::process::exit(1);
     }
 
-    Ok(())
+    info!("{} of {} DNS servers qualified", qualified.len(), total);
+    CONFIG.write().unwrap().set_dns_list(qualified);
+}
+
+// Input lines as they are read, stdin for `-`. Blank lines are skipped.
+fn read_input(path: &str) -> Box<Iterator<Item = String>> {
+    let reader: Box<BufRead> = if path == STDIO {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        let file = File::open(path).unwrap_or_else(|err| {
+            error!("failed to open {:?}: {}", path, err);
+            std::process::exit(1);
+        });
+        Box::new(BufReader::new(file))
+    };
+
+    let path = path.to_owned();
+    let lines = reader.lines().map(move |line| {
+        line.unwrap_or_else(|err| {
+            error!("failed to read {:?}: {}", path, err);
+            std::process::exit(1);
+        })
+    });
+    Box::new(lines.filter(|line| !line.trim().is_empty()))
+}
+
+// Transient failures that may go away on another try
+const RETRIED_REASONS: &[&str] = &["timeout", "servfail", "nameserver-unresolved"];
+
+// Line of a failures file written with `--failed`
+enum FailedLine {
+    // Name of one of the query types asked for that may resolve on another try
+    Retried(String, QueryType),
+    // Line to keep as is in the new failures file
+    Kept(String),
+}
+
+// Failures file lines as they are read, a malformed line stops the run
+fn read_failed(path: &str, qtypes: &[QueryType]) -> Box<Iterator<Item = FailedLine>> {
+    let qtypes = qtypes.to_vec();
+    let path = path.to_owned();
+
+    Box::new(read_input(&path).map(move |line| {
+        let retried = {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.get(1).map(|qtype| qtype.parse::<QueryType>()) {
+                Some(Ok(qtype)) if fields.len() == 3 => {
+                    if RETRIED_REASONS.contains(&fields[2]) && qtypes.contains(&qtype) {
+                        Some((fields[0].to_owned(), qtype))
+                    } else {
+                        None
+                    }
+                }
+                _ => {
+                    error!(
+                        "malformed line {:?} in {:?}, expected `name query_type reason`",
+                        line, path
+                    );
+                    std::process::exit(1);
+                }
+            }
+        };
+
+        match retried {
+            Some((name, qtype)) => FailedLine::Retried(name, qtype),
+            None => FailedLine::Kept(line),
+        }
+    }))
 }
 
 fn setup_logger(level: LogLevelFilter) {
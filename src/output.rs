@@ -0,0 +1,790 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use resolve::{AnswerRecord, DisagreementRx, FailedRx, Resolved, ResolvedRx, Response};
+
+/// Output file formats
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// Answer values, one per line
+    Plain,
+    /// RFC 4180 CSV with a header row
+    Csv,
+    /// Tab separated values with a header row, tabs and newlines in values are escaped
+    Tsv,
+    /// JSON object per query with all the details
+    Json,
+}
+
+impl OutputFormat {
+    /// Format by the output file extension
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => OutputFormat::Csv,
+            Some("tsv") => OutputFormat::Tsv,
+            Some("jsonl") | Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Plain,
+        }
+    }
+
+    fn has_header(&self) -> bool {
+        *self == OutputFormat::Csv || *self == OutputFormat::Tsv
+    }
+
+    // RFC 4180 records end with CRLF
+    fn line_ending(&self) -> &'static [u8] {
+        match *self {
+            OutputFormat::Csv => b"\r\n",
+            _ => b"\n",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" | "jsonl" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format {:?}, expected plain, csv, tsv or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Column of CSV and TSV output
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Column {
+    Name,
+    Qtype,
+    Value,
+    Ttl,
+    Rcode,
+    Server,
+    Latency,
+    Attempts,
+    Subnet,
+    Dnssec,
+    Chain,
+}
+
+pub const DEFAULT_COLUMNS: &[Column] = &[Column::Name, Column::Qtype, Column::Value, Column::Ttl];
+
+static COLUMN_NAMES: &[(&str, Column)] = &[
+    ("name", Column::Name),
+    ("qtype", Column::Qtype),
+    ("value", Column::Value),
+    ("ttl", Column::Ttl),
+    ("rcode", Column::Rcode),
+    ("server", Column::Server),
+    ("latency", Column::Latency),
+    ("attempts", Column::Attempts),
+    ("subnet", Column::Subnet),
+    ("dnssec", Column::Dnssec),
+    ("chain", Column::Chain),
+];
+
+impl Column {
+    /// Comma separated list of columns: `name,qtype,value,ttl`
+    pub fn parse_list(s: &str) -> Result<Vec<Column>, String> {
+        s.split(',').map(|column| column.trim().parse()).collect()
+    }
+
+    fn cell(&self, response: &Response, resolved: &Resolved) -> String {
+        match *self {
+            Column::Name => resolved.name.clone(),
+            Column::Qtype => response.qtype.to_string(),
+            Column::Value => resolved.value.clone(),
+            Column::Ttl => resolved.ttl.to_string(),
+            Column::Rcode => response.rcode.clone().unwrap_or_default(),
+            Column::Server => response.server.clone().unwrap_or_default(),
+            Column::Latency => response.latency_ms.to_string(),
+            Column::Attempts => response.attempts.to_string(),
+            Column::Subnet => resolved
+                .client_subnet
+                .map(|subnet| subnet.to_string())
+                .unwrap_or_default(),
+            Column::Dnssec => resolved
+                .dnssec
+                .map(|status| status.to_string())
+                .unwrap_or_default(),
+            // Aliases the name went through, `alias.>alias.`
+            Column::Chain => resolved.cname_chain.join(">"),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        COLUMN_NAMES
+            .iter()
+            .find(|&&(name, _)| name == s)
+            .map(|&(_, column)| column)
+            .ok_or_else(|| {
+                let names = COLUMN_NAMES
+                    .iter()
+                    .map(|&(name, _)| name)
+                    .collect::<Vec<_>>();
+                format!("unknown column {:?}, expected {}", s, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = COLUMN_NAMES
+            .iter()
+            .find(|&&(_, column)| column == *self)
+            .map(|&(name, _)| name)
+            .unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+/// How output files are written
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    /// Format for all outputs, chosen by file extension when not set
+    pub format: Option<OutputFormat>,
+    pub columns: Vec<Column>,
+    /// Append results to the file instead of overwriting it
+    pub append: bool,
+}
+
+impl OutputOptions {
+    pub fn format_of<P: AsRef<Path>>(&self, path: P) -> OutputFormat {
+        self.format
+            .unwrap_or_else(|| OutputFormat::of_path(path.as_ref()))
+    }
+}
+
+/// Path standing for stdin or stdout
+pub const STDIO: &str = "-";
+
+// Buffered output is written to disk at least this often
+const FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Write responses into the file as they arrive until all senders are gone
+pub fn stream_file<P: AsRef<Path>>(
+    responses: ResolvedRx,
+    path: P,
+    options: &OutputOptions,
+) -> io::Result<()> {
+    let format = options.format_of(path.as_ref());
+    let (out, empty) = open_output(path.as_ref(), options.append)?;
+    let mut out = BufWriter::new(out);
+
+    // Appended results go under the header already in the file
+    if format.has_header() && empty {
+        render_header(&mut out, format, &options.columns)?;
+    }
+
+    stream(responses, &mut out, |out, response| match format {
+        OutputFormat::Json => render_json(&response, out),
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            render_rows(&response, out, format, &options.columns)
+        }
+        OutputFormat::Plain => render_plain(&response, out),
+    })
+}
+
+/// Failed input lines as `name query_type reason` as they arrive, `kept` lines are written first
+pub fn stream_failed<P, I>(failed: FailedRx, kept: I, path: P) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = String>,
+{
+    let (out, _) = open_output(path.as_ref(), false)?;
+    let mut out = BufWriter::new(out);
+
+    for line in kept {
+        writeln!(out, "{}", line)?;
+    }
+
+    stream(failed, &mut out, |out, failed| {
+        writeln!(out, "{} {} {}", failed.name, failed.qtype, failed.reason)
+    })
+}
+
+/// Names the servers did not agree on as `name query_type server=answer...` as they arrive,
+/// tab separated as answer values may have spaces
+pub fn stream_disagreements<P: AsRef<Path>>(
+    disagreements: DisagreementRx,
+    path: P,
+) -> io::Result<()> {
+    let (out, _) = open_output(path.as_ref(), false)?;
+    let mut out = BufWriter::new(out);
+
+    stream(disagreements, &mut out, |out, disagreement| {
+        write!(out, "{}\t{}", disagreement.name, disagreement.qtype)?;
+        for (server, answer) in disagreement.answers {
+            write!(out, "\t{}={}", server, answer)?;
+        }
+        writeln!(out)
+    })
+}
+
+// Receive items until the channel is closed, flushing the output every FLUSH_INTERVAL_MS
+// so a crash loses at most that much of the results.
+fn stream<T, W, F>(rx: mpsc::Receiver<T>, out: &mut BufWriter<W>, mut write: F) -> io::Result<()>
+where
+    W: Write,
+    F: FnMut(&mut BufWriter<W>, T) -> io::Result<()>,
+{
+    let interval = Duration::from_millis(FLUSH_INTERVAL_MS);
+    let mut last_flush = Instant::now();
+
+    loop {
+        match rx.recv_timeout(interval) {
+            Ok(item) => write(out, item)?,
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if last_flush.elapsed() >= interval {
+            out.flush()?;
+            last_flush = Instant::now();
+        }
+    }
+
+    out.flush()
+}
+
+// Output file or stdout and whether it has no data yet
+fn open_output(path: &Path, append: bool) -> io::Result<(Box<Write>, bool)> {
+    if path == Path::new(STDIO) {
+        return Ok((Box::new(io::stdout()), true));
+    }
+
+    if !append {
+        return Ok((Box::new(File::create(path)?), true));
+    }
+
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    let empty = file.metadata()?.len() == 0;
+    Ok((Box::new(file), empty))
+}
+
+// Records sorted in memory at once, larger files are sorted in runs merged from disk
+const SORT_CHUNK_BYTES: usize = 64 << 20;
+
+/// Sort records of a written file, the header stays on top. Repeated records are dropped with `dedup`.
+/// Files larger than the memory taken for sorting are sorted in chunks merged next to the file.
+pub fn sort_file<P: AsRef<Path>>(path: P, format: OutputFormat, dedup: bool) -> io::Result<()> {
+    sort_chunked(path.as_ref(), format, dedup, SORT_CHUNK_BYTES)
+}
+
+fn sort_chunked(
+    path: &Path,
+    format: OutputFormat,
+    dedup: bool,
+    chunk_bytes: usize,
+) -> io::Result<()> {
+    let mut records = Records::new(BufReader::new(File::open(path)?), format);
+    let header = match records.next() {
+        Some(header) if format.has_header() => Some(header?),
+        // Files without a header start with a record
+        record => {
+            records.pending = record;
+            None
+        }
+    };
+
+    let mut runs = vec![];
+    let mut chunk = vec![];
+    let mut chunk_size = 0;
+    for record in records {
+        let record = record?;
+        chunk_size += record.len();
+        chunk.push(record);
+        if chunk_size >= chunk_bytes {
+            runs.push(write_run(path, runs.len(), &mut chunk, format, dedup)?);
+            chunk_size = 0;
+        }
+    }
+
+    // File is read whole by now, it is rewritten in place
+    let mut out = BufWriter::new(File::create(path)?);
+    if let Some(header) = header {
+        write_record(&mut out, &header, format)?;
+    }
+
+    if runs.is_empty() {
+        sort_chunk(&mut chunk, dedup);
+        for record in &chunk {
+            write_record(&mut out, record, format)?;
+        }
+        return out.flush();
+    }
+
+    if !chunk.is_empty() {
+        runs.push(write_run(path, runs.len(), &mut chunk, format, dedup)?);
+    }
+    // Runs are left behind if merging fails, they hold the sorted records
+    merge_runs(&runs, &mut out, format, dedup)?;
+    for run in &runs {
+        fs::remove_file(run)?;
+    }
+    out.flush()
+}
+
+fn sort_chunk(chunk: &mut Vec<String>, dedup: bool) {
+    chunk.sort();
+    if dedup {
+        chunk.dedup();
+    }
+}
+
+// Sorted chunk written next to the file, the chunk is emptied
+fn write_run(
+    path: &Path,
+    idx: usize,
+    chunk: &mut Vec<String>,
+    format: OutputFormat,
+    dedup: bool,
+) -> io::Result<PathBuf> {
+    let mut run_path = path.as_os_str().to_owned();
+    run_path.push(format!(".sort{}", idx));
+    let run_path = PathBuf::from(run_path);
+
+    sort_chunk(chunk, dedup);
+    let mut out = BufWriter::new(File::create(&run_path)?);
+    for record in chunk.drain(..) {
+        write_record(&mut out, &record, format)?;
+    }
+    out.flush()?;
+    Ok(run_path)
+}
+
+// Smallest of the run heads goes out first
+fn merge_runs<W: Write>(
+    runs: &[PathBuf],
+    out: &mut W,
+    format: OutputFormat,
+    dedup: bool,
+) -> io::Result<()> {
+    let mut readers = runs
+        .iter()
+        .map(|run| Ok(Records::new(BufReader::new(File::open(run)?), format)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut heads = BinaryHeap::new();
+    for (idx, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = reader.next() {
+            heads.push(Reverse((record?, idx)));
+        }
+    }
+
+    let mut last: Option<String> = None;
+    while let Some(Reverse((record, idx))) = heads.pop() {
+        if let Some(next) = readers[idx].next() {
+            heads.push(Reverse((next?, idx)));
+        }
+        if dedup && last.as_ref() == Some(&record) {
+            continue;
+        }
+        write_record(out, &record, format)?;
+        last = Some(record);
+    }
+
+    Ok(())
+}
+
+fn write_record<W: Write>(out: &mut W, record: &str, format: OutputFormat) -> io::Result<()> {
+    out.write_all(record.as_bytes())?;
+    out.write_all(format.line_ending())
+}
+
+// Records of a written file without their line endings. CSV cells may hold line breaks
+// within quotes, a record goes on until its quotes are closed.
+struct Records<R> {
+    reader: R,
+    format: OutputFormat,
+    // Record read ahead, returned first
+    pending: Option<io::Result<String>>,
+}
+
+impl<R: BufRead> Records<R> {
+    fn new(reader: R, format: OutputFormat) -> Self {
+        Records {
+            reader: reader,
+            format: format,
+            pending: None,
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<String>> {
+        let mut record = String::new();
+        if self.reader.read_line(&mut record)? == 0 {
+            return Ok(None);
+        }
+
+        // Quotes are balanced once the record is over, doubled ones included
+        if self.format == OutputFormat::Csv {
+            while record.matches('"').count() % 2 == 1 {
+                if self.reader.read_line(&mut record)? == 0 {
+                    break;
+                }
+            }
+        }
+
+        if record.ends_with('\n') {
+            record.pop();
+            if record.ends_with('\r') {
+                record.pop();
+            }
+        }
+        Ok(Some(record))
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        if let Some(record) = self.pending.take() {
+            return Some(record);
+        }
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+// Query details for JSON Lines output
+#[derive(Serialize)]
+struct JsonResponse<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    qtype: String,
+    rcode: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    server: Option<&'a str>,
+    latency_ms: u64,
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_subnet: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dnssec: Option<String>,
+    answers: &'a [AnswerRecord],
+}
+
+fn render_json<W: Write>(response: &Response, out: &mut W) -> io::Result<()> {
+    // Same for every value of the response
+    let resolved = response.resolved.first();
+    let json = JsonResponse {
+        name: &response.name,
+        qtype: response.qtype.to_string(),
+        rcode: response.rcode.as_ref().map(String::as_str),
+        error: response.error,
+        server: response.server.as_ref().map(String::as_str),
+        latency_ms: response.latency_ms,
+        attempts: response.attempts,
+        client_subnet: resolved
+            .and_then(|resolved| resolved.client_subnet)
+            .map(|subnet| subnet.to_string()),
+        dnssec: resolved
+            .and_then(|resolved| resolved.dnssec)
+            .map(|status| status.to_string()),
+        answers: &response.records,
+    };
+    serde_json::to_writer(&mut *out, &json).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    out.write_all(b"\n")
+}
+
+fn separator(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Tsv => "\t",
+        _ => ",",
+    }
+}
+
+fn render_header<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    columns: &[Column],
+) -> io::Result<()> {
+    let header = columns
+        .iter()
+        .map(Column::to_string)
+        .collect::<Vec<_>>()
+        .join(separator(format));
+    out.write_all(header.as_bytes())?;
+    out.write_all(format.line_ending())
+}
+
+// Row per answer value, names that did not resolve have no rows
+fn render_rows<W: Write>(
+    response: &Response,
+    out: &mut W,
+    format: OutputFormat,
+    columns: &[Column],
+) -> io::Result<()> {
+    let escape = |cell: &str| match format {
+        OutputFormat::Tsv => escape_tsv(cell),
+        _ => escape_csv(cell),
+    };
+
+    for resolved in &response.resolved {
+        let row = columns
+            .iter()
+            .map(|column| escape(&column.cell(response, resolved)))
+            .collect::<Vec<_>>()
+            .join(separator(format));
+        out.write_all(row.as_bytes())?;
+        out.write_all(format.line_ending())?;
+    }
+
+    Ok(())
+}
+
+// Fields with separators, quotes or line breaks are quoted, quotes are doubled
+fn escape_csv(cell: &str) -> String {
+    if cell.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_owned()
+    }
+}
+
+// TSV fields can't hold tabs and line breaks, they are written as `\t`, `\n` and `\r`
+fn escape_tsv(cell: &str) -> String {
+    cell.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn render_plain<W: Write>(response: &Response, out: &mut W) -> io::Result<()> {
+    for resolved in &response.resolved {
+        out.write_all(resolved.value.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns::rr::RecordType;
+
+    fn response(values: &[(&str, u32)]) -> Response {
+        Response {
+            name: "example.com".to_owned(),
+            qtype: RecordType::TXT.into(),
+            rcode: Some("NOERROR".to_owned()),
+            error: None,
+            server: Some("8.8.8.8:53".to_owned()),
+            latency_ms: 10,
+            attempts: 1,
+            records: vec![],
+            resolved: values
+                .iter()
+                .map(|&(value, ttl)| Resolved {
+                    name: "example.com".to_owned(),
+                    value: value.to_owned(),
+                    ttl: ttl,
+                    client_subnet: None,
+                    dnssec: None,
+                    cname_chain: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn csv_quotes_cells_with_separators_quotes_and_line_breaks() {
+        assert_eq!(escape_csv("v=spf1 -all"), "v=spf1 -all");
+        assert_eq!(escape_csv("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv("line\r\nbreak"), "\"line\r\nbreak\"");
+        assert_eq!(escape_csv(""), "");
+    }
+
+    #[test]
+    fn tsv_escapes_tabs_line_breaks_and_backslashes() {
+        assert_eq!(escape_tsv("a,b \"c\""), "a,b \"c\"");
+        assert_eq!(escape_tsv("a\tb"), "a\\tb");
+        assert_eq!(escape_tsv("a\r\nb"), "a\\r\\nb");
+        // A literal `\t` stays distinguishable from an escaped tab
+        assert_eq!(escape_tsv("a\\tb"), "a\\\\tb");
+    }
+
+    #[test]
+    fn column_list_is_parsed_in_order() {
+        assert_eq!(
+            Column::parse_list("name, value,ttl,chain"),
+            Ok(vec![
+                Column::Name,
+                Column::Value,
+                Column::Ttl,
+                Column::Chain
+            ])
+        );
+        assert_eq!(Column::parse_list("dnssec"), Ok(vec![Column::Dnssec]));
+    }
+
+    #[test]
+    fn column_list_rejects_unknown_and_empty_columns() {
+        assert!(Column::parse_list("name,size")
+            .unwrap_err()
+            .contains("\"size\""));
+        assert!(Column::parse_list("name,,value").is_err());
+        assert!(Column::parse_list("").is_err());
+    }
+
+    #[test]
+    fn column_names_round_trip() {
+        for &(name, column) in COLUMN_NAMES {
+            assert_eq!(column.to_string(), name);
+            assert_eq!(name.parse::<Column>(), Ok(column));
+        }
+    }
+
+    #[test]
+    fn rows_escape_every_cell() {
+        let mut out = Vec::new();
+        let columns = [Column::Name, Column::Value, Column::Ttl];
+        render_rows(
+            &response(&[("a,\"b\"", 60)]),
+            &mut out,
+            OutputFormat::Csv,
+            &columns,
+        )
+        .unwrap();
+        render_rows(
+            &response(&[("a\tb", 60)]),
+            &mut out,
+            OutputFormat::Tsv,
+            &columns,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "example.com,\"a,\"\"b\"\"\",60\r\nexample.com\ta\\tb\t60\n"
+        );
+    }
+
+    #[test]
+    fn plain_values_do_not_depend_on_ttl() {
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        render_plain(&response(&[("v=spf1 -all", 300)]), &mut first).unwrap();
+        render_plain(&response(&[("v=spf1 -all", 17)]), &mut second).unwrap();
+        assert_eq!(first, second);
+    }
+
+    fn json(response: &Response) -> serde_json::Value {
+        let mut out = Vec::new();
+        render_json(response, &mut out).unwrap();
+        assert_eq!(out.last(), Some(&b'\n'));
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn json_lines_carry_records_and_query_details() {
+        let mut resolved = response(&[("v=spf1 -all", 300)]);
+        resolved.records = vec![AnswerRecord {
+            name: "example.com.".to_owned(),
+            rtype: "TXT".to_owned(),
+            ttl: 300,
+            rdata: "\"v=spf1 -all\"".to_owned(),
+        }];
+        let line = json(&resolved);
+        assert_eq!(line["name"], "example.com");
+        assert_eq!(line["type"], "TXT");
+        assert_eq!(line["rcode"], "NOERROR");
+        assert_eq!(line["server"], "8.8.8.8:53");
+        assert_eq!(line["latency_ms"], 10);
+        assert_eq!(line["attempts"], 1);
+        assert!(line.get("error").is_none());
+        let answers = line["answers"].as_array().unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0]["name"], "example.com.");
+        assert_eq!(answers[0]["type"], "TXT");
+        assert_eq!(answers[0]["ttl"], 300);
+        assert_eq!(answers[0]["rdata"], "\"v=spf1 -all\"");
+
+        let mut failed = response(&[]);
+        failed.rcode = Some("SERVFAIL".to_owned());
+        failed.error = Some("servfail");
+        failed.attempts = 3;
+        let line = json(&failed);
+        assert_eq!(line["rcode"], "SERVFAIL");
+        assert_eq!(line["error"], "servfail");
+        assert_eq!(line["server"], "8.8.8.8:53");
+        assert_eq!(line["attempts"], 3);
+        assert_eq!(line["answers"].as_array().unwrap().len(), 0);
+
+        let mut unanswered = response(&[]);
+        unanswered.rcode = None;
+        unanswered.server = None;
+        unanswered.error = Some("timeout");
+        let line = json(&unanswered);
+        assert!(line["rcode"].is_null());
+        assert!(line["server"].is_null());
+        assert_eq!(line["error"], "timeout");
+    }
+
+    fn sorted(
+        name: &str,
+        contents: &str,
+        format: OutputFormat,
+        dedup: bool,
+        chunk_bytes: usize,
+    ) -> String {
+        let path =
+            ::std::env::temp_dir().join(format!("batch_resolve_{}_{}", ::std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        sort_chunked(&path, format, dedup, chunk_bytes).unwrap();
+        let sorted = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        sorted
+    }
+
+    #[test]
+    fn sorting_keeps_header_and_quoted_line_breaks() {
+        let csv = "name,value\r\nb.com,\"z\r\na\"\r\na.com,x\r\nb.com,\"z\r\na\"\r\n";
+        assert_eq!(
+            sorted("csv", csv, OutputFormat::Csv, true, SORT_CHUNK_BYTES),
+            "name,value\r\na.com,x\r\nb.com,\"z\r\na\"\r\n"
+        );
+    }
+
+    #[test]
+    fn large_files_are_merged_from_sorted_runs() {
+        let plain = "c\nb\na\nc\nd\na\n";
+        // Run of every two records
+        assert_eq!(
+            sorted("runs", plain, OutputFormat::Plain, false, 2),
+            "a\na\nb\nc\nc\nd\n"
+        );
+        assert_eq!(
+            sorted("runs_dedup", plain, OutputFormat::Plain, true, 2),
+            "a\nb\nc\nd\n"
+        );
+        assert!(!::std::env::temp_dir()
+            .join(format!("batch_resolve_{}_runs.sort0", ::std::process::id()))
+            .exists());
+    }
+}
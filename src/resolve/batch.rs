@@ -1,48 +1,156 @@
+use std::cell::RefCell;
+use std::iter;
+use std::rc::Rc;
 use std::sync::mpsc;
 use std::thread;
 
+use trust_dns::rr::RecordType;
+
+use config::CONFIG;
+use resolve::bloom::BloomFilter;
+use resolve::client_subnet::ClientSubnet;
+use resolve::dnssec::DnssecStatus;
+use resolve::expand::expand_line;
+use resolve::query_type::QueryType;
 use resolve::resolver_threadpool::ResolveTask;
 use resolve::resolver_threadpool::ResolverThreadPool;
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Status {
+    /// Tasks read from the inputs so far, duplicates excluded
+    pub queued: u64,
+    pub duplicates: u64,
     pub done: u64,
     pub success: u64,
     pub fail: u64,
+    pub nxdomain: u64,
+    pub nodata: u64,
+    pub servfail: u64,
+    pub refused: u64,
     pub errored: u64,
     pub running: u64,
+    pub tcp_fallbacks: u64,
+    /// Names the servers did not agree on in consensus mode
+    pub disagreements: u64,
+    /// Queries planned for the current second in total, retries and follow-up queries aside
+    pub qps: u64,
+    /// All inputs are read and resolved
+    pub finished: bool,
 }
 
 pub type StatusTx = mpsc::Sender<ResolveStatus>;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ResolveStatus {
+    /// Task is read from the input
+    Queued,
+    /// Task is skipped as a repeated one
+    Duplicate,
+    /// Queries planned for the next second
+    Qps(u64),
     Started,
+    TcpFallback,
     Success,
+    /// Timeout, unreachable nameservers or an unusual response code
     Failure,
+    NxDomain,
+    NoData,
+    ServFail,
+    Refused,
+    /// Too few servers agreed on the answer in consensus mode
+    Disagreement,
     Error,
 }
 
-pub type ResolvedTx = mpsc::Sender<(String, String)>;
-pub type ResolvedRx = mpsc::Receiver<(String, String)>;
+/// Single answer value for a queried name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub name: String,
+    pub value: String,
+    pub ttl: u32,
+    /// Client subnet returned by the server, if the query carried one
+    pub client_subnet: Option<ClientSubnet>,
+    /// DNSSEC status of the answer when validation is enabled
+    pub dnssec: Option<DnssecStatus>,
+    /// Aliases from the queried name to the owner of `value` when CNAME chains are recorded
+    pub cname_chain: Vec<String>,
+}
+
+/// Answer section record in presentation format
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct AnswerRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub ttl: u32,
+    pub rdata: String,
+}
+
+/// Outcome of the query for an input line
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub name: String,
+    pub qtype: QueryType,
+    /// Response code mnemonic, none when no response was received
+    pub rcode: Option<String>,
+    /// `ResolverError::reason` when the name did not resolve
+    pub error: Option<&'static str>,
+    /// Server the answer came from
+    pub server: Option<String>,
+    pub latency_ms: u64,
+    /// Queries sent for the name, retries, referrals and CNAME chasing included
+    pub attempts: u32,
+    /// Whole answer section of the response
+    pub records: Vec<AnswerRecord>,
+    /// Answer values of the queried type
+    pub resolved: Vec<Resolved>,
+}
+
+pub type ResolvedTx = mpsc::Sender<Response>;
+pub type ResolvedRx = mpsc::Receiver<Response>;
+
+/// Input line that did not resolve
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Failed {
+    pub name: String,
+    pub qtype: QueryType,
+    /// `ResolverError::reason` of the failure
+    pub reason: &'static str,
+}
+
+pub type FailedTx = mpsc::Sender<Failed>;
+pub type FailedRx = mpsc::Receiver<Failed>;
+
+/// Input line the servers did not agree on in consensus mode
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Disagreement {
+    pub name: String,
+    pub qtype: QueryType,
+    /// Server and its answer: values separated with commas, or the failure reason
+    pub answers: Vec<(String, String)>,
+}
+
+pub type DisagreementTx = mpsc::Sender<Disagreement>;
+pub type DisagreementRx = mpsc::Receiver<Disagreement>;
+
+/// Query type to resolve input names with and the output for its results
+pub type Query = (QueryType, ResolvedTx);
 
-pub struct Batch<I>
-where
-    I: IntoIterator<Item = String> + 'static,
-{
-    tasks: Vec<BatchTask<I>>,
-    outputs: Vec<ResolvedTx>,
+pub struct Batch {
+    tasks: Vec<BatchTask>,
+    failed: Option<FailedTx>,
+    disagreements: Option<DisagreementTx>,
+    dedup: Option<BloomFilter>,
     status_fn: Box<Fn(Status) + Send>,
 }
 
-impl<I> Batch<I>
-where
-    I: IntoIterator<Item = String> + 'static,
-{
+impl Batch {
     pub fn new() -> Self {
         Batch {
             tasks: vec![],
-            outputs: vec![],
+            failed: None,
+            disagreements: None,
+            dedup: None,
             status_fn: Box::new(|_| ()),
         }
     }
@@ -51,49 +159,61 @@ where
         self.status_fn = func
     }
 
-    pub fn add_task(&mut self, input: I, output: ResolvedTx, qtype: QueryType) {
-        self.tasks.push(BatchTask::new(input, qtype));
-        self.outputs.push(output)
+    /// Send names of all tasks that failed to resolve into `failed`
+    pub fn report_failures(&mut self, failed: FailedTx) {
+        self.failed = Some(failed)
     }
 
-    pub fn run(mut self) {
-        let tasks_cnt = self.tasks.len();
-
-        let (status_tx, status_rx) = mpsc::channel();
+    /// Send names the servers did not agree on in consensus mode into `disagreements`
+    pub fn report_disagreements(&mut self, disagreements: DisagreementTx) {
+        self.disagreements = Some(disagreements)
+    }
 
-        let mut resolve_pool = ResolverThreadPool::num_cpus();
+    /// Skip names repeated in an input for the same query type.
+    /// The Bloom filter takes memory for `capacity` names and grows when there are more.
+    pub fn deduplicate(&mut self, capacity: u64) {
+        self.dedup = Some(BloomFilter::new(capacity))
+    }
 
-        // Spawn resolve tasks
-        for _ in 0..tasks_cnt {
-            let task = self.tasks.pop().unwrap();
-            let out = self.outputs.pop().unwrap();
+    /// Add resolve task, every input name is resolved with each of the queries.
+    /// Input is read while resolving, ranges and CIDR blocks in PTR inputs are expanded lazily.
+    pub fn add_task<I>(&mut self, input: I, queries: Vec<Query>)
+    where
+        I: IntoIterator<Item = String>,
+        I::IntoIter: 'static,
+    {
+        self.tasks
+            .push(BatchTask::new(Box::new(input.into_iter()), queries));
+    }
 
-            for name in task.input {
-                trace!("Spawning task {} {}", name, task.qtype);
-                resolve_pool.spawn(ResolveTask {
-                    tx: out.clone(),
-                    name: name,
-                    qtype: task.qtype,
-                });
-            }
-        }
+    pub fn run(self) {
+        let (status_tx, status_rx) = mpsc::channel();
 
         let status_fn = self.status_fn;
 
         // Spawn status thread
-        thread::spawn(move || {
+        let status_thread = thread::spawn(move || {
             let mut status = Status::default();
 
             for resolve_status in status_rx {
                 trace!("Resolve status: received {:?}", resolve_status);
                 match resolve_status {
+                    ResolveStatus::Queued => status.queued += 1,
+                    ResolveStatus::Duplicate => status.duplicates += 1,
+                    ResolveStatus::Qps(qps) => status.qps = qps,
                     ResolveStatus::Started => status.running += 1,
+                    ResolveStatus::TcpFallback => status.tcp_fallbacks += 1,
                     other => {
                         status.done += 1;
                         status.running -= 1;
                         match other {
                             ResolveStatus::Success => status.success += 1,
                             ResolveStatus::Failure => status.fail += 1,
+                            ResolveStatus::NxDomain => status.nxdomain += 1,
+                            ResolveStatus::NoData => status.nodata += 1,
+                            ResolveStatus::ServFail => status.servfail += 1,
+                            ResolveStatus::Refused => status.refused += 1,
+                            ResolveStatus::Disagreement => status.disagreements += 1,
                             ResolveStatus::Error => status.errored += 1,
                             _ => (),
                         }
@@ -101,52 +221,100 @@ where
                 }
                 status_fn(status);
             }
+
+            // Senders are gone with the last resolver
+            status.finished = true;
+            status_fn(status);
         });
 
-        trace!("Starting resolve job on a thread pool");
-        resolve_pool.start(status_tx);
-        trace!("Finished resolve");
-    }
-}
+        let expand_limit = CONFIG.read().unwrap().expand_limit();
+        let failed = self.failed;
+        let disagreements = self.disagreements;
 
-arg_enum! {
-    #[derive(Copy, Clone, Debug)]
-    pub enum QueryType {
-        A,
-        AAAA,
-        PTR,
-        NS
-    }
-}
+        // Tasks are made as inputs are read, task index tells apart names of different inputs
+        let tasks = self
+            .tasks
+            .into_iter()
+            .enumerate()
+            .flat_map(move |(idx, task)| {
+                let BatchTask { input, queries } = task;
+                let failed = failed.clone();
+                let disagreements = disagreements.clone();
 
-use trust_dns::rr::RecordType;
-impl Into<RecordType> for QueryType {
-    fn into(self) -> RecordType {
-        match self {
-            QueryType::A => RecordType::A,
-            QueryType::AAAA => RecordType::AAAA,
-            QueryType::PTR => RecordType::PTR,
-            QueryType::NS => RecordType::NS,
+                input.flat_map(move |line| {
+                    let failed = failed.clone();
+                    let disagreements = disagreements.clone();
+
+                    queries.clone().into_iter().flat_map(move |(qtype, tx)| {
+                        let names: Box<Iterator<Item = String>> = match qtype.record_type() {
+                            Some(RecordType::PTR) => Box::new(expand_line(&line, expand_limit)),
+                            _ => Box::new(iter::once(line.clone())),
+                        };
+
+                        let failed = failed.clone();
+                        let disagreements = disagreements.clone();
+                        names.map(move |name| {
+                            let task = ResolveTask {
+                                tx: tx.clone(),
+                                failed_tx: failed.clone(),
+                                disagreement_tx: disagreements.clone(),
+                                name: name,
+                                qtype: qtype,
+                            };
+                            (idx, task)
+                        })
+                    })
+                })
+            });
+
+        // Tasks are read on this thread, the filter is back for the report when they are done
+        let dedup = Rc::new(RefCell::new(self.dedup));
+        let seen = dedup.clone();
+        let queue_status = status_tx.clone();
+        let tasks = tasks
+            .filter(move |&(idx, ref task)| {
+                let new = match *seen.borrow_mut() {
+                    Some(ref mut seen) => seen.insert(&(idx, task.qtype, &task.name)),
+                    None => true,
+                };
+
+                if new {
+                    trace!("Queueing task {} {}", task.name, task.qtype);
+                    queue_status.send(ResolveStatus::Queued).unwrap();
+                } else {
+                    trace!("Skipping duplicate task {} {}", task.name, task.qtype);
+                    queue_status.send(ResolveStatus::Duplicate).unwrap();
+                }
+                new
+            })
+            .map(|(_, task)| task);
+
+        trace!("Starting resolve job on a thread pool");
+        ResolverThreadPool::num_cpus().start(tasks, status_tx);
+        status_thread.join().unwrap();
+
+        if let Some(ref seen) = *dedup.borrow() {
+            // Dropped names can't be told from repeated ones, only their expected number is known
+            info!(
+                "{} distinct names deduplicated, about {:.1} new names expected to be skipped as repeated",
+                seen.len(),
+                seen.false_positives()
+            );
         }
+        trace!("Finished resolve");
     }
 }
 
-pub struct BatchTask<I>
-where
-    I: IntoIterator<Item = String>,
-{
-    input: I,
-    qtype: QueryType,
+pub struct BatchTask {
+    input: Box<Iterator<Item = String>>,
+    queries: Vec<Query>,
 }
 
-impl<I> BatchTask<I>
-where
-    I: IntoIterator<Item = String> + 'static,
-{
-    fn new(input: I, qtype: QueryType) -> Self {
+impl BatchTask {
+    fn new(input: Box<Iterator<Item = String>>, queries: Vec<Query>) -> Self {
         BatchTask {
             input: input,
-            qtype: qtype,
+            queries: queries,
         }
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+
+/// Probability of an item taken for a duplicate, however many items are inserted
+pub const FALSE_POSITIVE_RATE: f64 = 0.000_001;
+
+// Seed of the second hash function
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Fixed size filter, the building block of the growing one
+struct Layer {
+    bits: Vec<u64>,
+    bits_cnt: u64,
+    hashes_cnt: u32,
+    // Bits set so far, the share of them gives the actual false positive rate
+    ones: u64,
+    capacity: u64,
+    len: u64,
+}
+
+impl Layer {
+    fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        // Optimal size and number of hash functions for the capacity and false positive rate
+        let bits_cnt =
+            (-(capacity as f64) * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let hashes_cnt = (bits_cnt as f64 / capacity as f64 * LN_2).round().max(1.0) as u32;
+
+        debug!(
+            "Bloom filter layer of {} bytes with {} hash functions for {} items",
+            bits_cnt / 8,
+            hashes_cnt,
+            capacity
+        );
+
+        Layer {
+            bits: vec![0; (bits_cnt / 64 + 1) as usize],
+            bits_cnt: bits_cnt,
+            hashes_cnt: hashes_cnt,
+            ones: 0,
+            capacity: capacity,
+            len: 0,
+        }
+    }
+
+    // Double hashing: i-th bit is h1 + i * h2
+    fn bit(&self, (h1, h2): (u64, u64), i: u64) -> (usize, u64) {
+        let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.bits_cnt;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        (0..u64::from(self.hashes_cnt)).all(|i| {
+            let (word, mask) = self.bit(hash, i);
+            self.bits[word] & mask != 0
+        })
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for i in 0..u64::from(self.hashes_cnt) {
+            let (word, mask) = self.bit(hash, i);
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                self.ones += 1;
+            }
+        }
+        self.len += 1;
+    }
+
+    // Chance of a new item to have all its bits set already
+    fn false_positive_rate(&self) -> f64 {
+        (self.ones as f64 / self.bits_cnt as f64).powi(self.hashes_cnt as i32)
+    }
+}
+
+/// Bloom filter telling apart items seen before.
+/// Items are never missed, but a new item is taken for a seen one with `FALSE_POSITIVE_RATE`
+/// probability. The filter takes memory for `capacity` items and grows once there are more:
+/// every new layer is twice as large and twice as precise, which keeps the overall rate.
+pub struct BloomFilter {
+    layers: Vec<Layer>,
+    capacity: u64,
+    // Expected number of new items taken for seen ones so far
+    false_positives: f64,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: u64) -> Self {
+        let capacity = capacity.max(1);
+        BloomFilter {
+            layers: vec![Layer::new(capacity, FALSE_POSITIVE_RATE / 2.0)],
+            capacity: capacity,
+            false_positives: 0.0,
+        }
+    }
+
+    /// Insert the item, false if it was (probably) inserted before
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let hash = Self::hash(item);
+        if self.layers.iter().any(|layer| layer.contains(hash)) {
+            return false;
+        }
+
+        // The item got in, it had this chance to be dropped as a false positive
+        let missed = self.layers.iter().fold(1.0, |missed, layer| {
+            missed * (1.0 - layer.false_positive_rate())
+        });
+        self.false_positives += 1.0 - missed;
+
+        if self
+            .layers
+            .last()
+            .map_or(true, |layer| layer.len >= layer.capacity)
+        {
+            self.grow();
+        }
+        self.layers.last_mut().unwrap().insert(hash);
+        true
+    }
+
+    /// Items inserted so far
+    pub fn len(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.len).sum()
+    }
+
+    /// Expected number of new items the filter has taken for seen ones, they were dropped unseen
+    pub fn false_positives(&self) -> f64 {
+        self.false_positives
+    }
+
+    fn grow(&mut self) {
+        let layers = self.layers.len() as i32;
+        if layers == 1 {
+            warn!(
+                "More than {} distinct names to deduplicate, the filter grows past dedup_capacity",
+                self.capacity
+            );
+        }
+
+        // Rates FALSE_POSITIVE_RATE / 2^n sum up to FALSE_POSITIVE_RATE
+        self.layers.push(Layer::new(
+            self.capacity.saturating_mul(1 << layers.min(32)),
+            FALSE_POSITIVE_RATE / 2f64.powi(layers + 1),
+        ));
+    }
+
+    fn hash<T: Hash>(item: &T) -> (u64, u64) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+
+        let mut hasher = DefaultHasher::new();
+        SEED.hash(&mut hasher);
+        item.hash(&mut hasher);
+        // Odd step visits different bits for every hash function
+        let h2 = hasher.finish() | 1;
+
+        (h1, h2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_items_are_seen() {
+        let mut filter = BloomFilter::new(1000);
+        for i in 0..1000 {
+            assert!(filter.insert(&i), "{} taken for a seen item", i);
+        }
+        for i in 0..1000 {
+            assert!(!filter.insert(&i), "{} was not seen", i);
+        }
+        assert_eq!(filter.len(), 1000);
+    }
+
+    #[test]
+    fn false_positive_rate_holds_at_capacity() {
+        let capacity = 100_000;
+        let mut filter = BloomFilter::new(capacity);
+        for i in 0..capacity {
+            filter.insert(&i);
+        }
+        // Expected well below one of the new items to be taken for a seen one
+        let probes = capacity * 4;
+        let taken = (capacity..capacity + probes)
+            .filter(|i| !filter.insert(i))
+            .count();
+        assert!(
+            taken <= 2,
+            "{} of {} new items taken for seen",
+            taken,
+            probes
+        );
+        assert!(filter.false_positives() < 1.0);
+    }
+
+    #[test]
+    fn filter_grows_past_capacity() {
+        let capacity = 1000;
+        let mut filter = BloomFilter::new(capacity);
+        let items = capacity * 50;
+        let dropped = (0..items).filter(|i| !filter.insert(i)).count();
+        assert!(dropped <= 2, "{} of {} dropped", dropped, items);
+        assert!(filter.layers.len() > 1);
+        assert!(filter.false_positives() < FALSE_POSITIVE_RATE * items as f64 * 2.0);
+        assert!((0..items).all(|i| !filter.insert(&i)));
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use trust_dns::op::Message;
+use trust_dns::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+/// EDNS Client Subnet (RFC 7871) as `address/source prefix`.
+/// Scope prefix is only known for subnets returned by the server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    source_prefix: u8,
+    scope_prefix: Option<u8>,
+}
+
+impl ClientSubnet {
+    pub fn new(addr: IpAddr, source_prefix: u8) -> Result<Self, String> {
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if source_prefix > max_prefix {
+            return Err(format!(
+                "client subnet prefix /{} is too long for {}",
+                source_prefix, addr
+            ));
+        }
+
+        Ok(ClientSubnet {
+            addr: mask(addr, source_prefix),
+            source_prefix: source_prefix,
+            scope_prefix: None,
+        })
+    }
+
+    /// EDNS option to attach to a query
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+
+        // Address is truncated to the octets covered by the source prefix
+        let len = (self.source_prefix as usize + 7) / 8;
+        let mut data = Vec::with_capacity(4 + len);
+        data.push((family >> 8) as u8);
+        data.push(family as u8);
+        data.push(self.source_prefix);
+        data.push(0);
+        data.extend_from_slice(&octets[..len]);
+
+        EdnsOption::Unknown(EdnsCode::Subnet.into(), data)
+    }
+
+    /// Client subnet option echoed back by the server, with the scope prefix it applied
+    pub fn from_message(message: &Message) -> Option<Self> {
+        let data = match *message.edns()?.option(&EdnsCode::Subnet)? {
+            EdnsOption::Unknown(_, ref data) if data.len() >= 4 => data,
+            _ => return None,
+        };
+
+        let family = (data[0] as u16) << 8 | data[1] as u16;
+        let addr = &data[4..];
+        let addr = match family {
+            1 if addr.len() <= 4 => {
+                let mut octets = [0u8; 4];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if addr.len() <= 16 => {
+                let mut octets = [0u8; 16];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => {
+                warn!("malformed client subnet option in response: {:?}", data);
+                return None;
+            }
+        };
+
+        ClientSubnet::new(addr, data[2])
+            .map(|subnet| ClientSubnet {
+                scope_prefix: Some(data[3]),
+                ..subnet
+            })
+            .map_err(|e| warn!("malformed client subnet option in response: {}", e))
+            .ok()
+    }
+}
+
+/// Name and client subnet of an input line, `name` or `name,subnet`
+pub fn split_line(line: &str) -> (String, Option<Result<ClientSubnet, String>>) {
+    let mut parts = line.splitn(2, ',');
+    let name = parts.next().unwrap_or("").trim().to_owned();
+    (name, parts.next().map(str::parse::<ClientSubnet>))
+}
+
+// Zero address bits past the prefix, as RFC 7871 requires
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let bits = u32::from(addr);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u32 << (32 - prefix)
+            };
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        }
+        IpAddr::V6(addr) => {
+            let bits = u128::from(addr);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u128 << (128 - prefix)
+            };
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        }
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let mut parts = s.splitn(2, '/');
+
+        let addr = parts
+            .next()
+            .unwrap_or("")
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid client subnet {:?}: bad address", s))?;
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|_| format!("invalid client subnet {:?}: bad prefix length", s))?,
+            None => match addr {
+                IpAddr::V4(_) => 24,
+                IpAddr::V6(_) => 56,
+            },
+        };
+
+        ClientSubnet::new(addr, prefix)
+    }
+}
+
+/// `address/source` or, for subnets returned by the server, `address/source/scope` as dig prints it
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.scope_prefix {
+            Some(scope) => write!(f, "{}/{}/{}", self.addr, self.source_prefix, scope),
+            None => write!(f, "{}/{}", self.addr, self.source_prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use trust_dns::op::Edns;
+
+    // Response carrying the option of `subnet` as the server echoes it back
+    fn echo(subnet: &ClientSubnet, scope_prefix: u8) -> Message {
+        let option = match subnet.to_option() {
+            EdnsOption::Unknown(code, mut data) => {
+                data[3] = scope_prefix;
+                EdnsOption::Unknown(code, data)
+            }
+            option => option,
+        };
+        with_option(option)
+    }
+
+    // Message with the option as it is read from the wire
+    fn with_option(option: EdnsOption) -> Message {
+        let mut edns = Edns::new();
+        edns.set_option(option);
+        let mut message = Message::new();
+        message.set_edns(edns);
+        Message::from_vec(&message.to_vec().unwrap()).unwrap()
+    }
+
+    fn option_data(subnet: &ClientSubnet) -> Vec<u8> {
+        match subnet.to_option() {
+            EdnsOption::Unknown(_, data) => data,
+            option => panic!("unexpected {:?}", option),
+        }
+    }
+
+    #[test]
+    fn option_round_trips_for_ipv4_and_ipv6() {
+        let v4 = "192.0.2.0/24".parse::<ClientSubnet>().unwrap();
+        assert_eq!(option_data(&v4), vec![0, 1, 24, 0, 192, 0, 2]);
+        let echoed = ClientSubnet::from_message(&echo(&v4, 20)).unwrap();
+        assert_eq!(echoed.to_string(), "192.0.2.0/24/20");
+        assert_eq!(
+            echoed,
+            ClientSubnet {
+                scope_prefix: Some(20),
+                ..v4
+            }
+        );
+
+        let v6 = "2001:db8:1234::/48".parse::<ClientSubnet>().unwrap();
+        assert_eq!(
+            option_data(&v6),
+            vec![0, 2, 48, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34]
+        );
+        let echoed = ClientSubnet::from_message(&echo(&v6, 56)).unwrap();
+        assert_eq!(echoed.to_string(), "2001:db8:1234::/48/56");
+
+        let zero = "0.0.0.0/0".parse::<ClientSubnet>().unwrap();
+        assert_eq!(option_data(&zero), vec![0, 1, 0, 0]);
+        assert_eq!(
+            ClientSubnet::from_message(&echo(&zero, 0))
+                .unwrap()
+                .to_string(),
+            "0.0.0.0/0/0"
+        );
+    }
+
+    #[test]
+    fn address_bits_past_an_unaligned_prefix_are_zero() {
+        let v4 = "192.0.3.255/23".parse::<ClientSubnet>().unwrap();
+        assert_eq!(v4.to_string(), "192.0.2.0/23");
+        assert_eq!(option_data(&v4), vec![0, 1, 23, 0, 192, 0, 2]);
+
+        let v6 = "2001:db8:ffff::/57".parse::<ClientSubnet>().unwrap();
+        assert_eq!(v6.to_string(), "2001:db8:ffff::/57");
+        let v6 = "2001:db8:0:ffff::/57".parse::<ClientSubnet>().unwrap();
+        assert_eq!(v6.to_string(), "2001:db8:0:ff80::/57");
+        assert_eq!(
+            option_data(&v6),
+            vec![0, 2, 57, 0, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0xff, 0x80]
+        );
+
+        // Servers that leave bits past the prefix set are read as if they did not
+        let option = EdnsOption::Unknown(EdnsCode::Subnet.into(), vec![0, 1, 23, 0, 192, 0, 3]);
+        let echoed = ClientSubnet::from_message(&with_option(option)).unwrap();
+        assert_eq!(echoed.to_string(), "192.0.2.0/23/0");
+    }
+
+    #[test]
+    fn malformed_options_in_responses_are_ignored() {
+        let subnet = |data: Vec<u8>| {
+            let option = EdnsOption::Unknown(EdnsCode::Subnet.into(), data);
+            ClientSubnet::from_message(&with_option(option))
+        };
+        assert_eq!(subnet(vec![0, 1, 24]), None);
+        assert_eq!(subnet(vec![0, 1, 24, 0, 192, 0, 2, 0, 1]), None);
+        assert_eq!(subnet(vec![0, 1, 33, 0, 192, 0, 2, 0]), None);
+        assert_eq!(subnet(vec![0, 3, 24, 0, 192, 0, 2]), None);
+        assert_eq!(ClientSubnet::from_message(&Message::new()), None);
+    }
+
+    #[test]
+    fn lines_carry_an_optional_subnet_after_the_name() {
+        let (name, subnet) = split_line(" example.com , 192.0.2.0/24 ");
+        assert_eq!(name, "example.com");
+        assert_eq!(subnet.unwrap().unwrap().to_string(), "192.0.2.0/24");
+
+        assert_eq!(split_line("example.com"), ("example.com".to_owned(), None));
+
+        // Prefix defaults to /24 and /56, as RFC 7871 recommends
+        let (_, subnet) = split_line("example.com,198.51.100.7");
+        assert_eq!(subnet.unwrap().unwrap().to_string(), "198.51.100.0/24");
+        let (_, subnet) = split_line("example.com,2001:db8::1");
+        assert_eq!(subnet.unwrap().unwrap().to_string(), "2001:db8::/56");
+
+        for line in &[
+            "example.com,",
+            "example.com,192.0.2.0/33",
+            "example.com,2001:db8::/129",
+            "example.com,192.0.2.0/",
+            "example.com,192.0.2.0/x",
+            "example.com,192.0.2/24",
+            "example.com,192.0.2.0/24/20",
+            "example.com,192.0.2.0/24,extra",
+        ] {
+            let (_, subnet) = split_line(line);
+            assert!(subnet.unwrap().is_err(), "{} is accepted", line);
+        }
+    }
+}
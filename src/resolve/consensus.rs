@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+
+use config::NameServer;
+use resolve::batch::{Disagreement, ResolveStatus, Response};
+use resolve::error::ResolverError;
+use resolve::query_type::QueryType;
+
+/// Outcome of the query to one of the servers asked for a name, with its progress status
+pub type Vote = (NameServer, Result<(Response, ResolveStatus), ResolverError>);
+
+// Answers are the same when they fail the same way or have the same set of values, TTLs aside
+type AnswerKey = (Option<&'static str>, Vec<String>);
+
+/// Response at least `quorum` servers agree on, with the status a single server would get for it.
+/// Servers that did not respond have no say, and a server answering retries of others counts once.
+pub fn agreed(votes: &[Vote], quorum: usize) -> Option<(Response, ResolveStatus)> {
+    let mut groups: Vec<(AnswerKey, HashSet<&str>, &(Response, ResolveStatus))> = Vec::new();
+
+    for vote in votes.iter().filter_map(|&(_, ref rv)| rv.as_ref().ok()) {
+        let response = &vote.0;
+        let server = match (&response.rcode, &response.server) {
+            (&Some(_), &Some(ref server)) => server.as_str(),
+            _ => continue,
+        };
+        let key = answer_key(response);
+        match groups
+            .iter()
+            .position(|&(ref group_key, _, _)| *group_key == key)
+        {
+            Some(pos) => {
+                groups[pos].1.insert(server);
+            }
+            None => {
+                let mut servers = HashSet::new();
+                servers.insert(server);
+                groups.push((key, servers, vote));
+            }
+        }
+    }
+
+    // The first of the largest groups if there are several
+    groups
+        .into_iter()
+        .filter(|&(_, ref servers, _)| servers.len() >= quorum)
+        .rev()
+        .max_by_key(|&(_, ref servers, _)| servers.len())
+        .map(|(_, _, vote)| vote.clone())
+}
+
+/// Answers of every server for the disagreements output
+pub fn disagreement(name: &str, qtype: QueryType, votes: &[Vote]) -> Disagreement {
+    Disagreement {
+        name: name.to_owned(),
+        qtype: qtype,
+        answers: votes
+            .iter()
+            .map(|&(ref server, ref rv)| (server.to_string(), answer(rv)))
+            .collect(),
+    }
+}
+
+/// Response for a name the servers did not agree on, it resolves to nothing
+pub fn no_consensus(name: &str, qtype: QueryType, votes: &[Vote]) -> Response {
+    let responses = votes
+        .iter()
+        .filter_map(|&(_, ref rv)| rv.as_ref().ok().map(|&(ref response, _)| response))
+        .collect::<Vec<_>>();
+
+    Response {
+        name: name.to_owned(),
+        qtype: qtype,
+        rcode: None,
+        error: Some(ResolverError::NoConsensus.reason()),
+        server: None,
+        // Queries went in parallel, the name took as long as the slowest one
+        latency_ms: responses
+            .iter()
+            .map(|response| response.latency_ms)
+            .max()
+            .unwrap_or(0),
+        attempts: responses.iter().map(|response| response.attempts).sum(),
+        records: vec![],
+        resolved: vec![],
+    }
+}
+
+fn answer_key(response: &Response) -> AnswerKey {
+    let mut values = response
+        .resolved
+        .iter()
+        .map(|resolved| resolved.value.clone())
+        .collect::<Vec<_>>();
+    values.sort();
+    values.dedup();
+    (response.error, values)
+}
+
+// Values separated with commas, or the failure reason
+fn answer(rv: &Result<(Response, ResolveStatus), ResolverError>) -> String {
+    match *rv {
+        Ok((ref response, _)) => match response.error {
+            Some(reason) => reason.to_owned(),
+            None => answer_key(response).1.join(","),
+        },
+        Err(ref err) => err.reason().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resolve::batch::Resolved;
+    use trust_dns::rr::RecordType;
+
+    fn server(addr: &str) -> NameServer {
+        NameServer::udp(addr.parse().unwrap())
+    }
+
+    // Answer of `answered_by`, retries may have moved the query off the server it was sent to
+    fn vote(asked: &str, answered_by: &str, values: &[&str]) -> Vote {
+        let response = Response {
+            name: "example.com".to_owned(),
+            qtype: RecordType::A.into(),
+            rcode: Some("NOERROR".to_owned()),
+            error: None,
+            server: Some(server(answered_by).to_string()),
+            latency_ms: 10,
+            attempts: 1,
+            records: vec![],
+            resolved: values
+                .iter()
+                .map(|value| Resolved {
+                    name: "example.com".to_owned(),
+                    value: value.to_string(),
+                    ttl: 300,
+                    client_subnet: None,
+                    dnssec: None,
+                    cname_chain: vec![],
+                })
+                .collect(),
+        };
+        let status = if values.is_empty() {
+            ResolveStatus::Failure
+        } else {
+            ResolveStatus::Success
+        };
+        (server(asked), Ok((response, status)))
+    }
+
+    fn nxdomain(asked: &str) -> Vote {
+        let (server, response) = vote(asked, asked, &[]);
+        let (mut response, _) = response.unwrap();
+        response.rcode = Some("NXDOMAIN".to_owned());
+        response.error = Some("nxdomain");
+        (server, Ok((response, ResolveStatus::NxDomain)))
+    }
+
+    // Retries ran out without an answer
+    fn timed_out(asked: &str) -> Vote {
+        let (server, response) = vote(asked, asked, &[]);
+        let (mut response, status) = response.unwrap();
+        response.rcode = None;
+        response.server = None;
+        response.error = Some("timeout");
+        (server, Ok((response, status)))
+    }
+
+    fn values(agreed: Option<(Response, ResolveStatus)>) -> Option<Vec<String>> {
+        agreed.map(|(response, _)| answer_key(&response).1)
+    }
+
+    #[test]
+    fn majority_agrees_on_values_in_any_order() {
+        let votes = vec![
+            vote("1.1.1.1:53", "1.1.1.1:53", &["10.0.0.1", "10.0.0.2"]),
+            vote("8.8.8.8:53", "8.8.8.8:53", &["10.0.0.2", "10.0.0.1"]),
+            vote("9.9.9.9:53", "9.9.9.9:53", &["10.6.6.6"]),
+        ];
+        assert_eq!(
+            values(agreed(&votes, 2)),
+            Some(vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()])
+        );
+        assert!(agreed(&votes, 3).is_none());
+    }
+
+    #[test]
+    fn same_failure_is_an_agreement() {
+        let votes = vec![
+            nxdomain("1.1.1.1:53"),
+            nxdomain("8.8.8.8:53"),
+            vote("9.9.9.9:53", "9.9.9.9:53", &["10.6.6.6"]),
+        ];
+        let (response, status) = agreed(&votes, 2).unwrap();
+        assert_eq!(response.error, Some("nxdomain"));
+        assert_eq!(status, ResolveStatus::NxDomain);
+    }
+
+    #[test]
+    fn tie_goes_to_the_first_group() {
+        let votes = vec![
+            vote("1.1.1.1:53", "1.1.1.1:53", &["10.0.0.1"]),
+            vote("8.8.8.8:53", "8.8.8.8:53", &["10.0.0.2"]),
+            vote("9.9.9.9:53", "9.9.9.9:53", &["10.0.0.2"]),
+            vote("4.4.4.4:53", "4.4.4.4:53", &["10.0.0.1"]),
+        ];
+        assert_eq!(values(agreed(&votes, 2)), Some(vec!["10.0.0.1".to_owned()]));
+        assert!(agreed(&votes, 3).is_none());
+    }
+
+    #[test]
+    fn server_answering_retries_of_others_counts_once() {
+        // Queries to the two other servers timed out and were retried with 9.9.9.9
+        let votes = vec![
+            vote("1.1.1.1:53", "9.9.9.9:53", &["10.6.6.6"]),
+            vote("8.8.8.8:53", "9.9.9.9:53", &["10.6.6.6"]),
+            vote("9.9.9.9:53", "9.9.9.9:53", &["10.6.6.6"]),
+        ];
+        assert!(agreed(&votes, 2).is_none());
+        assert!(agreed(&votes, 1).is_some());
+    }
+
+    #[test]
+    fn timed_out_servers_have_no_say() {
+        let votes = vec![
+            timed_out("1.1.1.1:53"),
+            timed_out("8.8.8.8:53"),
+            (server("4.4.4.4:53"), Err(ResolverError::ConnectionTimeout)),
+            vote("9.9.9.9:53", "9.9.9.9:53", &["10.0.0.1"]),
+        ];
+        // The timeouts don't make a group of their own
+        assert!(agreed(&votes, 2).is_none());
+        assert_eq!(values(agreed(&votes, 1)), Some(vec!["10.0.0.1".to_owned()]));
+    }
+
+    #[test]
+    fn lone_answer_does_not_make_a_quorum() {
+        // The other servers of the group had no answer
+        let votes = vec![
+            vote("1.1.1.1:53", "1.1.1.1:53", &["10.6.6.6"]),
+            timed_out("8.8.8.8:53"),
+        ];
+        assert!(agreed(&votes, 2).is_none());
+        assert!(agreed(&votes[..1], 2).is_none());
+    }
+}
@@ -0,0 +1,1386 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::Duration;
+use data_encoding::{base16, base32hex, base64};
+use futures::future;
+use futures::Future;
+
+use trust_dns::op::{Message, ResponseCode};
+use trust_dns::rr::dnssec::{Algorithm, DigestType, KeyPair, Nsec3HashAlgorithm, Signer};
+use trust_dns::rr::rdata::{DNSKEY, DS};
+use trust_dns::rr::{Name, RData, Record, RecordType};
+use trust_dns::serialize::binary::{BinDecoder, BinEncoder, BinSerializable};
+
+use resolve::error::ResolverError;
+use resolve::query_type::QueryType;
+use resolve::wire::{bitmap_types, nsec3_bitmap, read_u16, record_data, skip_name};
+
+/// Root zone KSK-2017 (key tag 20326) digest
+const ROOT_ANCHOR_DS: &str = "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D";
+
+/// Root zone KSK-2024 (key tag 38696) digest, either key is accepted through the rollover
+const ROOT_ANCHOR_2024_DS: &str =
+    "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16";
+
+/// Chain of trust lookups deeper than that are cut as Indeterminate
+const MAX_CHAIN_DEPTH: u8 = 32;
+
+/// DNSSEC validation result of an answer, as defined in RFC 4033 section 5
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DnssecStatus {
+    /// Chain of signatures from the trust anchor down to the answer
+    Secure,
+    /// Chain ends with a proven unsigned delegation
+    Insecure,
+    /// Signatures are expected but missing, expired or wrong
+    Bogus,
+    /// Chain could not be fetched or does not lead to the trust anchor
+    Indeterminate,
+}
+
+impl DnssecStatus {
+    // Answer made of several RRsets is as trustworthy as the weakest of them
+    fn worst(self, other: DnssecStatus) -> DnssecStatus {
+        use self::DnssecStatus::*;
+        match (self, other) {
+            (Bogus, _) | (_, Bogus) => Bogus,
+            (Indeterminate, _) | (_, Indeterminate) => Indeterminate,
+            (Insecure, _) | (_, Insecure) => Insecure,
+            _ => Secure,
+        }
+    }
+
+    // RRset signed by several zones is as trustworthy as the strongest of the signers
+    fn best(self, other: DnssecStatus) -> DnssecStatus {
+        use self::DnssecStatus::*;
+        match (self, other) {
+            (Secure, _) | (_, Secure) => Secure,
+            (Insecure, _) | (_, Insecure) => Insecure,
+            (Indeterminate, _) | (_, Indeterminate) => Indeterminate,
+            _ => Bogus,
+        }
+    }
+}
+
+impl fmt::Display for DnssecStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match *self {
+            DnssecStatus::Secure => "secure",
+            DnssecStatus::Insecure => "insecure",
+            DnssecStatus::Bogus => "bogus",
+            DnssecStatus::Indeterminate => "indeterminate",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Trusted DS and DNSKEY records of a single zone, root KSKs by default
+#[derive(Debug, Clone)]
+pub struct TrustAnchor {
+    zone: String,
+    ds: Vec<DS>,
+    dnskeys: Vec<DNSKEY>,
+}
+
+impl Default for TrustAnchor {
+    fn default() -> Self {
+        TrustAnchor {
+            zone: ".".to_owned(),
+            ds: vec![
+                DS::new(
+                    20326,
+                    Algorithm::RSASHA256,
+                    DigestType::SHA256,
+                    base16::decode(ROOT_ANCHOR_DS.as_bytes()).unwrap(),
+                ),
+                DS::new(
+                    38696,
+                    Algorithm::RSASHA256,
+                    DigestType::SHA256,
+                    base16::decode(ROOT_ANCHOR_2024_DS.as_bytes()).unwrap(),
+                ),
+            ],
+            dnskeys: vec![],
+        }
+    }
+}
+
+impl TrustAnchor {
+    /// Parse DS and DNSKEY records in zone file format, like the `root.key` of unbound-anchor:
+    /// `. 172800 IN DS 20326 8 2 E06D44B8...`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut zone: Option<Name> = None;
+        let mut ds = Vec::new();
+        let mut dnskeys = Vec::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            let type_idx = tokens
+                .iter()
+                .position(|token| *token == "DS" || *token == "DNSKEY")
+                .ok_or_else(|| format!("trust anchor {:?} is not a DS or DNSKEY record", line))?;
+
+            let owner = Name::parse(tokens[0], Some(&Name::root()))
+                .map_err(|e| format!("invalid trust anchor owner {:?}: {}", tokens[0], e))?;
+            match zone {
+                Some(ref zone) if *zone != owner => {
+                    return Err(format!(
+                        "trust anchor records belong to different zones: {} and {}",
+                        zone, owner
+                    ))
+                }
+                _ => zone = Some(owner),
+            }
+
+            let rdata = &tokens[type_idx + 1..];
+            let invalid = || format!("invalid trust anchor record {:?}", line);
+            if rdata.len() < 4 {
+                return Err(invalid());
+            }
+
+            let number = |idx: usize| rdata[idx].parse::<u16>().map_err(|_| invalid());
+
+            if tokens[type_idx] == "DS" {
+                // key tag, algorithm, digest type, digest
+                let algorithm = Algorithm::from_u8(number(1)? as u8).map_err(|_| invalid())?;
+                let digest_type = DigestType::from_u8(number(2)? as u8).map_err(|_| invalid())?;
+                let digest = rdata[3..].concat().to_uppercase();
+                let digest = base16::decode(digest.as_bytes()).map_err(|_| invalid())?;
+                ds.push(DS::new(number(0)?, algorithm, digest_type, digest));
+            } else {
+                // flags, protocol, algorithm, public key
+                let algorithm = Algorithm::from_u8(number(2)? as u8).map_err(|_| invalid())?;
+                let flags = number(0)?;
+                let public_key =
+                    base64::decode(rdata[3..].concat().as_bytes()).map_err(|_| invalid())?;
+                dnskeys.push(DNSKEY::new(
+                    flags & 0x0100 != 0,
+                    flags & 0x0001 != 0,
+                    flags & 0x0080 != 0,
+                    algorithm,
+                    public_key,
+                ));
+            }
+        }
+
+        match zone {
+            Some(zone) => Ok(TrustAnchor {
+                zone: zone.to_string(),
+                ds: ds,
+                dnskeys: dnskeys,
+            }),
+            None => Err("trust anchor file has no DS or DNSKEY records".to_owned()),
+        }
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+}
+
+/// Query used to fetch DNSKEY, DS and denial of existence records along the chain
+pub type DnsQuery = Rc<Fn(Name, QueryType) -> Box<Future<Item = Message, Error = ResolverError>>>;
+
+type StatusFuture = Box<Future<Item = DnssecStatus, Error = ResolverError>>;
+
+// Keys of a zone that signatures can be checked against
+enum ZoneKeys {
+    Trusted(Vec<DNSKEY>),
+    Untrusted(DnssecStatus),
+}
+
+// Records of the same name and type with signatures covering them
+struct Rrset {
+    name: Name,
+    rtype: QueryType,
+    records: Vec<Record>,
+    sigs: Vec<Rrsig>,
+}
+
+fn rrsets(records: &[Record]) -> Vec<Rrset> {
+    let mut rrsets: Vec<Rrset> = Vec::new();
+
+    let rrsig = QueryType::from(RecordType::RRSIG);
+    for record in records.iter().filter(|r| QueryType::from(*r) != rrsig) {
+        let rtype = QueryType::from(record);
+        match rrsets
+            .iter_mut()
+            .find(|rrset| rrset.rtype == rtype && rrset.name == *record.name())
+        {
+            Some(rrset) => rrset.records.push(record.clone()),
+            None => rrsets.push(Rrset {
+                name: record.name().clone(),
+                rtype: rtype,
+                records: vec![record.clone()],
+                sigs: vec![],
+            }),
+        }
+    }
+
+    for record in records {
+        if let Some(sig) = Rrsig::from_record(record) {
+            if let Some(rrset) = rrsets
+                .iter_mut()
+                .find(|rrset| rrset.rtype == sig.type_covered() && rrset.name == *record.name())
+            {
+                rrset.sigs.push(sig);
+            }
+        }
+    }
+
+    rrsets
+}
+
+// RRSIG record data as it was received, RFC 4034 section 3.1
+#[derive(Clone)]
+struct Rrsig {
+    data: Vec<u8>,
+    signer_name: Name,
+    signature_at: usize,
+}
+
+impl Rrsig {
+    fn from_record(record: &Record) -> Option<Self> {
+        if QueryType::from(record) != RecordType::RRSIG.into() {
+            return None;
+        }
+
+        // Type covered, algorithm, labels, original TTL, expiration, inception and key tag
+        // come before the signer name
+        let data = record_data(record)?;
+        let signature_at = skip_name(&data, 18)?;
+        let signer_name = Name::read(&mut BinDecoder::new(&data[18..signature_at])).ok()?;
+        Some(Rrsig {
+            data: data,
+            signer_name: signer_name,
+            signature_at: signature_at,
+        })
+    }
+
+    fn type_covered(&self) -> QueryType {
+        QueryType::from(read_u16(&self.data, 0).unwrap_or(0))
+    }
+
+    fn algorithm(&self) -> u8 {
+        self.data[2]
+    }
+
+    fn labels(&self) -> u8 {
+        self.data[3]
+    }
+
+    fn original_ttl(&self) -> &[u8] {
+        &self.data[4..8]
+    }
+
+    fn expiration(&self) -> u32 {
+        read_u32(&self.data, 8)
+    }
+
+    fn inception(&self) -> u32 {
+        read_u32(&self.data, 12)
+    }
+
+    fn key_tag(&self) -> u16 {
+        read_u16(&self.data, 16).unwrap_or(0)
+    }
+
+    fn signer_name(&self) -> &Name {
+        &self.signer_name
+    }
+
+    fn signature(&self) -> &[u8] {
+        &self.data[self.signature_at..]
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    data[at..at + 4]
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+/// DNSSEC validator of a resolver thread, authenticated zone keys are cached for the batch
+#[derive(Clone)]
+pub struct Validator {
+    anchor_zone: Name,
+    anchor_ds: Rc<Vec<DS>>,
+    anchor_keys: Rc<Vec<DNSKEY>>,
+    zones: Rc<RefCell<HashMap<Name, Rc<ZoneKeys>>>>,
+}
+
+impl Validator {
+    pub fn new(anchor: &TrustAnchor) -> Self {
+        Validator {
+            anchor_zone: Name::parse(&anchor.zone, Some(&Name::root())).unwrap(),
+            anchor_ds: Rc::new(anchor.ds.clone()),
+            anchor_keys: Rc::new(anchor.dnskeys.clone()),
+            zones: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Status of the answer section, or of the NSEC and NSEC3 proof that there is no `qtype`
+    /// data for the query name when the answer is empty
+    pub fn validate(&self, message: &Message, qtype: QueryType, query: DnsQuery) -> StatusFuture {
+        let checks = rrsets(message.answers())
+            .into_iter()
+            .map(|rrset| self.verify_rrset(rrset, query.clone(), 0))
+            .collect::<Vec<_>>();
+
+        if checks.is_empty() {
+            return self.denial_status(message, qtype, query);
+        }
+
+        Box::new(future::join_all(checks).map(|statuses| {
+            statuses
+                .into_iter()
+                .fold(DnssecStatus::Secure, DnssecStatus::worst)
+        }))
+    }
+
+    // Zone of the SOA record that comes with an empty answer tells whether it could be signed,
+    // a signed zone has to prove the denial with its own NSEC or NSEC3 records
+    fn denial_status(&self, message: &Message, qtype: QueryType, query: DnsQuery) -> StatusFuture {
+        let zone = message
+            .name_servers()
+            .iter()
+            .find(|record| record.rr_type() == RecordType::SOA)
+            .map(|record| record.name().clone());
+        let qname = message.queries().first().map(|query| query.name().clone());
+
+        let (zone, qname) = match (zone, qname) {
+            (Some(zone), Some(qname)) => (zone, qname),
+            _ => return Box::new(future::ok(DnssecStatus::Indeterminate)),
+        };
+        let nxdomain = match message.response_code() {
+            ResponseCode::NoError => false,
+            ResponseCode::NXDomain => true,
+            _ => return Box::new(future::ok(DnssecStatus::Indeterminate)),
+        };
+
+        let mut proofs = rrsets(message.name_servers())
+            .into_iter()
+            .filter(|rrset| {
+                rrset.rtype == RecordType::NSEC.into() || rrset.rtype == RecordType::NSEC3.into()
+            })
+            .collect::<Vec<_>>();
+        for proof in &mut proofs {
+            proof.sigs.retain(|sig| *sig.signer_name() == zone);
+        }
+
+        let validator = self.clone();
+
+        Box::new(
+            self.zone_keys(zone, query.clone(), 1)
+                .and_then(move |keys| -> StatusFuture {
+                    match *keys {
+                        ZoneKeys::Untrusted(status) => return Box::new(future::ok(status)),
+                        ZoneKeys::Trusted(_) => (),
+                    }
+
+                    let claim = denial_proof(&qname, qtype, nxdomain, &proofs);
+                    if claim == DnssecStatus::Bogus {
+                        debug!("no proof of missing {} {}", qname, qtype);
+                        return Box::new(future::ok(claim));
+                    }
+
+                    let checks = proofs
+                        .into_iter()
+                        .map(|proof| validator.verify_rrset(proof, query.clone(), 1))
+                        .collect::<Vec<_>>();
+
+                    Box::new(future::join_all(checks).map(move |statuses| {
+                        match statuses
+                            .into_iter()
+                            .fold(DnssecStatus::Secure, DnssecStatus::worst)
+                        {
+                            DnssecStatus::Secure => claim,
+                            status => status,
+                        }
+                    }))
+                }),
+        )
+    }
+
+    fn verify_rrset(&self, mut rrset: Rrset, query: DnsQuery, depth: u8) -> StatusFuture {
+        if depth > MAX_CHAIN_DEPTH {
+            return Box::new(future::ok(DnssecStatus::Indeterminate));
+        }
+
+        if rrset.sigs.is_empty() {
+            return self.unsigned_status(rrset.name, query, depth + 1);
+        }
+
+        // DS records live in the parent zone and are signed by it
+        let name = rrset.name.clone();
+        let rtype = rrset.rtype;
+        rrset.sigs.retain(|sig| {
+            sig.signer_name().zone_of(&name)
+                && (rtype != RecordType::DS.into() || *sig.signer_name() != name)
+        });
+        if rrset.sigs.is_empty() {
+            return Box::new(future::ok(DnssecStatus::Bogus));
+        }
+
+        let mut signers: Vec<Name> = Vec::new();
+        for sig in &rrset.sigs {
+            if !signers.contains(sig.signer_name()) {
+                signers.push(sig.signer_name().clone());
+            }
+        }
+
+        // Each signer is checked against its own keys, one chain of trust is enough
+        let rrset = Rc::new(rrset);
+        let checks = signers
+            .into_iter()
+            .map(|signer| {
+                let rrset = rrset.clone();
+                self.zone_keys(signer.clone(), query.clone(), depth + 1)
+                    .map(move |keys| match *keys {
+                        ZoneKeys::Untrusted(status) => status,
+                        ZoneKeys::Trusted(ref keys) => {
+                            let verified = rrset
+                                .sigs
+                                .iter()
+                                .filter(|sig| *sig.signer_name() == signer)
+                                .any(|sig| keys.iter().any(|key| verify_sig(&rrset, sig, key)));
+                            if verified {
+                                DnssecStatus::Secure
+                            } else {
+                                debug!(
+                                    "bad signature of {} over {} {}",
+                                    signer, rrset.name, rrset.rtype
+                                );
+                                DnssecStatus::Bogus
+                            }
+                        }
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(checks).map(|statuses| {
+            statuses
+                .into_iter()
+                .fold(DnssecStatus::Bogus, DnssecStatus::best)
+        }))
+    }
+
+    // Unsigned data is fine only in a zone that is proven to be unsigned
+    fn unsigned_status(&self, name: Name, query: DnsQuery, depth: u8) -> StatusFuture {
+        let validator = self.clone();
+
+        Box::new(
+            query(name, RecordType::SOA.into()).then(move |result| -> StatusFuture {
+                let message = match result {
+                    Ok(message) => message,
+                    Err(_) => return Box::new(future::ok(DnssecStatus::Indeterminate)),
+                };
+
+                let zone = message
+                    .answers()
+                    .iter()
+                    .chain(message.name_servers())
+                    .find(|record| record.rr_type() == RecordType::SOA)
+                    .map(|record| record.name().clone());
+
+                match zone {
+                    Some(zone) => Box::new(validator.zone_keys(zone, query, depth + 1).map(
+                        |keys| match *keys {
+                            ZoneKeys::Trusted(_) => DnssecStatus::Bogus,
+                            ZoneKeys::Untrusted(status) => status,
+                        },
+                    )),
+                    None => Box::new(future::ok(DnssecStatus::Indeterminate)),
+                }
+            }),
+        )
+    }
+
+    fn zone_keys(
+        &self,
+        zone: Name,
+        query: DnsQuery,
+        depth: u8,
+    ) -> Box<Future<Item = Rc<ZoneKeys>, Error = ResolverError>> {
+        if let Some(keys) = self.zones.borrow().get(&zone) {
+            return Box::new(future::ok(keys.clone()));
+        }
+
+        if depth > MAX_CHAIN_DEPTH {
+            let keys = ZoneKeys::Untrusted(DnssecStatus::Indeterminate);
+            return Box::new(future::ok(Rc::new(keys)));
+        }
+
+        let validator = self.clone();
+        let cache = self.clone();
+        let cache_zone = zone.clone();
+
+        let keys = self
+            .trusted_ds(zone.clone(), query.clone(), depth + 1)
+            .and_then(
+                move |ds| -> Box<Future<Item = ZoneKeys, Error = ResolverError>> {
+                    let ds = match ds {
+                        Ok(ds) => ds,
+                        Err(status) => return Box::new(future::ok(ZoneKeys::Untrusted(status))),
+                    };
+
+                    Box::new(
+                        query(zone.clone(), RecordType::DNSKEY.into()).then(move |result| {
+                            Ok(match result {
+                                Ok(message) => validator.trust_keys(&zone, &message, &ds),
+                                Err(_) => ZoneKeys::Untrusted(DnssecStatus::Indeterminate),
+                            })
+                        }),
+                    )
+                },
+            );
+
+        Box::new(keys.map(move |keys| {
+            let keys = Rc::new(keys);
+            // Failed lookups may succeed later, everything else holds for the whole batch
+            match *keys {
+                ZoneKeys::Untrusted(DnssecStatus::Indeterminate) => (),
+                _ => {
+                    cache.zones.borrow_mut().insert(cache_zone, keys.clone());
+                }
+            }
+            keys
+        }))
+    }
+
+    // Authenticated DS set of a zone or the reason there is none
+    fn trusted_ds(
+        &self,
+        zone: Name,
+        query: DnsQuery,
+        depth: u8,
+    ) -> Box<Future<Item = Result<Vec<DS>, DnssecStatus>, Error = ResolverError>> {
+        if zone == self.anchor_zone {
+            return Box::new(future::ok(Ok(self.anchor_ds.to_vec())));
+        }
+
+        // Chain has to pass through the anchor
+        if zone.is_root() || !self.anchor_zone.zone_of(&zone) {
+            return Box::new(future::ok(Err(DnssecStatus::Indeterminate)));
+        }
+
+        let validator = self.clone();
+
+        Box::new(query(zone.clone(), RecordType::DS.into()).then(
+            move |result| -> Box<Future<Item = _, Error = _>> {
+                let message = match result {
+                    Ok(message) => message,
+                    Err(_) => return Box::new(future::ok(Err(DnssecStatus::Indeterminate))),
+                };
+
+                let rrset = rrsets(message.answers())
+                    .into_iter()
+                    .find(|rrset| rrset.rtype == RecordType::DS.into() && rrset.name == zone);
+
+                match rrset {
+                    Some(rrset) => {
+                        let ds = rrset
+                            .records
+                            .iter()
+                            .filter_map(|record| match *record.rdata() {
+                                RData::DS(ref ds) => Some(ds.clone()),
+                                _ => None,
+                            })
+                            .collect::<Vec<_>>();
+
+                        Box::new(validator.verify_rrset(rrset, query, depth + 1).map(
+                            move |status| match status {
+                                DnssecStatus::Secure => Ok(ds),
+                                status => Err(status),
+                            },
+                        ))
+                    }
+                    None => Box::new(
+                        validator
+                            .no_ds_status(zone, &message, query, depth + 1)
+                            .map(Err),
+                    ),
+                }
+            },
+        ))
+    }
+
+    // Missing DS is Insecure only with a signed NSEC or NSEC3 proof from the parent
+    fn no_ds_status(
+        &self,
+        zone: Name,
+        message: &Message,
+        query: DnsQuery,
+        depth: u8,
+    ) -> StatusFuture {
+        let mut proofs = rrsets(message.name_servers())
+            .into_iter()
+            .filter(|rrset| {
+                rrset.rtype == RecordType::NSEC.into() || rrset.rtype == RecordType::NSEC3.into()
+            })
+            .collect::<Vec<_>>();
+
+        // Denial of existence from the child side of the cut proves nothing
+        for proof in &mut proofs {
+            proof
+                .sigs
+                .retain(|sig| *sig.signer_name() != zone && sig.signer_name().zone_of(&zone));
+        }
+
+        if proofs.is_empty() {
+            // Fine if the parent is not signed itself
+            return self.unsigned_status(zone.base_name(), query, depth + 1);
+        }
+
+        let claim = insecure_delegation(&zone, &proofs);
+        let checks = proofs
+            .into_iter()
+            .map(|proof| self.verify_rrset(proof, query.clone(), depth + 1))
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(checks).map(move |statuses| {
+            match statuses
+                .into_iter()
+                .fold(DnssecStatus::Secure, DnssecStatus::worst)
+            {
+                DnssecStatus::Secure => claim,
+                status => status,
+            }
+        }))
+    }
+
+    // DNSKEY RRset is trusted when one of its keys matches the DS set and signs the RRset
+    fn trust_keys(&self, zone: &Name, message: &Message, ds: &[DS]) -> ZoneKeys {
+        let rrset = rrsets(message.answers())
+            .into_iter()
+            .find(|rrset| rrset.rtype == RecordType::DNSKEY.into() && rrset.name == *zone);
+
+        let rrset = match rrset {
+            Some(rrset) => rrset,
+            None => {
+                debug!("no DNSKEY for signed zone {}", zone);
+                return ZoneKeys::Untrusted(DnssecStatus::Bogus);
+            }
+        };
+
+        let keys = rrset
+            .records
+            .iter()
+            .filter_map(|record| match *record.rdata() {
+                RData::DNSKEY(ref key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let anchored = *zone == self.anchor_zone;
+        let entry_points = keys
+            .iter()
+            .filter(|key| {
+                ds.iter().any(|ds| ds.covers(zone, key).unwrap_or(false))
+                    || (anchored
+                        && self
+                            .anchor_keys
+                            .iter()
+                            .any(|a| a.public_key() == key.public_key()))
+            })
+            .collect::<Vec<_>>();
+
+        let verified = rrset
+            .sigs
+            .iter()
+            .filter(|sig| sig.signer_name() == zone)
+            .any(|sig| entry_points.iter().any(|key| verify_sig(&rrset, sig, key)));
+
+        if verified {
+            ZoneKeys::Trusted(keys)
+        } else {
+            debug!("DNSKEY set of {} does not match its DS records", zone);
+            ZoneKeys::Untrusted(DnssecStatus::Bogus)
+        }
+    }
+}
+
+// Signed proof says the zone is a delegation without DS
+fn insecure_delegation(zone: &Name, proofs: &[Rrset]) -> DnssecStatus {
+    let unsigned_cut = |types: &[u16]| {
+        let has = |rtype: RecordType| types.contains(&rtype.into());
+        has(RecordType::NS) && !has(RecordType::DS) && !has(RecordType::SOA)
+    };
+
+    for proof in proofs {
+        for record in &proof.records {
+            let data = match record_data(record) {
+                Some(data) => data,
+                None => continue,
+            };
+
+            if proof.rtype == RecordType::NSEC.into() {
+                if record.name() == zone && nsec_types(&data).map_or(false, |t| unsigned_cut(&t)) {
+                    return DnssecStatus::Insecure;
+                }
+                continue;
+            }
+
+            let nsec3 = match Nsec3::read(&data) {
+                Some(nsec3) => nsec3,
+                None => continue,
+            };
+            let hash = match nsec3.hash(zone) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let owner = match nsec3_owner(record) {
+                Some(owner) => owner,
+                None => continue,
+            };
+
+            if hash == owner {
+                if unsigned_cut(&nsec3.types) {
+                    return DnssecStatus::Insecure;
+                }
+            } else if nsec3.opt_out && covers(&owner, &nsec3.next, &hash) {
+                // Opt-out span may hold unsigned delegations
+                return DnssecStatus::Insecure;
+            }
+        }
+    }
+
+    DnssecStatus::Bogus
+}
+
+// Signed proof says there is no `qtype` data at `qname`, RFC 4035 section 5.4 for NSEC
+// and RFC 5155 section 8 for NSEC3
+fn denial_proof(qname: &Name, qtype: QueryType, nxdomain: bool, proofs: &[Rrset]) -> DnssecStatus {
+    let mut nsecs = Vec::new();
+    let mut nsec3s = Vec::new();
+    for proof in proofs {
+        for record in &proof.records {
+            let data = match record_data(record) {
+                Some(data) => data,
+                None => continue,
+            };
+            if proof.rtype == RecordType::NSEC.into() {
+                if let Some(nsec) = Nsec::read(record.name(), &data) {
+                    nsecs.push(nsec);
+                }
+            } else if let (Some(owner), Some(nsec3)) = (nsec3_owner(record), Nsec3::read(&data)) {
+                nsec3s.push((owner, nsec3));
+            }
+        }
+    }
+
+    // CNAME would have been followed instead
+    let missing = |types: &[u16]| {
+        !types.contains(&qtype.code()) && !types.contains(&RecordType::CNAME.into())
+    };
+
+    if !nsecs.is_empty() {
+        if !nxdomain {
+            // Name with other types or an empty non-terminal before its descendants
+            if nsecs.iter().any(|nsec| {
+                (nsec.owner == *qname && missing(&nsec.types))
+                    || (covers(&nsec.owner, &nsec.next, qname) && qname.zone_of(&nsec.next))
+            }) {
+                return DnssecStatus::Secure;
+            }
+        }
+
+        // Name is missing, and so is the wildcard that could have made it up
+        let cover = nsecs
+            .iter()
+            .find(|nsec| nsec.owner != *qname && covers(&nsec.owner, &nsec.next, qname));
+        if let Some(cover) = cover {
+            let owner_side = common_ancestor(qname, &cover.owner);
+            let next_side = common_ancestor(qname, &cover.next);
+            let encloser = if next_side.num_labels() > owner_side.num_labels() {
+                next_side
+            } else {
+                owner_side
+            };
+            let wildcard = wildcard_of(&encloser);
+            let proven = if nxdomain {
+                nsecs
+                    .iter()
+                    .any(|nsec| covers(&nsec.owner, &nsec.next, &wildcard))
+            } else {
+                nsecs
+                    .iter()
+                    .any(|nsec| nsec.owner == wildcard && missing(&nsec.types))
+            };
+            if proven {
+                return DnssecStatus::Secure;
+            }
+        }
+        return DnssecStatus::Bogus;
+    }
+
+    let matching = |name: &Name| {
+        nsec3s
+            .iter()
+            .find(|&&(ref owner, ref nsec3)| nsec3.hash(name).as_ref() == Some(owner))
+            .map(|&(_, ref nsec3)| nsec3)
+    };
+    let covering = |name: &Name| {
+        nsec3s
+            .iter()
+            .find(|&&(ref owner, ref nsec3)| {
+                nsec3
+                    .hash(name)
+                    .map_or(false, |hash| covers(owner, &nsec3.next, &hash))
+            })
+            .map(|&(_, ref nsec3)| nsec3)
+    };
+
+    if !nxdomain {
+        if let Some(nsec3) = matching(qname) {
+            return if missing(&nsec3.types) {
+                DnssecStatus::Secure
+            } else {
+                DnssecStatus::Bogus
+            };
+        }
+    }
+
+    // Closest provable encloser and the next closer name under it, RFC 5155 section 8.3
+    let labels = qname.num_labels() as usize;
+    let encloser = (0..labels)
+        .rev()
+        .map(|n| qname.trim_to(n))
+        .find(|name| matching(name).is_some());
+    let encloser = match encloser {
+        Some(encloser) => encloser,
+        None => return DnssecStatus::Bogus,
+    };
+    let next_closer = qname.trim_to(encloser.num_labels() as usize + 1);
+    let cover = match covering(&next_closer) {
+        Some(cover) => cover,
+        None => return DnssecStatus::Bogus,
+    };
+
+    let wildcard = wildcard_of(&encloser);
+    let proven = if nxdomain {
+        covering(&wildcard).is_some()
+    } else {
+        // Missing type at a wildcard, or an unsigned delegation in an opt-out span
+        matching(&wildcard).map_or(false, |nsec3| missing(&nsec3.types))
+            || (cover.opt_out && qtype == RecordType::DS.into())
+    };
+
+    match (proven, cover.opt_out) {
+        (false, _) => DnssecStatus::Bogus,
+        (true, true) => DnssecStatus::Insecure,
+        (true, false) => DnssecStatus::Secure,
+    }
+}
+
+fn common_ancestor(name: &Name, other: &Name) -> Name {
+    (0..name.num_labels() as usize + 1)
+        .rev()
+        .map(|n| name.trim_to(n))
+        .find(|ancestor| ancestor.zone_of(other))
+        .unwrap_or_else(Name::root)
+}
+
+fn wildcard_of(name: &Name) -> Name {
+    let mut wildcard = Name::new().label("*");
+    wildcard.append(name);
+    wildcard
+}
+
+// NSEC record data, RFC 4034 section 4.1
+struct Nsec {
+    owner: Name,
+    next: Name,
+    types: Vec<u16>,
+}
+
+impl Nsec {
+    fn read(owner: &Name, data: &[u8]) -> Option<Self> {
+        let next_end = skip_name(data, 0)?;
+        Some(Nsec {
+            owner: owner.clone(),
+            next: Name::read(&mut BinDecoder::new(&data[..next_end])).ok()?,
+            types: nsec_types(data)?,
+        })
+    }
+}
+
+// Types of an NSEC record, the bitmap follows the next owner name
+fn nsec_types(data: &[u8]) -> Option<Vec<u16>> {
+    bitmap_types(data.get(skip_name(data, 0)?..)?)
+}
+
+// Hash in the first label of an NSEC3 owner name
+fn nsec3_owner(record: &Record) -> Option<Vec<u8>> {
+    base32hex::decode(record.name()[0].to_uppercase().as_bytes()).ok()
+}
+
+// NSEC3 record data, RFC 5155 section 3.2
+struct Nsec3 {
+    opt_out: bool,
+    iterations: u16,
+    salt: Vec<u8>,
+    next: Vec<u8>,
+    types: Vec<u16>,
+}
+
+impl Nsec3 {
+    fn read(data: &[u8]) -> Option<Self> {
+        // SHA-1 is the only hash algorithm defined
+        if *data.first()? != 1 {
+            return None;
+        }
+        let salt_len = *data.get(4)? as usize;
+        let hash_len = *data.get(5 + salt_len)? as usize;
+        Some(Nsec3 {
+            opt_out: data[1] & 0x01 != 0,
+            iterations: read_u16(data, 2)?,
+            salt: data[5..5 + salt_len].to_vec(),
+            next: data.get(6 + salt_len..6 + salt_len + hash_len)?.to_vec(),
+            types: bitmap_types(nsec3_bitmap(data)?)?,
+        })
+    }
+
+    fn hash(&self, name: &Name) -> Option<Vec<u8>> {
+        Nsec3HashAlgorithm::SHA1
+            .hash(&self.salt, name, self.iterations)
+            .ok()
+            .map(|hash| hash.to_vec())
+    }
+}
+
+// Name or hash falls between NSEC owner and next owner in canonical order, the last NSEC
+// of a zone wraps around to the first one
+fn covers<T: Ord + ?Sized>(owner: &T, next: &T, item: &T) -> bool {
+    match owner.cmp(next) {
+        Ordering::Less => owner < item && item < next,
+        _ => item > owner || item < next,
+    }
+}
+
+fn verify_sig(rrset: &Rrset, sig: &Rrsig, key: &DNSKEY) -> bool {
+    if key.revoke() || !key.zone_key() || u8::from(*key.algorithm()) != sig.algorithm() {
+        return false;
+    }
+
+    // Signature validity period, RFC 4035 section 5.3.1
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as u32)
+        .unwrap_or(0);
+    if now < sig.inception() || now > sig.expiration() {
+        return false;
+    }
+
+    let pkey = match KeyPair::from_public_bytes(key.public_key(), *key.algorithm()) {
+        Ok(pkey) => pkey,
+        Err(e) => {
+            debug!(
+                "unusable {:?} key of {}: {}",
+                key.algorithm(),
+                sig.signer_name(),
+                e
+            );
+            return false;
+        }
+    };
+    let signer = Signer::dnssec(
+        key.clone(),
+        pkey,
+        sig.signer_name().clone(),
+        Duration::zero(),
+    );
+
+    match signer.calculate_key_tag() {
+        Ok(key_tag) if key_tag != sig.key_tag() => return false,
+        _ => (),
+    }
+
+    signed_data(rrset, sig).map_or(false, |data| signer.verify(&data, sig.signature()).is_ok())
+}
+
+// Data signed by `sig`: its own fields but the signature, then the records of the RRset in
+// canonical form and order, with their data as it was received (RFC 4034 section 6)
+fn signed_data(rrset: &Rrset, sig: &Rrsig) -> Option<Vec<u8>> {
+    let mut data = sig.data[..18].to_vec();
+    emit_canonical(&sig.signer_name().to_lowercase(), &mut data)?;
+
+    // Records expanded from a wildcard are signed as the wildcard, RFC 4035 section 5.3.2
+    let mut owner = rrset.name.to_lowercase();
+    if owner.num_labels() > sig.labels() {
+        let mut wildcard = Name::new().label("*");
+        wildcard.append(&owner.trim_to(sig.labels() as usize));
+        owner = wildcard;
+    }
+    let mut owner_data = Vec::new();
+    emit_canonical(&owner, &mut owner_data)?;
+
+    let mut rdatas = rrset
+        .records
+        .iter()
+        .map(record_data)
+        .collect::<Option<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    let rtype = rrset.rtype.code();
+    for rdata in rdatas {
+        data.extend_from_slice(&owner_data);
+        // Type and class IN
+        data.extend_from_slice(&[(rtype >> 8) as u8, rtype as u8, 0, 1]);
+        data.extend_from_slice(sig.original_ttl());
+        data.extend_from_slice(&[(rdata.len() >> 8) as u8, rdata.len() as u8]);
+        data.extend_from_slice(&rdata);
+    }
+    Some(data)
+}
+
+fn emit_canonical(name: &Name, out: &mut Vec<u8>) -> Option<()> {
+    let mut encoder = BinEncoder::new(out);
+    name.emit_as_canonical(&mut encoder, true).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::base32hex;
+    use trust_dns::rr::rdata::{NSEC, NSEC3, NULL};
+
+    fn name(name: &str) -> Name {
+        Name::parse(name, Some(&Name::root())).unwrap()
+    }
+
+    fn proof(owner: Name, rdata: RData) -> Rrset {
+        let rtype = match rdata {
+            RData::NSEC(_) => RecordType::NSEC,
+            _ => RecordType::NSEC3,
+        };
+        Rrset {
+            name: owner.clone(),
+            rtype: rtype.into(),
+            records: vec![Record::from_rdata(owner, 3600, rtype, rdata)],
+            sigs: vec![],
+        }
+    }
+
+    // NSEC3 of the zone `com.` owning `hash`
+    fn nsec3(hash: &[u8], next: Vec<u8>, opt_out: bool, types: Vec<RecordType>) -> Rrset {
+        let label = base32hex::encode(hash).to_lowercase();
+        let owner = name(&format!("{}.com.", label));
+        let nsec3 = NSEC3::new(
+            Nsec3HashAlgorithm::SHA1,
+            opt_out,
+            1,
+            vec![0xab],
+            next,
+            types,
+        );
+        proof(owner, RData::NSEC3(nsec3))
+    }
+
+    fn hash(zone: &Name) -> Vec<u8> {
+        Nsec3HashAlgorithm::SHA1
+            .hash(&[0xab], zone, 1)
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn trust_anchor_takes_ds_and_dnskey_records() {
+        let anchor = TrustAnchor::parse(
+            "; root.key\n\
+             . 172800 IN DS 20326 8 2 e06d44b80b8f1d39a95c0b0d7c65d084 58e880409bbc683457104237c7f8ec8d\n\
+             \n\
+             . 172800 IN DNSKEY 257 3 8 AwEAAQ== ; KSK\n",
+        )
+        .unwrap();
+        assert_eq!(anchor.zone(), ".");
+        assert_eq!(anchor.ds.len(), 1);
+        assert_eq!(anchor.ds[0].key_tag(), 20326);
+        assert_eq!(anchor.ds[..], TrustAnchor::default().ds[..1]);
+        assert_eq!(anchor.dnskeys.len(), 1);
+        assert!(anchor.dnskeys[0].secure_entry_point());
+        assert!(anchor.dnskeys[0].zone_key());
+    }
+
+    #[test]
+    fn trust_anchor_rejects_other_records_and_zones() {
+        assert!(TrustAnchor::parse("").is_err());
+        assert!(TrustAnchor::parse(". 3600 IN NS a.root-servers.net.").is_err());
+        assert!(TrustAnchor::parse(". IN DS 20326 8 2 not-hex").is_err());
+        assert!(TrustAnchor::parse(". IN DS 20326 8").is_err());
+        assert!(TrustAnchor::parse(
+            ". IN DS 20326 8 2 E06D44B8\nexample.com. IN DS 370 13 2 E06D44B8"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn nsec3_span_covers_hashes_between_owner_and_next() {
+        assert!(covers(&[2], &[5], &[3]));
+        assert!(!covers(&[2], &[5], &[2]));
+        assert!(!covers(&[2], &[5], &[5]));
+        assert!(!covers(&[2], &[5], &[7]));
+        // The last NSEC3 of the zone wraps around to the first one
+        assert!(covers(&[8], &[2], &[9]));
+        assert!(covers(&[8], &[2], &[1]));
+        assert!(!covers(&[8], &[2], &[5]));
+    }
+
+    #[test]
+    fn nsec_proves_delegation_without_ds() {
+        let zone = name("example.com.");
+        let next = name("next.com.");
+        let nsec = |types| proof(zone.clone(), RData::NSEC(NSEC::new(next.clone(), types)));
+
+        let unsigned = nsec(vec![RecordType::NS, RecordType::RRSIG, RecordType::NSEC]);
+        assert_eq!(
+            insecure_delegation(&zone, &[unsigned]),
+            DnssecStatus::Insecure
+        );
+
+        let signed = nsec(vec![RecordType::NS, RecordType::DS, RecordType::RRSIG]);
+        assert_eq!(insecure_delegation(&zone, &[signed]), DnssecStatus::Bogus);
+
+        // Apex of the zone itself, not a delegation
+        let apex = nsec(vec![RecordType::NS, RecordType::SOA, RecordType::RRSIG]);
+        assert_eq!(insecure_delegation(&zone, &[apex]), DnssecStatus::Bogus);
+
+        let other = proof(
+            name("other.com."),
+            RData::NSEC(NSEC::new(next.clone(), vec![RecordType::NS])),
+        );
+        assert_eq!(insecure_delegation(&zone, &[other]), DnssecStatus::Bogus);
+    }
+
+    #[test]
+    fn nsec3_proves_delegation_without_ds() {
+        let zone = name("example.com.");
+        let hash = hash(&zone);
+        let next = vec![0xff; hash.len()];
+
+        let unsigned = nsec3(&hash, next.clone(), false, vec![RecordType::NS]);
+        assert_eq!(
+            insecure_delegation(&zone, &[unsigned]),
+            DnssecStatus::Insecure
+        );
+
+        let signed = nsec3(
+            &hash,
+            next.clone(),
+            false,
+            vec![RecordType::NS, RecordType::DS],
+        );
+        assert_eq!(insecure_delegation(&zone, &[signed]), DnssecStatus::Bogus);
+    }
+
+    #[test]
+    fn nsec3_opt_out_span_may_hold_unsigned_delegations() {
+        let zone = name("example.com.");
+        let hash = hash(&zone);
+        // Span over every hash but the lowest and the highest one
+        let before = vec![0; hash.len()];
+        let after = vec![0xff; hash.len()];
+
+        let opt_out = nsec3(&before, after.clone(), true, vec![RecordType::NS]);
+        assert_eq!(
+            insecure_delegation(&zone, &[opt_out]),
+            DnssecStatus::Insecure
+        );
+
+        let no_opt_out = nsec3(&before, after.clone(), false, vec![RecordType::NS]);
+        assert_eq!(
+            insecure_delegation(&zone, &[no_opt_out]),
+            DnssecStatus::Bogus
+        );
+    }
+
+    // Record of a type the DNS client has no `RecordType` for, as `wire` keeps it
+    fn opaque_record(owner: Name, code: u16, data: &[u8]) -> Record {
+        let mut opaque = vec![(code >> 8) as u8, code as u8];
+        opaque.extend_from_slice(data);
+        Record::from_rdata(
+            owner,
+            3600,
+            RecordType::NULL,
+            RData::NULL(NULL::with(opaque)),
+        )
+    }
+
+    #[test]
+    fn signed_data_is_made_of_record_data_as_received() {
+        // RRSIG over CAA 0 issue "ca.test" of a wildcard, both of them kept as received
+        let mut rrsig = vec![
+            1, 1, 13, 2, 0, 0, 0x0e, 0x10, 0, 0, 0, 2, 0, 0, 0, 1, 0x12, 0x34,
+        ];
+        rrsig.extend_from_slice(b"\x07Example\x03com\x00");
+        let signed_fields = rrsig.clone();
+        rrsig.extend_from_slice(&[0xab; 64]);
+
+        let owner = name("a.b.Example.com.");
+        let caa = b"\x00\x05issueca.test";
+        let records = [
+            opaque_record(owner.clone(), 257, caa),
+            opaque_record(owner.clone(), 46, &rrsig),
+        ];
+        let rrset = rrsets(&records).remove(0);
+        assert_eq!(rrset.rtype, QueryType::from(257));
+        assert_eq!(rrset.sigs.len(), 1);
+
+        let sig = &rrset.sigs[0];
+        assert_eq!(sig.type_covered(), QueryType::from(257));
+        assert_eq!(*sig.signer_name(), name("example.com."));
+        assert_eq!(sig.key_tag(), 0x1234);
+        assert_eq!(sig.signature(), &[0xab; 64][..]);
+
+        let mut expected = signed_fields[..18].to_vec();
+        expected.extend_from_slice(b"\x07example\x03com\x00");
+        expected.extend_from_slice(b"\x01*\x07example\x03com\x00");
+        expected.extend_from_slice(&[1, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, caa.len() as u8]);
+        expected.extend_from_slice(caa);
+        assert_eq!(signed_data(&rrset, sig), Some(expected));
+    }
+
+    #[test]
+    fn nsec_with_unknown_types_proves_delegation_without_ds() {
+        let zone = name("example.com.");
+        // Next owner next.com., types NS, RRSIG, NSEC and CAA
+        let mut data = b"\x04next\x03com\x00".to_vec();
+        data.extend_from_slice(&[0, 6, 0x20, 0, 0, 0, 0, 0x03, 1, 1, 0x40]);
+        let records = [opaque_record(zone.clone(), 47, &data)];
+
+        assert_eq!(
+            insecure_delegation(&zone, &rrsets(&records)),
+            DnssecStatus::Insecure
+        );
+    }
+
+    #[test]
+    fn weakest_status_wins() {
+        use self::DnssecStatus::*;
+        assert_eq!(Secure.worst(Insecure), Insecure);
+        assert_eq!(Insecure.worst(Indeterminate), Indeterminate);
+        assert_eq!(Indeterminate.worst(Bogus), Bogus);
+        assert_eq!(Secure.worst(Secure), Secure);
+    }
+
+    #[test]
+    fn strongest_signer_wins() {
+        use self::DnssecStatus::*;
+        assert_eq!(Bogus.best(Secure), Secure);
+        assert_eq!(Bogus.best(Indeterminate), Indeterminate);
+        assert_eq!(Indeterminate.best(Insecure), Insecure);
+        assert_eq!(Bogus.best(Bogus), Bogus);
+    }
+
+    #[test]
+    fn default_anchor_takes_both_root_ksks() {
+        let tags = TrustAnchor::default()
+            .ds
+            .iter()
+            .map(|ds| ds.key_tag())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, vec![20326, 38696]);
+    }
+
+    fn nsec(owner: &str, next: &str, types: Vec<RecordType>) -> Rrset {
+        proof(name(owner), RData::NSEC(NSEC::new(name(next), types)))
+    }
+
+    #[test]
+    fn nsec_proves_missing_name_and_wildcard() {
+        let qname = name("b.example.com.");
+        let a = RecordType::A.into();
+        let name_gap = nsec("a.example.com.", "c.example.com.", vec![RecordType::A]);
+        // `*.example.com.` sorts right after the apex
+        let wildcard_gap = nsec("example.com.", "a.example.com.", vec![RecordType::SOA]);
+
+        let proofs = [name_gap, wildcard_gap];
+        assert_eq!(denial_proof(&qname, a, true, &proofs), DnssecStatus::Secure);
+        assert_eq!(
+            denial_proof(&qname, a, true, &proofs[..1]),
+            DnssecStatus::Bogus
+        );
+        assert_eq!(
+            denial_proof(&name("d.example.com."), a, true, &proofs),
+            DnssecStatus::Bogus
+        );
+    }
+
+    #[test]
+    fn nsec_proves_missing_type() {
+        let qname = name("www.example.com.");
+        let www = nsec(
+            "www.example.com.",
+            "x.example.com.",
+            vec![RecordType::A, RecordType::RRSIG, RecordType::NSEC],
+        );
+        let proofs = [www];
+        let proof = |rtype: RecordType| denial_proof(&qname, rtype.into(), false, &proofs);
+
+        assert_eq!(proof(RecordType::AAAA), DnssecStatus::Secure);
+        assert_eq!(proof(RecordType::A), DnssecStatus::Bogus);
+
+        // Empty non-terminal comes right before its descendants
+        let ent = [nsec(
+            "a.example.com.",
+            "x.www.example.com.",
+            vec![RecordType::A],
+        )];
+        assert_eq!(
+            denial_proof(&qname, RecordType::A.into(), false, &ent),
+            DnssecStatus::Secure
+        );
+    }
+
+    #[test]
+    fn nsec3_proves_missing_name_from_closest_encloser() {
+        let qname = name("example.com.");
+        let a = RecordType::A.into();
+        let encloser = || {
+            nsec3(
+                &hash(&name("com.")),
+                vec![0; 20],
+                false,
+                vec![RecordType::NS],
+            )
+        };
+        // Span over every other hash covers the next closer name and the wildcard
+        let span = |opt_out| nsec3(&[0; 20], vec![0xff; 20], opt_out, vec![]);
+
+        let proofs = [encloser(), span(false)];
+        assert_eq!(denial_proof(&qname, a, true, &proofs), DnssecStatus::Secure);
+
+        let opt_out = [encloser(), span(true)];
+        assert_eq!(
+            denial_proof(&qname, a, true, &opt_out),
+            DnssecStatus::Insecure
+        );
+
+        assert_eq!(
+            denial_proof(&qname, a, true, &[span(false)]),
+            DnssecStatus::Bogus
+        );
+    }
+
+    #[test]
+    fn nsec3_proves_missing_type() {
+        let qname = name("example.com.");
+        let types = vec![RecordType::A, RecordType::RRSIG];
+        let proofs = [nsec3(&hash(&qname), vec![0; 20], false, types)];
+        let proof = |rtype: RecordType| denial_proof(&qname, rtype.into(), false, &proofs);
+
+        assert_eq!(proof(RecordType::AAAA), DnssecStatus::Secure);
+        assert_eq!(proof(RecordType::A), DnssecStatus::Bogus);
+    }
+}
@@ -1,16 +1,54 @@
 use std::error::Error;
 use std::fmt;
 
+use trust_dns::op::ResponseCode;
+
 #[derive(Debug)]
 pub enum ResolverError {
     ConnectionTimeout,
     NameServerNotResolved,
     NotFound,
+    InvalidAddress,
+    InvalidName,
+    InvalidClientSubnet(String),
+    CnameLoop,
+    CnameChainTooLong,
+    NxDomain,
+    NoData,
+    ServFail,
+    Refused,
+    /// Any other error response code
+    Rcode(ResponseCode),
+    /// Too few of the servers asked in consensus mode agreed on the answer
+    NoConsensus,
     DnsClientError(::trust_dns::error::ClientError),
 }
 
 unsafe impl Send for ResolverError {}
 
+impl ResolverError {
+    /// Short reason written to the failures file
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            ResolverError::ConnectionTimeout => "timeout",
+            ResolverError::NameServerNotResolved => "nameserver-unresolved",
+            ResolverError::NotFound => "not-found",
+            ResolverError::InvalidAddress => "invalid-address",
+            ResolverError::InvalidName => "invalid-name",
+            ResolverError::InvalidClientSubnet(_) => "invalid-subnet",
+            ResolverError::CnameLoop => "cname-loop",
+            ResolverError::CnameChainTooLong => "cname-chain-too-long",
+            ResolverError::NxDomain => "nxdomain",
+            ResolverError::NoData => "nodata",
+            ResolverError::ServFail => "servfail",
+            ResolverError::Refused => "refused",
+            ResolverError::Rcode(_) => "rcode",
+            ResolverError::NoConsensus => "no-consensus",
+            ResolverError::DnsClientError(_) => "client-error",
+        }
+    }
+}
+
 impl fmt::Display for ResolverError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.description())
@@ -23,6 +61,17 @@ impl Error for ResolverError {
             ResolverError::ConnectionTimeout => "Connection timeout",
             ResolverError::NameServerNotResolved => "Failed to resolve nameserver",
             ResolverError::NotFound => "Not found",
+            ResolverError::InvalidAddress => "Invalid IP address",
+            ResolverError::InvalidName => "Invalid domain name",
+            ResolverError::InvalidClientSubnet(ref err) => err,
+            ResolverError::CnameLoop => "CNAME loop",
+            ResolverError::CnameChainTooLong => "CNAME chain is too long",
+            ResolverError::NxDomain => "Domain does not exist (NXDOMAIN)",
+            ResolverError::NoData => "No records of the requested type (NODATA)",
+            ResolverError::ServFail => "Server failure (SERVFAIL)",
+            ResolverError::Refused => "Query refused (REFUSED)",
+            ResolverError::Rcode(ref rcode) => rcode.to_str(),
+            ResolverError::NoConsensus => "Servers disagree on the answer",
             ResolverError::DnsClientError(ref err) => err.description(),
         }
     }
@@ -0,0 +1,252 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Lazily expanded PTR input line: single address, CIDR block (`10.0.0.0/22`, `2001:db8::/120`)
+/// or an inclusive address range (`192.0.2.10-192.0.2.50`). A client subnet following the range
+/// after a comma is kept with every address.
+#[derive(Debug, Clone)]
+pub enum AddressRange {
+    Single(Option<String>),
+    V4 {
+        next: u64,
+        last: u64,
+        suffix: String,
+    },
+    V6 {
+        next: u128,
+        last: u128,
+        done: bool,
+        suffix: String,
+    },
+}
+
+impl AddressRange {
+    /// Parse input line, lines which are neither ranges nor CIDR blocks are passed through as is
+    pub fn parse(line: &str) -> Self {
+        let line = line.trim();
+        let (addr, suffix) = match line.find(',') {
+            Some(pos) => line.split_at(pos),
+            None => (line, ""),
+        };
+        let (addr, suffix) = (addr.trim(), suffix.to_owned());
+
+        let range = if addr.contains('/') {
+            Self::parse_cidr(addr, suffix)
+        } else if addr.contains('-') {
+            Self::parse_range(addr, suffix)
+        } else {
+            None
+        };
+
+        range.unwrap_or_else(|| AddressRange::Single(Some(line.to_owned())))
+    }
+
+    fn parse_cidr(line: &str, suffix: String) -> Option<Self> {
+        let mut parts = line.splitn(2, '/');
+        let addr = parts.next()?.parse::<IpAddr>().ok()?;
+        let prefix = parts.next()?.parse::<u32>().ok()?;
+
+        match addr {
+            IpAddr::V4(addr) if prefix <= 32 => {
+                let host_bits = 32 - prefix;
+                let first = u64::from(u32::from(addr)) >> host_bits << host_bits;
+                Some(AddressRange::V4 {
+                    next: first,
+                    last: first + (1 << host_bits) - 1,
+                    suffix: suffix,
+                })
+            }
+            IpAddr::V6(addr) if prefix <= 128 => {
+                let host_mask = u128::max_value().checked_shr(prefix).unwrap_or(0);
+                let first = u128::from(addr) & !host_mask;
+                Some(AddressRange::V6 {
+                    next: first,
+                    last: first | host_mask,
+                    done: false,
+                    suffix: suffix,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_range(line: &str, suffix: String) -> Option<Self> {
+        let mut parts = line.splitn(2, '-');
+        let first = parts.next()?.trim().parse::<IpAddr>().ok()?;
+        let last = parts.next()?.trim().parse::<IpAddr>().ok()?;
+
+        match (first, last) {
+            (IpAddr::V4(first), IpAddr::V4(last)) if first <= last => Some(AddressRange::V4 {
+                next: u64::from(u32::from(first)),
+                last: u64::from(u32::from(last)),
+                suffix: suffix,
+            }),
+            (IpAddr::V6(first), IpAddr::V6(last)) if first <= last => Some(AddressRange::V6 {
+                next: u128::from(first),
+                last: u128::from(last),
+                done: false,
+                suffix: suffix,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Number of addresses left in the range, saturated at `u64::max_value()`
+    pub fn size(&self) -> u64 {
+        match *self {
+            AddressRange::Single(ref line) => line.is_some() as u64,
+            AddressRange::V4 { next, last, .. } => (last + 1).saturating_sub(next),
+            AddressRange::V6 { done: true, .. } => 0,
+            AddressRange::V6 { next, last, .. } => {
+                let len = last - next;
+                if len >= u128::from(u64::max_value()) {
+                    u64::max_value()
+                } else {
+                    len as u64 + 1
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for AddressRange {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        match *self {
+            AddressRange::Single(ref mut line) => line.take(),
+            AddressRange::V4 {
+                ref mut next,
+                last,
+                ref suffix,
+            } => {
+                if *next > last {
+                    return None;
+                }
+                let addr = Ipv4Addr::from(*next as u32);
+                *next += 1;
+                Some(format!("{}{}", addr, suffix))
+            }
+            AddressRange::V6 {
+                ref mut next,
+                last,
+                ref mut done,
+                ref suffix,
+            } => {
+                if *done {
+                    return None;
+                }
+                let addr = Ipv6Addr::from(*next);
+                if *next == last {
+                    *done = true;
+                } else {
+                    *next += 1;
+                }
+                Some(format!("{}{}", addr, suffix))
+            }
+        }
+    }
+}
+
+/// Addresses of an input line, expanded as they are consumed.
+/// Ranges larger than `limit` are skipped.
+pub fn expand_line(line: &str, limit: u64) -> AddressRange {
+    let range = AddressRange::parse(line);
+    if range.size() > limit {
+        error!(
+            "{:?} expands to {} addresses which is over the limit of {}, skipping",
+            line,
+            range.size(),
+            limit
+        );
+        AddressRange::Single(None)
+    } else {
+        range
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_address_is_passed_through() {
+        let range = AddressRange::parse(" 192.0.2.1 ");
+        assert_eq!(range.size(), 1);
+        assert_eq!(range.collect::<Vec<_>>(), vec!["192.0.2.1"]);
+    }
+
+    #[test]
+    fn cidr_block_is_aligned_to_the_prefix() {
+        let range = AddressRange::parse("10.0.0.5/30");
+        assert_eq!(range.size(), 4);
+        assert_eq!(
+            range.collect::<Vec<_>>(),
+            vec!["10.0.0.4", "10.0.0.5", "10.0.0.6", "10.0.0.7"]
+        );
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let range = AddressRange::parse("2001:db8::1/126");
+        assert_eq!(
+            range.collect::<Vec<_>>(),
+            vec!["2001:db8::", "2001:db8::1", "2001:db8::2", "2001:db8::3"]
+        );
+        assert_eq!(
+            AddressRange::parse("2001:db8::/64").size(),
+            u64::max_value()
+        );
+        assert_eq!(AddressRange::parse("::/0").size(), u64::max_value());
+
+        // The last address of the space ends the iteration instead of overflowing
+        let range = AddressRange::parse("ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffe/127");
+        assert_eq!(range.count(), 2);
+    }
+
+    #[test]
+    fn range_includes_both_ends() {
+        let range = AddressRange::parse("192.0.2.254-192.0.3.1");
+        assert_eq!(range.size(), 4);
+        assert_eq!(
+            range.collect::<Vec<_>>(),
+            vec!["192.0.2.254", "192.0.2.255", "192.0.3.0", "192.0.3.1"]
+        );
+        assert_eq!(AddressRange::parse("2001:db8::1-2001:db8::1").count(), 1);
+        assert_eq!(
+            AddressRange::parse("255.255.255.255-255.255.255.255").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn invalid_ranges_are_passed_through() {
+        for line in &[
+            "192.0.2.2-192.0.2.1",
+            "192.0.2.1-2001:db8::1",
+            "10.0.0.0/33",
+            "example.com",
+        ] {
+            assert_eq!(AddressRange::parse(line).collect::<Vec<_>>(), vec![*line]);
+        }
+    }
+
+    #[test]
+    fn client_subnet_is_kept_with_every_address() {
+        let range = AddressRange::parse("10.0.0.0/31,1.2.3.0/24");
+        assert_eq!(range.size(), 2);
+        assert_eq!(
+            range.collect::<Vec<_>>(),
+            vec!["10.0.0.0,1.2.3.0/24", "10.0.0.1,1.2.3.0/24"]
+        );
+        assert_eq!(
+            AddressRange::parse("192.0.2.1,1.2.3.0/24").collect::<Vec<_>>(),
+            vec!["192.0.2.1,1.2.3.0/24"]
+        );
+    }
+
+    #[test]
+    fn ranges_over_the_limit_are_skipped() {
+        assert_eq!(expand_line("10.0.0.0/24", 256).count(), 256);
+        assert_eq!(expand_line("10.0.0.0/23", 256).count(), 0);
+    }
+}
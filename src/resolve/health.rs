@@ -0,0 +1,201 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use config::NameServer;
+
+// Attempts a healthy server is judged on
+const WINDOW_ATTEMPTS: u32 = 50;
+// Share of failed attempts a server is ejected at
+const MAX_FAILURE_RATE: f64 = 0.5;
+// Ejection time, doubled for every ejection in a row
+const EJECT_SECONDS: u64 = 5;
+const MAX_EJECT_SECONDS: u64 = 300;
+/// Queries per second sent to a server on probation, it is back once as many are answered in a row
+pub const PROBE_QUERIES: u64 = 5;
+// Weight of the last answer in the average latency
+const LATENCY_SMOOTHING: f64 = 0.1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HealthState {
+    Healthy,
+    /// Gets no queries until the time is over
+    Ejected(Instant),
+    /// Gets `PROBE_QUERIES` per second until it proves healthy or is ejected again
+    Probing,
+}
+
+impl HealthState {
+    pub fn is_ejected(&self) -> bool {
+        match *self {
+            HealthState::Ejected(_) => true,
+            _ => false,
+        }
+    }
+}
+
+struct ServerHealth {
+    server: NameServer,
+    state: HealthState,
+    // Attempts since the server was last judged
+    attempts: u32,
+    failures: u32,
+    // Ejections in a row, reset once the server is back
+    ejections: u32,
+    // Totals for the report
+    total_attempts: u64,
+    total_failures: u64,
+    total_ejections: u32,
+    latency_ms: Option<f64>,
+}
+
+impl ServerHealth {
+    fn reset(&mut self) {
+        self.attempts = 0;
+        self.failures = 0;
+    }
+}
+
+/// Success rate and latency of every attempt to query the configured servers.
+/// Servers failing most queries are ejected for a while and probed back in later.
+/// Servers are known by their position in the list, each one is locked on its own,
+/// so the workers recording attempts to different servers don't wait for each other.
+pub struct Health {
+    servers: Vec<Mutex<ServerHealth>>,
+    // Servers that are not ejected
+    up: AtomicUsize,
+}
+
+impl Health {
+    pub fn new(servers: &[NameServer]) -> Self {
+        Health {
+            servers: servers
+                .iter()
+                .map(|server| {
+                    Mutex::new(ServerHealth {
+                        server: server.clone(),
+                        state: HealthState::Healthy,
+                        attempts: 0,
+                        failures: 0,
+                        ejections: 0,
+                        total_attempts: 0,
+                        total_failures: 0,
+                        total_ejections: 0,
+                        latency_ms: None,
+                    })
+                })
+                .collect(),
+            up: AtomicUsize::new(servers.len()),
+        }
+    }
+
+    /// Record an attempt to query the server number `idx`, `latency` is none if it timed out.
+    /// Failed attempts are timeouts, client errors and answers the server gave up on.
+    pub fn record(&self, idx: usize, latency: Option<Duration>, failed: bool) {
+        let mut health = self.servers[idx].lock().unwrap();
+        health.total_attempts += 1;
+        if let Some(latency) = latency {
+            let ms =
+                latency.as_secs() as f64 * 1000.0 + f64::from(latency.subsec_nanos()) / 1_000_000.0;
+            health.latency_ms = Some(
+                health
+                    .latency_ms
+                    .map_or(ms, |avg| avg + (ms - avg) * LATENCY_SMOOTHING),
+            );
+        }
+        if failed {
+            health.total_failures += 1;
+        }
+
+        // Late outcomes of queries sent before the ejection
+        if health.state.is_ejected() {
+            return;
+        }
+
+        health.attempts += 1;
+        if failed {
+            health.failures += 1;
+        }
+
+        match health.state {
+            HealthState::Probing if health.failures > 0 => self.eject(&mut health),
+            HealthState::Probing if u64::from(health.attempts) >= PROBE_QUERIES => {
+                info!("{} answers again, bringing it back", health.server);
+                health.state = HealthState::Healthy;
+                health.ejections = 0;
+                health.reset();
+            }
+            HealthState::Healthy if health.attempts >= WINDOW_ATTEMPTS => {
+                if f64::from(health.failures) / f64::from(health.attempts) > MAX_FAILURE_RATE {
+                    self.eject(&mut health);
+                } else {
+                    health.reset();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// State of the server number `idx`, ejected servers go on probation once their time is over
+    pub fn state(&self, idx: usize) -> HealthState {
+        let mut health = self.servers[idx].lock().unwrap();
+        if let HealthState::Ejected(until) = health.state {
+            if Instant::now() >= until {
+                info!("Probing {}", health.server);
+                health.state = HealthState::Probing;
+                health.reset();
+                self.up.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        health.state
+    }
+
+    fn eject(&self, health: &mut ServerHealth) {
+        // The last server standing is kept, there is nowhere else to send queries
+        let mut up = self.up.load(Ordering::SeqCst);
+        loop {
+            if up <= 1 {
+                health.reset();
+                return;
+            }
+            match self
+                .up
+                .compare_exchange(up, up - 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(current) => up = current,
+            }
+        }
+
+        let seconds = (EJECT_SECONDS << health.ejections.min(16)).min(MAX_EJECT_SECONDS);
+        warn!(
+            "{} of {} queries to {} failed, ejecting it for {}s",
+            health.failures, health.attempts, health.server, seconds
+        );
+        health.state = HealthState::Ejected(Instant::now() + Duration::from_secs(seconds));
+        health.ejections += 1;
+        health.total_ejections += 1;
+        health.reset();
+    }
+
+    /// Log success rate, average latency and ejections of every server
+    pub fn report(&self) {
+        for health in &self.servers {
+            let health = health.lock().unwrap();
+            if health.total_attempts == 0 {
+                continue;
+            }
+            let succeeded = health.total_attempts - health.total_failures;
+            info!(
+                "{}: {:.1}% of {} queries succeeded, {} latency, ejected {} times",
+                health.server,
+                succeeded as f64 * 100.0 / health.total_attempts as f64,
+                health.total_attempts,
+                health
+                    .latency_ms
+                    .map_or("unknown".to_owned(), |ms| format!("{:.0}ms", ms)),
+                health.total_ejections
+            );
+        }
+    }
+}
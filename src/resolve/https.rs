@@ -0,0 +1,208 @@
+use std::io;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use data_encoding::base64url;
+use futures::future;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+use futures::{Future, Poll, Stream};
+use h2;
+use h2::client::{self, SendRequest};
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Request, StatusCode};
+use openssl::ssl::SslConnector;
+use tokio_core::net::TcpStream as TokioTcpStream;
+use tokio_core::reactor::Handle;
+use tokio_openssl::SslConnectorExt;
+
+use config::HttpMethod;
+use resolve::wire::{make_wire_client, WireClientHandle};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Stream of wireformat DNS responses received from DNS-over-HTTPS server
+pub struct HttpsClientStream {
+    responses: UnboundedReceiver<Vec<u8>>,
+}
+
+impl Stream for HttpsClientStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        self.responses
+            .poll()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "response channel closed"))
+    }
+}
+
+/// RFC 8484 DNS-over-HTTPS client over a single HTTP/2 connection, kept open for as long as
+/// the client handle is.
+///
+/// Every DNS message sent through the client handle becomes a separate HTTP/2 request with
+/// message id 0 for HTTP caches, the id is put back into the response for the client
+/// to match it with the query.
+pub fn make_https_client(
+    loop_handle: Handle,
+    name_server: SocketAddr,
+    host: String,
+    url: String,
+    method: HttpMethod,
+    connector: SslConnector,
+) -> WireClientHandle {
+    let (queries_tx, queries_rx) = unbounded::<Vec<u8>>();
+    let (responses_tx, responses_rx) = unbounded();
+    let handle = loop_handle.clone();
+
+    let stream = TokioTcpStream::connect(&name_server, &loop_handle)
+        .and_then(move |tcp| {
+            connector
+                .connect_async(&host, tcp)
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, format!("tls error: {}", e)))
+        })
+        .and_then(|tls| client::handshake(tls).map_err(h2_error))
+        .map(move |(send_request, connection)| {
+            handle.spawn(connection.map_err(|e| debug!("DoH connection closed: {}", e)));
+
+            let requests_handle = handle.clone();
+            handle.spawn(queries_rx.for_each(move |mut query| {
+                let responses_tx = responses_tx.clone();
+                let id = take_id(&mut query);
+                let request = send_query(send_request.clone(), url.clone(), method, query)
+                    .map(move |mut response| {
+                        put_id(&mut response, id);
+                        responses_tx.unbounded_send(response).unwrap_or(())
+                    })
+                    .map_err(|e| warn!("DoH request failed: {}", e));
+                requests_handle.spawn(request);
+                Ok(())
+            }));
+
+            HttpsClientStream {
+                responses: responses_rx,
+            }
+        });
+
+    make_wire_client(Box::new(stream), Box::new(queries_tx), loop_handle)
+}
+
+// Send single DNS query as HTTP/2 request and collect response body
+fn send_query(
+    send_request: SendRequest<Bytes>,
+    url: String,
+    method: HttpMethod,
+    query: Vec<u8>,
+) -> Box<Future<Item = Vec<u8>, Error = io::Error>> {
+    let request = match method {
+        HttpMethod::Get => {
+            let uri = get_uri(&url, &query);
+            Request::get(uri.as_str()).header(ACCEPT, DNS_MESSAGE).body(())
+        }
+        HttpMethod::Post => Request::post(url.as_str())
+            .header(ACCEPT, DNS_MESSAGE)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(()),
+    };
+
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return Box::new(future::err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid DoH request: {}", e),
+            )))
+        }
+    };
+
+    let response = send_request
+        .ready()
+        .and_then(move |mut send_request| {
+            let end_of_stream = method == HttpMethod::Get;
+            let (response, mut body) = send_request.send_request(request, end_of_stream)?;
+            if method == HttpMethod::Post {
+                body.send_data(Bytes::from(query), true)?;
+            }
+            Ok(response)
+        })
+        .and_then(|response| response)
+        .map_err(h2_error)
+        .and_then(|response| {
+            let status = response.status();
+            let mut body = response.into_body();
+
+            // Received data has to be released back to the flow control window
+            let mut release = body.release_capacity().clone();
+            body.map_err(h2_error)
+                .fold(Vec::new(), move |mut message, chunk| {
+                    release.release_capacity(chunk.len()).map_err(h2_error)?;
+                    message.extend_from_slice(&chunk);
+                    Ok::<_, io::Error>(message)
+                })
+                .and_then(move |message| {
+                    if status == StatusCode::OK {
+                        Ok(message)
+                    } else {
+                        Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("unexpected HTTP status {}", status),
+                        ))
+                    }
+                })
+        });
+
+    Box::new(response)
+}
+
+// Query goes base64url encoded in the `dns` parameter, after the ones already in the url
+fn get_uri(url: &str, query: &[u8]) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}dns={}", url, separator, base64url::encode_nopad(query))
+}
+
+// Message id of the query, zeroed in it (RFC 8484 section 4.1)
+fn take_id(message: &mut [u8]) -> [u8; 2] {
+    let mut id = [0; 2];
+    if message.len() >= 2 {
+        id.copy_from_slice(&message[..2]);
+        message[..2].copy_from_slice(&[0, 0]);
+    }
+    id
+}
+
+fn put_id(message: &mut [u8], id: [u8; 2]) {
+    if message.len() >= 2 {
+        message[..2].copy_from_slice(&id);
+    }
+}
+
+fn h2_error(err: h2::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("http2 error: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_query_is_added_to_url_parameters() {
+        assert_eq!(
+            get_uri("https://dns.google/dns-query", &[0, 0, 1]),
+            "https://dns.google/dns-query?dns=AAAB"
+        );
+        assert_eq!(
+            get_uri("https://doh.example/q?key=1", &[0, 0, 1]),
+            "https://doh.example/q?key=1&dns=AAAB"
+        );
+    }
+
+    #[test]
+    fn message_id_is_zero_on_the_wire_and_restored_in_the_response() {
+        let mut query = vec![0x12, 0x34, 1, 0];
+        let id = take_id(&mut query);
+        assert_eq!(query, vec![0, 0, 1, 0]);
+
+        let mut response = vec![0, 0, 0x81, 0x80];
+        put_id(&mut response, id);
+        assert_eq!(response, vec![0x12, 0x34, 0x81, 0x80]);
+    }
+}
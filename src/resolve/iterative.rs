@@ -0,0 +1,248 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use trust_dns::op::Message;
+use trust_dns::rr::domain::Name;
+use trust_dns::rr::RData;
+
+/// Addresses of the root servers a.root-servers.net through m.root-servers.net, IPv4 first
+const ROOT_HINTS: &[&str] = &[
+    "198.41.0.4",
+    "170.247.170.2",
+    "192.33.4.12",
+    "199.7.91.13",
+    "192.203.230.10",
+    "192.5.5.241",
+    "192.112.36.4",
+    "198.97.190.53",
+    "192.36.148.17",
+    "192.58.128.30",
+    "193.0.14.129",
+    "199.7.83.42",
+    "202.12.27.33",
+    "2001:503:ba3e::2:30",
+    "2801:1b8:10::b",
+    "2001:500:2::c",
+    "2001:500:2d::d",
+    "2001:500:a8::e",
+    "2001:500:2f::f",
+    "2001:500:12::d0d",
+    "2001:500:1::53",
+    "2001:7fe::53",
+    "2001:503:c27::2:30",
+    "2001:7fd::1",
+    "2001:500:9f::42",
+    "2001:dc3::35",
+];
+
+/// Delegation cache shared by all resolver threads of a batch
+pub type SharedDelegationCache = Arc<RwLock<DelegationCache>>;
+
+/// Nameserver addresses of zones discovered while following referrals, until their TTL runs out.
+/// Zones are keyed by lowercase name, `Name` itself can't be sent between threads.
+pub struct DelegationCache {
+    zones: HashMap<String, Delegation>,
+}
+
+struct Delegation {
+    servers: Vec<SocketAddr>,
+    /// Root hints never expire
+    expires: Option<Instant>,
+}
+
+impl DelegationCache {
+    /// Cache holding only the root zone from built-in root hints
+    pub fn new() -> Self {
+        let root_servers = ROOT_HINTS
+            .iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 53))
+            .collect();
+
+        let mut zones = HashMap::new();
+        zones.insert(
+            Name::root().to_string(),
+            Delegation {
+                servers: root_servers,
+                expires: None,
+            },
+        );
+
+        DelegationCache { zones: zones }
+    }
+
+    pub fn shared() -> SharedDelegationCache {
+        Arc::new(RwLock::new(Self::new()))
+    }
+
+    /// Closest enclosing zone of `name` with an unexpired delegation, and its nameservers
+    pub fn closest(&self, name: &Name) -> (Name, Vec<SocketAddr>) {
+        let now = Instant::now();
+        let name = name.to_lowercase();
+        for labels in (0..name.num_labels() as usize + 1).rev() {
+            let zone = name.trim_to(labels);
+            match self.zones.get(&zone.to_string()) {
+                Some(delegation) if delegation.expires.map_or(true, |expires| expires > now) => {
+                    return (zone, delegation.servers.clone());
+                }
+                _ => (),
+            }
+        }
+        unreachable!("root zone is always cached")
+    }
+
+    /// Delegation of `zone` to `servers` for `ttl` seconds
+    pub fn insert(&mut self, zone: &Name, servers: Vec<SocketAddr>, ttl: u32) {
+        let delegation = Delegation {
+            servers: servers,
+            expires: Some(Instant::now() + Duration::from_secs(ttl as u64)),
+        };
+        self.zones
+            .insert(zone.to_lowercase().to_string(), delegation);
+    }
+}
+
+/// Delegation of a zone closer to the queried name than the one asked
+pub struct Referral {
+    pub zone: Name,
+    pub ns_names: Vec<Name>,
+    /// Addresses of nameservers from the additional section, IPv4 first
+    pub glue: Vec<SocketAddr>,
+    /// Lowest TTL of the NS records and the glue
+    pub ttl: u32,
+}
+
+impl Referral {
+    /// Referral is a response without answers that names nameservers of a subzone of `zone`
+    /// enclosing `name`. Anything else is final: an answer, NXDOMAIN or NODATA.
+    pub fn from_message(message: &Message, name: &Name, zone: &Name) -> Option<Self> {
+        if !message.answers().is_empty() {
+            return None;
+        }
+
+        let mut child = None;
+        let mut ns_names = Vec::new();
+        let mut ttl = u32::max_value();
+        for record in message.name_servers() {
+            if let RData::NS(ref ns) = *record.rdata() {
+                let owner = record.name();
+                if owner.zone_of(name) && owner.num_labels() > zone.num_labels() {
+                    child = Some(owner.clone());
+                    ns_names.push(ns.clone());
+                    ttl = cmp::min(ttl, record.ttl());
+                }
+            }
+        }
+        let zone = child?;
+
+        let mut glue = Vec::new();
+        for record in message.additionals() {
+            // Addresses out of the delegated zone are not the parent's to tell
+            if !ns_names.iter().any(|ns| ns == record.name()) || !zone.zone_of(record.name()) {
+                continue;
+            }
+            let addr = match *record.rdata() {
+                RData::A(ip) => SocketAddr::new(IpAddr::V4(ip), 53),
+                RData::AAAA(ip) => SocketAddr::new(IpAddr::V6(ip), 53),
+                _ => continue,
+            };
+            ttl = cmp::min(ttl, record.ttl());
+            if !glue.contains(&addr) {
+                glue.push(addr);
+            }
+        }
+        glue.sort_by_key(SocketAddr::is_ipv6);
+
+        Some(Referral {
+            zone: zone,
+            ns_names: ns_names,
+            glue: glue,
+            ttl: ttl,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use trust_dns::rr::{Record, RecordType};
+
+    fn name(name: &str) -> Name {
+        Name::parse(name, Some(&Name::root())).unwrap()
+    }
+
+    fn record(owner: &str, ttl: u32, rdata: RData) -> Record {
+        let rtype = match rdata {
+            RData::NS(_) => RecordType::NS,
+            RData::A(_) => RecordType::A,
+            _ => RecordType::AAAA,
+        };
+        Record::from_rdata(name(owner), ttl, rtype, rdata)
+    }
+
+    #[test]
+    fn referral_keeps_only_glue_within_the_delegated_zone() {
+        let mut message = Message::new();
+        message
+            .add_name_server(record(
+                "example.com.",
+                3600,
+                RData::NS(name("ns1.example.com.")),
+            ))
+            .add_name_server(record(
+                "example.com.",
+                7200,
+                RData::NS(name("ns.other.net.")),
+            ))
+            .add_additional(record(
+                "ns1.example.com.",
+                600,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .add_additional(record(
+                "ns1.example.com.",
+                600,
+                RData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ))
+            .add_additional(record(
+                "ns.other.net.",
+                60,
+                RData::A(Ipv4Addr::new(198, 51, 100, 1)),
+            ));
+
+        let referral =
+            Referral::from_message(&message, &name("www.example.com."), &name("com.")).unwrap();
+        assert_eq!(referral.zone, name("example.com."));
+        assert_eq!(referral.ns_names.len(), 2);
+        assert_eq!(
+            referral.glue,
+            vec![
+                "192.0.2.1:53".parse().unwrap(),
+                "[2001:db8::1]:53".parse().unwrap(),
+            ]
+        );
+        assert_eq!(referral.ttl, 600);
+    }
+
+    #[test]
+    fn delegations_expire_with_their_ttl() {
+        let mut cache = DelegationCache::new();
+        let servers = vec!["192.0.2.1:53".parse().unwrap()];
+        cache.insert(&name("example.com."), servers.clone(), 3600);
+        cache.insert(&name("expired.com."), servers.clone(), 0);
+
+        assert_eq!(
+            cache.closest(&name("www.Example.com.")),
+            (name("example.com."), servers)
+        );
+        let (zone, root_servers) = cache.closest(&name("www.expired.com."));
+        assert!(zone.is_root());
+        assert_eq!(root_servers.len(), ROOT_HINTS.len());
+        assert!(root_servers.contains(&"170.247.170.2:53".parse().unwrap()));
+    }
+}
@@ -1,7 +1,22 @@
 pub mod batch;
+mod bloom;
+pub mod client_subnet;
+mod consensus;
+pub mod dnssec;
 pub mod error;
+pub mod expand;
+mod health;
+mod https;
+pub mod iterative;
+pub mod qualify;
+pub mod query_type;
 pub mod resolver;
 mod resolver_threadpool;
+mod upstream;
+mod wire;
 
 pub use batch::*;
+pub use client_subnet::ClientSubnet;
+pub use dnssec::TrustAnchor;
+pub use query_type::QueryType;
 pub use resolver::*;
@@ -0,0 +1,244 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::Future;
+use tokio_core::reactor::Core;
+
+use trust_dns::op::{Message, ResponseCode};
+use trust_dns::rr::{RData, RecordType};
+
+use config::{NameServer, Upstream, CONFIG};
+use resolve::error::ResolverError;
+use resolve::iterative::DelegationCache;
+use resolve::resolver::TrustDNSResolver;
+use resolve::upstream::Upstreams;
+
+// Tries for every check, dead servers shouldn't hold the batch for long
+const CHECK_TRIES: u32 = 2;
+// Zones without wildcards, random names under them don't exist
+const NXDOMAIN_ZONES: &[&str] = &["com", "net", "org"];
+
+/// Outcome of the checks of a server, the details tell the name and what was wrong with the answer
+pub enum Verdict {
+    Passed,
+    /// Did not answer
+    Dead(String),
+    /// Answered with an error
+    Failed(String),
+    /// Answered a known name with addresses it does not have
+    Poisoned(String),
+    /// Answered a nonexistent name with anything but NXDOMAIN
+    HijacksNxdomain(String),
+}
+
+impl Verdict {
+    pub fn passed(&self) -> bool {
+        match *self {
+            Verdict::Passed => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Verdict::Passed => write!(f, "passed"),
+            Verdict::Dead(ref details) => write!(f, "dead\t{}", details),
+            Verdict::Failed(ref details) => write!(f, "failed\t{}", details),
+            Verdict::Poisoned(ref details) => write!(f, "poisoned\t{}", details),
+            Verdict::HijacksNxdomain(ref details) => write!(f, "hijacks-nxdomain\t{}", details),
+        }
+    }
+}
+
+type Check = Box<Future<Item = (), Error = Verdict>>;
+
+/// Query every configured server for the known names and for random nonexistent ones.
+/// Servers are checked a batch a second to stay within the configured QPS, verdicts are in `dns_list` order.
+pub fn qualify() -> Vec<(Upstream, Verdict)> {
+    let (upstreams, known, qps) = {
+        let config = CONFIG.read().unwrap();
+        (
+            config.dns_list().to_vec(),
+            config.qualify_names().to_vec(),
+            config.qps() as usize,
+        )
+    };
+    let nonexistent = NXDOMAIN_ZONES
+        .iter()
+        .map(|zone| random_name(zone))
+        .collect::<Vec<_>>();
+
+    let mut core = Core::new().unwrap();
+    // Checks report no progress, the receiver only keeps the channel open
+    let (status_tx, _status_rx) = mpsc::channel();
+    // Retries stay with the server being checked
+    let resolver = TrustDNSResolver::new(
+        core.handle(),
+        status_tx,
+        DelegationCache::shared(),
+        Upstreams::shared(&[]),
+    )
+    .unwrap_or_else(|err| {
+        error!("failed to set up the resolver: {}", err);
+        std::process::exit(1);
+    });
+
+    let queries_per_server = known
+        .iter()
+        .map(|&(_, ref addrs)| query_types(addrs).len())
+        .sum::<usize>()
+        + nonexistent.len();
+    let servers_at_once = (qps / queries_per_server.max(1)).max(1);
+
+    let mut verdicts = Vec::new();
+    for upstreams in upstreams.chunks(servers_at_once) {
+        let started = Instant::now();
+        let checks = upstreams
+            .iter()
+            .map(|upstream| {
+                let dns = upstream.server.clone();
+                let mut checks = Vec::new();
+                for &(ref name, ref addrs) in &known {
+                    for rtype in query_types(addrs) {
+                        checks.push(check_known(&resolver, dns.clone(), name, rtype, addrs));
+                    }
+                }
+                for name in &nonexistent {
+                    checks.push(check_nonexistent(&resolver, dns.clone(), name));
+                }
+
+                // First failed check is the verdict
+                future::join_all(checks).then(|rv| {
+                    Ok::<_, ()>(match rv {
+                        Ok(_) => Verdict::Passed,
+                        Err(verdict) => verdict,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let chunk = core.run(future::join_all(checks)).unwrap();
+        verdicts.extend(upstreams.iter().cloned().zip(chunk));
+
+        // Fast servers answer well within a second, the next batch waits for it to pass
+        let elapsed = started.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            thread::sleep(Duration::from_secs(1) - elapsed);
+        }
+    }
+
+    verdicts
+}
+
+// Address families of the known name to ask for
+fn query_types(addrs: &[IpAddr]) -> Vec<RecordType> {
+    let mut rtypes = Vec::new();
+    if addrs.iter().any(IpAddr::is_ipv4) {
+        rtypes.push(RecordType::A);
+    }
+    if addrs.iter().any(IpAddr::is_ipv6) {
+        rtypes.push(RecordType::AAAA);
+    }
+    rtypes
+}
+
+// Random label is different for every run, so no cache can have it
+fn random_name(zone: &str) -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    format!("qualify-{:016x}.{}", hasher.finish(), zone)
+}
+
+// Answer must hold some of the known addresses and nothing else
+fn check_known(
+    resolver: &TrustDNSResolver,
+    dns: NameServer,
+    name: &str,
+    rtype: RecordType,
+    addrs: &[IpAddr],
+) -> Check {
+    let name = name.to_owned();
+    let addrs = addrs.to_vec();
+
+    Box::new(
+        resolver
+            .query(dns, &name, rtype, CHECK_TRIES)
+            .then(move |rv| {
+                let message = rv.map_err(|err| error_verdict(&name, rtype, &err))?;
+                if message.response_code() != ResponseCode::NoError {
+                    return Err(Verdict::Failed(format!(
+                        "{} {:?} {}",
+                        name,
+                        rtype,
+                        rcode_name(message.response_code())
+                    )));
+                }
+
+                let answered = addresses(&message);
+                if answered.is_empty() {
+                    return Err(Verdict::Failed(format!("{} {:?} no answer", name, rtype)));
+                }
+                match answered.iter().find(|addr| !addrs.contains(addr)) {
+                    Some(addr) => Err(Verdict::Poisoned(format!("{} {:?} {}", name, rtype, addr))),
+                    None => Ok(()),
+                }
+            }),
+    )
+}
+
+// Nonexistent name must get NXDOMAIN, a NOERROR means the server makes up answers
+fn check_nonexistent(resolver: &TrustDNSResolver, dns: NameServer, name: &str) -> Check {
+    let name = name.to_owned();
+
+    Box::new(
+        resolver
+            .query(dns, &name, RecordType::A, CHECK_TRIES)
+            .then(move |rv| {
+                let message = rv.map_err(|err| error_verdict(&name, RecordType::A, &err))?;
+                match message.response_code() {
+                    ResponseCode::NXDomain => Ok(()),
+                    ResponseCode::NoError => {
+                        let answer = addresses(&message)
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(",");
+                        Err(Verdict::HijacksNxdomain(format!("{} A {}", name, answer)))
+                    }
+                    rcode => Err(Verdict::Failed(format!("{} A {}", name, rcode_name(rcode)))),
+                }
+            }),
+    )
+}
+
+fn error_verdict(name: &str, rtype: RecordType, err: &ResolverError) -> Verdict {
+    let details = format!("{} {:?} {}", name, rtype, err.reason());
+    match *err {
+        ResolverError::ConnectionTimeout => Verdict::Dead(details),
+        _ => Verdict::Failed(details),
+    }
+}
+
+fn rcode_name(rcode: ResponseCode) -> String {
+    format!("{:?}", rcode).to_uppercase()
+}
+
+fn addresses(message: &Message) -> Vec<IpAddr> {
+    message
+        .answers()
+        .iter()
+        .filter_map(|record| match *record.rdata() {
+            RData::A(ip) => Some(IpAddr::V4(ip)),
+            RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        })
+        .collect()
+}
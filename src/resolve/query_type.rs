@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use trust_dns::rr::{Record, RecordType};
+
+use resolve::wire::{decodable, opaque};
+
+// IANA DNS resource record type registry, mnemonic to type code
+static IANA_TYPES: &[(&str, u16)] = &[
+    ("A", 1),
+    ("NS", 2),
+    ("MD", 3),
+    ("MF", 4),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("MB", 7),
+    ("MG", 8),
+    ("MR", 9),
+    ("NULL", 10),
+    ("WKS", 11),
+    ("PTR", 12),
+    ("HINFO", 13),
+    ("MINFO", 14),
+    ("MX", 15),
+    ("TXT", 16),
+    ("RP", 17),
+    ("AFSDB", 18),
+    ("X25", 19),
+    ("ISDN", 20),
+    ("RT", 21),
+    ("NSAP", 22),
+    ("NSAP-PTR", 23),
+    ("SIG", 24),
+    ("KEY", 25),
+    ("PX", 26),
+    ("GPOS", 27),
+    ("AAAA", 28),
+    ("LOC", 29),
+    ("NXT", 30),
+    ("EID", 31),
+    ("NIMLOC", 32),
+    ("SRV", 33),
+    ("ATMA", 34),
+    ("NAPTR", 35),
+    ("KX", 36),
+    ("CERT", 37),
+    ("A6", 38),
+    ("DNAME", 39),
+    ("SINK", 40),
+    ("OPT", 41),
+    ("APL", 42),
+    ("DS", 43),
+    ("SSHFP", 44),
+    ("IPSECKEY", 45),
+    ("RRSIG", 46),
+    ("NSEC", 47),
+    ("DNSKEY", 48),
+    ("DHCID", 49),
+    ("NSEC3", 50),
+    ("NSEC3PARAM", 51),
+    ("TLSA", 52),
+    ("SMIMEA", 53),
+    ("HIP", 55),
+    ("NINFO", 56),
+    ("RKEY", 57),
+    ("TALINK", 58),
+    ("CDS", 59),
+    ("CDNSKEY", 60),
+    ("OPENPGPKEY", 61),
+    ("CSYNC", 62),
+    ("ZONEMD", 63),
+    ("SVCB", 64),
+    ("HTTPS", 65),
+    ("SPF", 99),
+    ("UINFO", 100),
+    ("UID", 101),
+    ("GID", 102),
+    ("UNSPEC", 103),
+    ("NID", 104),
+    ("L32", 105),
+    ("L64", 106),
+    ("LP", 107),
+    ("EUI48", 108),
+    ("EUI64", 109),
+    ("TKEY", 249),
+    ("TSIG", 250),
+    ("IXFR", 251),
+    ("AXFR", 252),
+    ("MAILB", 253),
+    ("MAILA", 254),
+    ("ANY", 255),
+    ("*", 255),
+    ("URI", 256),
+    ("CAA", 257),
+    ("AVC", 258),
+    ("DOA", 259),
+    ("AMTRELAY", 260),
+    ("TA", 32768),
+    ("DLV", 32769),
+];
+
+/// Query type accepted either as an IANA mnemonic (`MX`) or as RFC 3597 numeric code (`TYPE15`).
+/// Types the DNS client has no `RecordType` for are sent and received in the wire format.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QueryType(u16);
+
+impl QueryType {
+    /// Type code as sent on the wire
+    pub fn code(self) -> u16 {
+        self.0
+    }
+
+    /// Type as the DNS client knows it, `None` for the ones sent in the wire format
+    pub fn record_type(self) -> Option<RecordType> {
+        if decodable(self.0) {
+            RecordType::from_u16(self.0).ok()
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mnemonic = s.trim().to_uppercase();
+
+        let code = if mnemonic.starts_with("TYPE") {
+            mnemonic[4..]
+                .parse::<u16>()
+                .map_err(|_| format!("invalid numeric record type {:?}", s))?
+        } else {
+            IANA_TYPES
+                .iter()
+                .find(|&&(name, _)| name == mnemonic)
+                .map(|&(_, code)| code)
+                .ok_or_else(|| format!("unknown record type {:?}", s))?
+        };
+
+        // Type 0 is reserved, the DNS client uses it for records it can not decode
+        if code == 0 {
+            return Err(format!("record type {} is reserved", s));
+        }
+        Ok(QueryType(code))
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match IANA_TYPES.iter().find(|&&(_, code)| code == self.0) {
+            Some(&(mnemonic, _)) => write!(f, "{}", mnemonic),
+            None => write!(f, "TYPE{}", self.0),
+        }
+    }
+}
+
+impl From<u16> for QueryType {
+    fn from(code: u16) -> Self {
+        QueryType(code)
+    }
+}
+
+impl From<RecordType> for QueryType {
+    fn from(rtype: RecordType) -> Self {
+        QueryType(rtype.into())
+    }
+}
+
+impl<'a> From<&'a Record> for QueryType {
+    fn from(record: &'a Record) -> Self {
+        match opaque(record) {
+            Some((code, _)) => QueryType(code),
+            None => record.rr_type().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics_and_numeric_codes_are_the_same_type() {
+        let caa = "caa".parse::<QueryType>().unwrap();
+        assert_eq!(caa, "TYPE257".parse().unwrap());
+        assert_eq!(caa.code(), 257);
+        assert_eq!(caa.to_string(), "CAA");
+        assert_eq!(
+            "TYPE65280".parse::<QueryType>().unwrap().to_string(),
+            "TYPE65280"
+        );
+        assert!("TYPE0".parse::<QueryType>().is_err());
+        assert!("FOO".parse::<QueryType>().is_err());
+    }
+
+    #[test]
+    fn types_without_record_type_go_in_the_wire_format() {
+        let record_type = |s: &str| s.parse::<QueryType>().unwrap().record_type();
+        assert_eq!(record_type("MX"), Some(RecordType::MX));
+        assert_eq!(record_type("ANY"), Some(RecordType::ANY));
+        for s in &["HTTPS", "SVCB", "CAA", "NULL", "TLSA"] {
+            assert_eq!(record_type(s), None);
+        }
+    }
+}
@@ -1,53 +1,399 @@
 use std::borrow::Borrow;
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::str;
+use std::time::Instant;
 
 use futures::future;
 use futures::future::Loop;
 use futures::Future;
 use tokio_core::reactor::Handle;
 
-use trust_dns::client::{BasicClientHandle, ClientFuture, ClientHandle};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod};
+use openssl::x509::X509;
+
 use trust_dns::error::ClientError;
 use trust_dns::error::ClientErrorKind;
 use trust_dns::op::message::Message;
+use trust_dns::op::{Edns, MessageType, OpCode, Query, ResponseCode};
 use trust_dns::rr::dns_class::DNSClass;
 use trust_dns::rr::domain::Name;
+use trust_dns::rr::rdata::opt::{EdnsCode, EdnsOption};
 use trust_dns::rr::record_type::RecordType;
 use trust_dns::rr::resource::Record;
+use trust_dns::tcp::TcpClientStream;
+use trust_dns::tls::TlsClientStream;
 use trust_dns::udp::UdpClientStream;
 
-use config::CONFIG;
-use resolve::batch::{QueryType, ResolveStatus, StatusTx};
+use config::{ConfigResult, NameServer, Transport, CONFIG};
+use resolve::batch::{AnswerRecord, ResolveStatus, Resolved, Response, StatusTx};
+use resolve::client_subnet::{self, ClientSubnet};
+use resolve::dnssec::{DnsQuery, DnssecStatus, Validator};
 use resolve::error::*;
+use resolve::https::make_https_client;
+use resolve::iterative::{Referral, SharedDelegationCache};
+use resolve::query_type::QueryType;
+use resolve::upstream::{InFlight, SharedUpstreams, Upstreams};
+use resolve::wire::{make_wire_client, opaque, WireClientHandle};
 
-fn make_client(loop_handle: Handle, name_server: SocketAddr) -> BasicClientHandle {
+fn make_client(loop_handle: Handle, name_server: SocketAddr) -> WireClientHandle {
     let (stream, stream_handle) = UdpClientStream::new(name_server, loop_handle.clone());
 
-    ClientFuture::new(stream, stream_handle, loop_handle, None)
+    make_wire_client(stream, stream_handle, loop_handle)
+}
+
+fn make_tcp_client(loop_handle: Handle, name_server: SocketAddr) -> WireClientHandle {
+    let (stream, stream_handle) = TcpClientStream::new(name_server, loop_handle.clone());
+
+    make_wire_client(stream, stream_handle, loop_handle)
+}
+
+// DNS-over-TLS client, SNI and certificate verification are done against `tls_name`
+fn make_tls_client(
+    loop_handle: Handle,
+    name_server: SocketAddr,
+    tls_name: String,
+    ca: &[X509],
+) -> WireClientHandle {
+    let mut builder = TlsClientStream::builder();
+    for cert in ca {
+        builder.add_ca(cert.clone());
+    }
+    let (stream, stream_handle) = builder.build(name_server, tls_name, loop_handle.clone());
+
+    make_wire_client(stream, stream_handle, loop_handle)
+}
+
+// CA bundles of common distributions, as OpenSSL is built to look for them
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+    "/usr/local/etc/openssl/cert.pem",
+];
+
+// Certificates DNS-over-TLS servers are verified against. trust-dns trusts only the ones it is given,
+// so the system bundle, or the one in SSL_CERT_FILE, goes along with the ones from config.
+fn tls_ca_certs(ca_pem: Option<&[u8]>) -> ConfigResult<Vec<X509>> {
+    let bundle = env::var_os("SSL_CERT_FILE")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(SYSTEM_CA_BUNDLES.iter().map(PathBuf::from))
+        .filter_map(|path| fs::read(&path).ok().map(|pem| (path, pem)))
+        .next();
+
+    let mut certs = match bundle {
+        Some((path, pem)) => X509::stack_from_pem(&pem).unwrap_or_else(|err| {
+            warn!(
+                "failed to read system certificates from {:?}: {}",
+                path, err
+            );
+            vec![]
+        }),
+        None => vec![],
+    };
+    if let Some(ca_pem) = ca_pem {
+        certs.extend(X509::stack_from_pem(ca_pem)?);
+    }
+    Ok(certs)
+}
+
+// DNS-over-HTTPS connector trusting system certificates and the ones from config
+fn make_tls_connector(ca_pem: Option<&[u8]>, alpn: &[&[u8]]) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls())?;
+    if !alpn.is_empty() {
+        builder.builder_mut().set_alpn_protocols(alpn)?;
+    }
+    if let Some(ca_pem) = ca_pem {
+        for ca in X509::stack_from_pem(ca_pem)? {
+            builder.builder_mut().cert_store_mut().add_cert(ca)?;
+        }
+    }
+    Ok(builder.build())
+}
+
+// What is known about the query of an input line besides the answer values
+#[derive(Default)]
+struct QueryStats {
+    sent: u32,
+    last_server: Option<NameServer>,
+    // Taken when the answer arrives, later lookups for DNSSEC validation don't count
+    attempts: u32,
+    server: Option<NameServer>,
+    rcode: Option<ResponseCode>,
+    records: Vec<AnswerRecord>,
+}
+
+impl QueryStats {
+    fn answered(&mut self, message: &Message) {
+        self.attempts = self.sent;
+        self.server = self.last_server.clone();
+        self.rcode = Some(message.response_code());
+        self.records = message.answers().iter().map(answer_record).collect();
+    }
+
+    fn response(
+        &self,
+        name: String,
+        qtype: QueryType,
+        started: Instant,
+        error: Option<&'static str>,
+        resolved: Vec<Resolved>,
+    ) -> Response {
+        // Queries that timed out never got an answer to take the numbers from
+        let (attempts, server) = match self.rcode {
+            Some(_) => (self.attempts, &self.server),
+            None => (self.sent, &self.last_server),
+        };
+        let latency = started.elapsed();
+
+        Response {
+            name: name,
+            qtype: qtype,
+            // IANA mnemonic: NOERROR, NXDOMAIN, SERVFAIL...
+            rcode: self
+                .rcode
+                .map(|rcode| format!("{:?}", rcode).to_uppercase()),
+            error: error,
+            server: server.as_ref().map(NameServer::to_string),
+            latency_ms: latency.as_secs() * 1000 + u64::from(latency.subsec_nanos()) / 1_000_000,
+            attempts: attempts,
+            records: self.records.clone(),
+            resolved: resolved,
+        }
+    }
 }
 
+// Open connections of a worker to stream servers, queries to the same server share one
+type Connections = Rc<RefCell<HashMap<NameServer, WireClientHandle>>>;
+
 #[derive(Clone)]
 struct ClientFactory {
     loop_handle: Handle,
-    name_server: SocketAddr,
+    name_server: NameServer,
+    force_tcp: bool,
+    tls_ca: Rc<Vec<X509>>,
+    https_connector: SslConnector,
+    connections: Connections,
+    edns: Edns,
+    recursion_desired: bool,
+    checking_disabled: bool,
+    status_tx: StatusTx,
+    upstreams: SharedUpstreams,
+    // Position of `name_server` in the configured list, none for servers out of it
+    upstream: Option<usize>,
+    // Slot of the server a timed out query was retried with, held until the query is done
+    _retry_slot: Option<Rc<InFlight>>,
+    // First query of the name is taken from the server's share when the name is dispatched
+    prepaid: Rc<Cell<bool>>,
+    stats: Rc<RefCell<QueryStats>>,
 }
 
 impl ClientFactory {
-    pub fn new(loop_handle: Handle, name_server: SocketAddr) -> ClientFactory {
+    pub fn new(
+        loop_handle: Handle,
+        name_server: NameServer,
+        upstream: Option<usize>,
+        force_tcp: bool,
+        tls_ca: Rc<Vec<X509>>,
+        https_connector: SslConnector,
+        connections: Connections,
+        edns: Edns,
+        status_tx: StatusTx,
+        upstreams: SharedUpstreams,
+    ) -> ClientFactory {
         ClientFactory {
             loop_handle: loop_handle,
             name_server: name_server,
+            force_tcp: force_tcp,
+            tls_ca: tls_ca,
+            https_connector: https_connector,
+            connections: connections,
+            edns: edns,
+            recursion_desired: true,
+            checking_disabled: false,
+            status_tx: status_tx,
+            upstreams: upstreams,
+            upstream: upstream,
+            _retry_slot: None,
+            prepaid: Rc::new(Cell::new(upstream.is_some())),
+            stats: Rc::new(RefCell::new(QueryStats::default())),
+        }
+    }
+
+    // Same factory settings for another nameserver, one out of the configured list
+    fn with_name_server(&self, name_server: NameServer) -> ClientFactory {
+        if name_server == self.name_server {
+            return self.clone();
+        }
+        ClientFactory {
+            name_server: name_server,
+            upstream: None,
+            _retry_slot: None,
+            ..self.clone()
+        }
+    }
+
+    // Non-recursive queries to authoritative servers, plain DNS only
+    fn iterative(&self, addr: SocketAddr) -> ClientFactory {
+        ClientFactory {
+            name_server: NameServer::udp(addr),
+            upstream: None,
+            _retry_slot: None,
+            recursion_desired: false,
+            ..self.clone()
+        }
+    }
+
+    // Validating resolver wants the data even if the upstream thinks it is bogus
+    fn checking_disabled(&self) -> ClientFactory {
+        ClientFactory {
+            checking_disabled: true,
+            ..self.clone()
+        }
+    }
+
+    // Stream transports are never truncated
+    fn is_stream(&self) -> bool {
+        self.force_tcp || self.name_server.transport != Transport::Udp
+    }
+
+    fn new_client(&self, tcp: bool) -> WireClientHandle {
+        let addr = self.name_server.addr;
+        match self.name_server.transport {
+            Transport::Udp if tcp => make_tcp_client(self.loop_handle.clone(), addr),
+            Transport::Udp => make_client(self.loop_handle.clone(), addr),
+            Transport::Tls(ref tls_name) => self.connection(|| {
+                make_tls_client(
+                    self.loop_handle.clone(),
+                    addr,
+                    tls_name.clone(),
+                    &self.tls_ca,
+                )
+            }),
+            Transport::Https {
+                ref url,
+                ref host,
+                method,
+            } => self.connection(|| {
+                make_https_client(
+                    self.loop_handle.clone(),
+                    addr,
+                    host.clone(),
+                    url.clone(),
+                    method,
+                    self.https_connector.clone(),
+                )
+            }),
+        }
+    }
+
+    // Open connection to the server or a new one made with `connect`
+    fn connection<F: FnOnce() -> WireClientHandle>(&self, connect: F) -> WireClientHandle {
+        let connection = RefCell::borrow(&self.connections)
+            .get(&self.name_server)
+            .cloned();
+        connection.unwrap_or_else(|| {
+            let client = connect();
+            self.connections
+                .borrow_mut()
+                .insert(self.name_server.clone(), client.clone());
+            client
+        })
+    }
+
+    // Connection that failed a query is opened anew for the next one
+    fn drop_connection(&self) {
+        self.connections.borrow_mut().remove(&self.name_server);
+    }
+
+    // Report switch to TCP after truncated UDP response
+    fn report_tcp_fallback(&self) {
+        self.status_tx.send(ResolveStatus::TcpFallback).unwrap();
+    }
+
+    // Every query to a configured server counts towards its `qps`
+    fn charge_attempt(&self) {
+        if let Some(idx) = self.upstream {
+            if !self.prepaid.replace(false) {
+                self.upstreams.charge(idx);
+            }
+        }
+    }
+
+    // Outcome of a query sent at `sent` for the server health and rate, `response` is none if it timed out
+    fn report_attempt(&self, sent: Instant, response: Option<&Message>) {
+        if let Some(idx) = self.upstream {
+            self.upstreams
+                .record(idx, sent.elapsed(), response.map(Message::response_code));
         }
     }
 
-    fn new_client(&self) -> BasicClientHandle {
-        make_client(self.loop_handle.clone(), self.name_server)
+    // Same factory for the server to retry a timed out query with. The retry takes a slot of that server,
+    // the query stays with this server when no other one has a slot.
+    fn retry_server(&self) -> ClientFactory {
+        let idx = match self.upstream {
+            Some(idx) => idx,
+            None => return self.clone(),
+        };
+        match Upstreams::retry(&self.upstreams, idx) {
+            Some(in_flight) => {
+                debug!(
+                    "Retrying with {} instead of {}",
+                    in_flight.server(),
+                    self.name_server
+                );
+                ClientFactory {
+                    name_server: in_flight.server().clone(),
+                    upstream: Some(in_flight.idx()),
+                    _retry_slot: Some(Rc::new(in_flight)),
+                    ..self.clone()
+                }
+            }
+            None => self.clone(),
+        }
+    }
+
+    fn dns(&self) -> NameServer {
+        self.name_server.clone()
     }
 
-    fn dns(&self) -> SocketAddr {
-        self.name_server
+    fn edns(&self) -> Edns {
+        self.edns.clone()
+    }
+}
+
+// EDNS0 OPT record attached to every query
+fn make_edns(payload: u16, dnssec_ok: bool, nsid: bool) -> Edns {
+    let mut edns = Edns::new();
+    edns.set_version(0);
+    edns.set_max_payload(payload);
+    edns.set_dnssec_ok(dnssec_ok);
+    if nsid {
+        // NSID is requested with an empty option
+        edns.set_option(EdnsOption::Unknown(EdnsCode::NSID.into(), vec![]));
+    }
+    edns
+}
+
+// NSID of the answering server, printable ones as text and the rest as hex
+fn nsid(message: &Message) -> Option<String> {
+    let nsid = match message.edns()?.option(&EdnsCode::NSID)? {
+        EdnsOption::Unknown(_, ref data) if !data.is_empty() => data,
+        _ => return None,
+    };
+
+    if nsid.iter().all(|&byte| byte >= 0x20 && byte < 0x7f) {
+        Some(String::from_utf8_lossy(nsid).into_owned())
+    } else {
+        Some(nsid.iter().map(|byte| format!("{:02x}", byte)).collect())
     }
 }
 
@@ -55,55 +401,332 @@ pub struct TrustDNSResolver {
     loop_handle: Handle,
     status_tx: StatusTx,
     timeout_retries: u32,
+    force_tcp: bool,
+    tls_ca: Rc<Vec<X509>>,
+    https_connector: SslConnector,
+    connections: Connections,
+    edns: Edns,
+    client_subnet: Option<ClientSubnet>,
+    iterative: bool,
+    delegations: SharedDelegationCache,
+    upstreams: SharedUpstreams,
+    validator: Option<Validator>,
+    cname_chain: bool,
+    max_cname_chain: usize,
 }
 
 impl TrustDNSResolver {
-    pub fn new(loop_handle: Handle, status_tx: StatusTx) -> Self {
-        TrustDNSResolver {
+    pub fn new(
+        loop_handle: Handle,
+        status_tx: StatusTx,
+        delegations: SharedDelegationCache,
+        upstreams: SharedUpstreams,
+    ) -> ConfigResult<Self> {
+        let config = CONFIG.read().unwrap();
+        let validate = config.validate();
+        Ok(TrustDNSResolver {
             loop_handle: loop_handle.clone(),
             status_tx: status_tx,
-            timeout_retries: CONFIG.read().unwrap().timeout_retries(),
-        }
+            timeout_retries: config.timeout_retries(),
+            force_tcp: config.force_tcp(),
+            tls_ca: Rc::new(tls_ca_certs(config.tls_ca())?),
+            https_connector: make_tls_connector(config.tls_ca(), &[b"h2"])?,
+            connections: Rc::new(RefCell::new(HashMap::new())),
+            // Validation needs signatures in responses
+            edns: make_edns(
+                config.edns_payload(),
+                config.dnssec_ok() || validate,
+                config.nsid(),
+            ),
+            client_subnet: config.client_subnet(),
+            iterative: config.iterative(),
+            delegations: delegations,
+            upstreams: upstreams,
+            validator: if validate {
+                Some(Validator::new(config.trust_anchor()))
+            } else {
+                None
+            },
+            cname_chain: config.cname_chain(),
+            max_cname_chain: config.max_cname_chain(),
+        })
     }
 }
 
 impl TrustDNSResolver {
+    /// Resolve an input line: a name, optionally followed by `,subnet` for EDNS Client Subnet.
+    /// Names that did not resolve are responses with `error` set, only unexpected errors fail.
     pub fn resolve(
         &self,
-        dns: SocketAddr,
-        name: &str,
+        dns: &InFlight,
+        line: &str,
         query_type: QueryType,
-    ) -> Box<Future<Item = Vec<String>, Error = ResolverError>> {
-        let client_factory = ClientFactory::new(self.loop_handle.clone(), dns);
-
+    ) -> Box<Future<Item = Response, Error = ResolverError>> {
         self.status_tx.send(ResolveStatus::Started).unwrap();
-        let status_tx = self.status_tx.clone();
+        Box::new(
+            self.resolve_line(dns, line, query_type, Some(self.status_tx.clone()))
+                .map(|(response, _)| response),
+        )
+    }
+
+    /// Same as `resolve`, but the progress is left to the caller along with the status to report,
+    /// as with the answers of several servers for one name in consensus mode
+    pub fn resolve_unreported(
+        &self,
+        dns: &InFlight,
+        line: &str,
+        query_type: QueryType,
+    ) -> Box<Future<Item = (Response, ResolveStatus), Error = ResolverError>> {
+        self.resolve_line(dns, line, query_type, None)
+    }
+
+    fn resolve_line(
+        &self,
+        dns: &InFlight,
+        line: &str,
+        query_type: QueryType,
+        status_tx: Option<StatusTx>,
+    ) -> Box<Future<Item = (Response, ResolveStatus), Error = ResolverError>> {
+        let started = Instant::now();
+
+        let (name, client_subnet) = client_subnet::split_line(line);
+        let client_subnet = match client_subnet {
+            Some(Ok(subnet)) => Some(subnet),
+            Some(Err(err)) => {
+                let rv: Result<Vec<Resolved>, _> = Err(ResolverError::InvalidClientSubnet(err));
+                let status = rv.status();
+                let error = rv
+                    .report_status(&name, status_tx)
+                    .err()
+                    .map(|err| err.reason());
+                let response =
+                    QueryStats::default().response(name, query_type, started, error, vec![]);
+                return Box::new(future::ok((response, status)));
+            }
+            None => self.client_subnet,
+        };
+
+        let mut edns = self.edns.clone();
+        if let Some(subnet) = client_subnet {
+            edns.set_option(subnet.to_option());
+        }
+
+        let client_factory = ClientFactory::new(
+            self.loop_handle.clone(),
+            dns.server().clone(),
+            Some(dns.idx()),
+            self.force_tcp,
+            self.tls_ca.clone(),
+            self.https_connector.clone(),
+            self.connections.clone(),
+            edns,
+            self.status_tx.clone(),
+            self.upstreams.clone(),
+        );
+        let client_factory = if self.validator.is_some() {
+            client_factory.checking_disabled()
+        } else {
+            client_factory
+        };
+        let lookup = self.lookup(client_factory.clone());
+        let stats = client_factory.stats.clone();
+
+        let future = match query_type.record_type() {
+            _ if self.iterative => self.iterative_resolve(client_factory, &name, query_type),
+            Some(RecordType::PTR) => self.reverse_resolve(client_factory, &name),
+            _ => self.simple_resolve(client_factory, &name, query_type),
+        };
+
+        // Authoritative servers leave aliases to other zones for the resolver to follow
+        let future = if self.cname_chain || self.iterative {
+            self.chase_cnames(lookup.clone(), future, query_type)
+        } else {
+            future
+        };
 
-        let future = match query_type {
-            QueryType::PTR => self.reverse_resolve(client_factory, name),
-            _ => self.simple_resolve(client_factory, name, query_type.into()),
+        let answer_stats = stats.clone();
+        let future = future.map(move |msg| {
+            answer_stats.borrow_mut().answered(&msg);
+            msg
+        });
+
+        let future: Box<Future<Item = _, Error = _>> = match self.validator.clone() {
+            Some(validator) => Box::new(future.and_then(move |msg| {
+                validator
+                    .validate(&msg, query_type, lookup)
+                    .map(move |status| (msg, Some(status)))
+            })),
+            None => Box::new(future.map(|msg| (msg, None))),
         };
 
-        let name = name.to_owned();
+        let query_name = name.clone();
+        let max_cname_chain = if self.cname_chain {
+            Some(self.max_cname_chain)
+        } else {
+            None
+        };
         let future = future
-            .map(move |msg| msg.extract_answer(query_type))
-            .then(move |rv| rv.report_status(&name, status_tx))
-            .then(move |rv| rv.partial_ok());
+            .and_then(move |(msg, dnssec)| {
+                let mut resolved = match max_cname_chain {
+                    Some(max_chain) => msg.extract_chained(&query_name, query_type, max_chain)?,
+                    None => msg.extract_resolved(&query_name, query_type),
+                };
+                for resolved in &mut resolved {
+                    resolved.dnssec = dnssec;
+                }
+                if resolved.is_empty() {
+                    return Err(empty_answer_error(&msg));
+                }
+                if dnssec == Some(DnssecStatus::Bogus) {
+                    warn!("{} failed DNSSEC validation", query_name);
+                }
+                Ok(resolved)
+            })
+            .then({
+                let name = name.clone();
+                move |rv| rv.report_status(&name, status_tx)
+            })
+            .then(move |rv: Result<Vec<Resolved>, ResolverError>| {
+                let status = rv.status();
+                let error = rv.as_ref().err().map(ResolverError::reason);
+                rv.partial_ok().map(|resolved| {
+                    // `Borrow` is in scope, RefCell's method has to be named
+                    let stats = RefCell::borrow(&stats);
+                    (
+                        stats.response(name, query_type, started, error, resolved),
+                        status,
+                    )
+                })
+            });
 
         Box::new(future)
     }
 
+    /// Plain query to `dns` with its own number of tries, for checking the server itself:
+    /// no validation, CNAME chasing or client subnet, and errors are left in the response code
+    pub fn query(
+        &self,
+        dns: NameServer,
+        name: &str,
+        rtype: RecordType,
+        tries: u32,
+    ) -> Box<Future<Item = Message, Error = ResolverError>> {
+        let name = match Name::parse(name, Some(&Name::root())) {
+            Ok(name) => name,
+            Err(_) => return Box::new(future::err(ResolverError::InvalidName)),
+        };
+        let client_factory = ClientFactory::new(
+            self.loop_handle.clone(),
+            dns,
+            None,
+            self.force_tcp,
+            self.tls_ca.clone(),
+            self.https_connector.clone(),
+            self.connections.clone(),
+            self.edns.clone(),
+            self.status_tx.clone(),
+            self.upstreams.clone(),
+        );
+
+        Self::resolve_retry(client_factory, tries, name, DNSClass::IN, rtype.into())
+    }
+
+    // Follow-up lookups (CNAME targets, DNSSEC chain of trust) go the same way as the answer
+    fn lookup(&self, client_factory: ClientFactory) -> DnsQuery {
+        if self.iterative {
+            let delegations = self.delegations.clone();
+            Rc::new(move |name, rtype| {
+                Self::resolve_iteratively(
+                    client_factory.clone(),
+                    delegations.clone(),
+                    name,
+                    rtype,
+                    0,
+                )
+            })
+        } else {
+            let timeout_retries = self.timeout_retries;
+            Rc::new(move |name, rtype| {
+                Self::resolve_retry(
+                    client_factory.clone(),
+                    timeout_retries,
+                    name,
+                    DNSClass::IN,
+                    rtype,
+                )
+            })
+        }
+    }
+
+    // Query the last alias again when upstream stopped in the middle of a CNAME chain.
+    // Answers of every hop are merged into the first response, so the chain stays complete.
+    fn chase_cnames(
+        &self,
+        lookup: DnsQuery,
+        first: Box<Future<Item = Message, Error = ResolverError>>,
+        rtype: QueryType,
+    ) -> Box<Future<Item = Message, Error = ResolverError>> {
+        let max_chain = self.max_cname_chain;
+
+        Box::new(first.and_then(move |message| {
+            // Response so far and the chain length it had on the previous hop
+            future::loop_fn(
+                (message, 0),
+                move |(mut message, chain_len)| -> Box<Future<Item = _, Error = _>> {
+                    let qname = match message.queries().first() {
+                        Some(query) => query.name().clone(),
+                        None => return Box::new(future::ok(Loop::Break(message))),
+                    };
+
+                    let chain = match message.cname_chain(&qname, rtype, max_chain) {
+                        Ok(chain) => chain,
+                        Err(err) => return Box::new(future::err(err)),
+                    };
+
+                    // Last hop gave nothing new to follow
+                    let alias = match chain.last() {
+                        Some(alias) if chain.len() > chain_len => alias.clone(),
+                        _ => return Box::new(future::ok(Loop::Break(message))),
+                    };
+
+                    let answered = message.response_code() != ResponseCode::NoError
+                        || message.answers().iter().any(|record| {
+                            *record.name() == alias && QueryType::from(record) == rtype
+                        });
+                    if answered {
+                        return Box::new(future::ok(Loop::Break(message)));
+                    }
+
+                    debug!("Chasing CNAME {} -> {}", qname, alias);
+                    let chain_len = chain.len();
+                    Box::new(lookup(alias, rtype).map(move |mut next| {
+                        message.add_answers(next.take_answers());
+                        message.take_name_servers();
+                        message.insert_name_servers(next.take_name_servers());
+                        message.set_response_code(next.response_code());
+                        Loop::Continue((message, chain_len))
+                    }))
+                },
+            )
+        }))
+    }
+
     // Simple DNS lookup queries
     fn simple_resolve(
         &self,
         client_factory: ClientFactory,
         name: &str,
-        rtype: RecordType,
+        rtype: QueryType,
     ) -> Box<Future<Item = Message, Error = ResolverError>> {
+        let name = match Name::parse(name, Some(&Name::root())) {
+            Ok(name) => name,
+            Err(_) => return Box::new(future::err(ResolverError::InvalidName)),
+        };
+
         Box::new(Self::resolve_retry(
             client_factory,
             self.timeout_retries,
-            Name::parse(name, Some(&Name::root())).unwrap(),
+            name,
             DNSClass::IN,
             rtype,
         ))
@@ -115,14 +738,222 @@ impl TrustDNSResolver {
         client_factory: ClientFactory,
         ip: &str,
     ) -> Box<Future<Item = Message, Error = ResolverError>> {
-        let mut labels = ip.split('.').map(str::to_owned).collect::<Vec<_>>();
-        labels.reverse();
-
-        let name = Name::with_labels(labels).label("in-addr").label("arpa");
+        // Name conversion builds in-addr.arpa for IPv4 and nibble-reversed ip6.arpa for IPv6
+        let name = match ip.trim().parse::<IpAddr>() {
+            Ok(ip) => Name::from(ip),
+            Err(_) => return Box::new(future::err(ResolverError::InvalidAddress)),
+        };
 
         Box::new(self.recurse_ptr(client_factory, name, DNSClass::IN, RecordType::PTR))
     }
 
+    // Iterative lookup starting from root hints, for PTR queries the input is an IP address
+    fn iterative_resolve(
+        &self,
+        client_factory: ClientFactory,
+        name: &str,
+        rtype: QueryType,
+    ) -> Box<Future<Item = Message, Error = ResolverError>> {
+        let name = match rtype.record_type() {
+            Some(RecordType::PTR) => match name.trim().parse::<IpAddr>() {
+                Ok(ip) => Name::from(ip),
+                Err(_) => return Box::new(future::err(ResolverError::InvalidAddress)),
+            },
+            _ => match Name::parse(name, Some(&Name::root())) {
+                Ok(name) => name,
+                Err(_) => return Box::new(future::err(ResolverError::InvalidName)),
+            },
+        };
+
+        Self::resolve_iteratively(client_factory, self.delegations.clone(), name, rtype, 0)
+    }
+
+    // Follow referrals from the closest cached delegation down to the authoritative servers.
+    // Nameservers without glue are resolved iteratively as well, `depth` bounds that recursion.
+    fn resolve_iteratively(
+        client_factory: ClientFactory,
+        delegations: SharedDelegationCache,
+        name: Name,
+        rtype: QueryType,
+        depth: u8,
+    ) -> Box<Future<Item = Message, Error = ResolverError>> {
+        const MAX_REFERRALS: u8 = 16;
+        const MAX_DEPTH: u8 = 4;
+        const TRIES_PER_SERVER: u32 = 2;
+
+        struct State {
+            zone: Name,
+            servers: Vec<SocketAddr>,
+            referrals: u8,
+        }
+
+        if depth > MAX_DEPTH {
+            debug!("Too deep nameserver resolution for {}", name);
+            return Box::new(future::err(ResolverError::NameServerNotResolved));
+        }
+
+        let (zone, servers) = delegations.read().unwrap().closest(&name);
+        let state = State {
+            zone: zone,
+            servers: servers,
+            referrals: 0,
+        };
+
+        let resolve_loop = future::loop_fn(
+            state,
+            move |mut state| -> Box<Future<Item = _, Error = _>> {
+                let server = match state.servers.first().cloned() {
+                    Some(server) => server,
+                    None => {
+                        debug!("No nameserver of {} answered for {}", state.zone, name);
+                        return Box::new(future::err(ResolverError::NameServerNotResolved));
+                    }
+                };
+                state.servers.remove(0);
+
+                let client_factory = client_factory.clone();
+                let delegations = delegations.clone();
+                let name = name.clone();
+
+                let query = Self::resolve_retry(
+                    client_factory.iterative(server),
+                    TRIES_PER_SERVER,
+                    name.clone(),
+                    DNSClass::IN,
+                    rtype,
+                );
+
+                Box::new(query.then(
+                    move |result| -> Box<Future<Item = _, Error = ResolverError>> {
+                        let message = match result {
+                            Ok(message) => message,
+                            Err(err) => {
+                                debug!("{} failed for {}: {}", server, name, err);
+                                return Box::new(future::ok(Loop::Continue(state)));
+                            }
+                        };
+
+                        // Lame or broken server, ask the next one
+                        match message.response_code() {
+                            ResponseCode::NoError | ResponseCode::NXDomain => (),
+                            rcode => {
+                                debug!("{} answered {:?} for {}", server, rcode, name);
+                                return Box::new(future::ok(Loop::Continue(state)));
+                            }
+                        }
+
+                        let referral = match Referral::from_message(&message, &name, &state.zone) {
+                            Some(referral) => referral,
+                            None => return Box::new(future::ok(Loop::Break(message))),
+                        };
+
+                        if state.referrals >= MAX_REFERRALS {
+                            debug!("Too many referrals for {}", name);
+                            return Box::new(future::err(ResolverError::NameServerNotResolved));
+                        }
+
+                        info!(
+                            "Delegation {} -> {}",
+                            referral.zone,
+                            referral
+                                .ns_names
+                                .iter()
+                                .map(Name::to_string)
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        );
+
+                        let addrs: Box<Future<Item = Vec<SocketAddr>, Error = ResolverError>> =
+                            if referral.glue.is_empty() {
+                                Self::resolve_ns_addrs(
+                                    client_factory,
+                                    delegations.clone(),
+                                    referral.ns_names,
+                                    depth + 1,
+                                )
+                            } else {
+                                Box::new(future::ok(referral.glue))
+                            };
+
+                        let zone = referral.zone;
+                        let ttl = referral.ttl;
+                        Box::new(addrs.map(move |addrs| {
+                            if !addrs.is_empty() {
+                                delegations
+                                    .write()
+                                    .unwrap()
+                                    .insert(&zone, addrs.clone(), ttl);
+                            }
+                            Loop::Continue(State {
+                                zone: zone,
+                                servers: addrs,
+                                referrals: state.referrals + 1,
+                            })
+                        }))
+                    },
+                ))
+            },
+        );
+
+        Box::new(resolve_loop)
+    }
+
+    // IPv4 and IPv6 addresses of the first nameserver from the list that resolves
+    fn resolve_ns_addrs(
+        client_factory: ClientFactory,
+        delegations: SharedDelegationCache,
+        ns_names: Vec<Name>,
+        depth: u8,
+    ) -> Box<Future<Item = Vec<SocketAddr>, Error = ResolverError>> {
+        let resolve_loop = future::loop_fn(
+            ns_names,
+            move |mut ns_names| -> Box<Future<Item = _, Error = _>> {
+                let ns_name = match ns_names.pop() {
+                    Some(ns_name) => ns_name,
+                    None => return Box::new(future::ok(Loop::Break(vec![]))),
+                };
+
+                let queries = [RecordType::A, RecordType::AAAA]
+                    .iter()
+                    .map(|&rtype| {
+                        Self::resolve_iteratively(
+                            client_factory.clone(),
+                            delegations.clone(),
+                            ns_name.clone(),
+                            rtype.into(),
+                            depth,
+                        )
+                        .then(move |result| -> Result<_, ResolverError> {
+                            Ok(result
+                                .map(|message| message.extract_answer(rtype.into()))
+                                .unwrap_or_default())
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                Box::new(future::join_all(queries).then(move |result| {
+                    let mut addrs = result
+                        .unwrap_or_default()
+                        .concat()
+                        .iter()
+                        .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                        .map(|ip| SocketAddr::new(ip, 53))
+                        .collect::<Vec<_>>();
+                    // Same order as glue, IPv6 may be unreachable
+                    addrs.sort_by_key(SocketAddr::is_ipv6);
+
+                    if addrs.is_empty() {
+                        Ok(Loop::Continue(ns_names))
+                    } else {
+                        Ok(Loop::Break(addrs))
+                    }
+                }))
+            },
+        );
+
+        Box::new(resolve_loop)
+    }
+
     // Recursive DNS request for PTR queries
     fn recurse_ptr(
         &self,
@@ -134,7 +965,6 @@ impl TrustDNSResolver {
         // Because recursion is not possible with futures this implementation of Depth-First lookup
         // uses state with discovered nameservers excluding visited ones to avoid infinite loops
         struct State {
-            handle: Handle,
             client_factory: ClientFactory,
             nameservers: Vec<NS>,
             visited: HashSet<NS>,
@@ -170,7 +1000,6 @@ impl TrustDNSResolver {
 
         let timeout_retries = self.timeout_retries;
         let state = State {
-            handle: self.loop_handle.clone(),
             client_factory: client_factory.clone(),
             nameservers: vec![NS::Known(client_factory.dns())],
             visited: HashSet::new(),
@@ -179,7 +1008,6 @@ impl TrustDNSResolver {
 
         let resolve_loop = future::loop_fn(state, move |mut state| {
             Self::resolve_with_ns(
-                state.handle.clone(),
                 state.client_factory.clone(),
                 timeout_retries,
                 state.pop_ns().unwrap(),
@@ -213,7 +1041,6 @@ impl TrustDNSResolver {
     // Perform DNS query with some nameserver.
     // If nameserver is not a SocketAddr, resolve the domain first.
     fn resolve_with_ns(
-        loop_handle: Handle,
         client_factory: ClientFactory,
         timeout_retries: u32,
         nameserver: NS,
@@ -226,44 +1053,48 @@ impl TrustDNSResolver {
             name.to_string(),
             nameserver.to_string()
         );
-        let ns_resolve: Box<Future<Item = Option<SocketAddr>, Error = ResolverError>> =
+        let ns_resolve: Box<Future<Item = Option<NameServer>, Error = ResolverError>> =
             match nameserver {
                 NS::Known(addr) => future::ok(Some(addr)).boxed(),
-                NS::Unknown(domain) => Box::new(
-                    Self::resolve_retry(
-                        client_factory.clone(),
-                        timeout_retries,
-                        Name::parse(&domain, Some(&Name::root())).unwrap(),
-                        DNSClass::IN,
-                        RecordType::A,
-                    )
-                    .map(|msg| {
-                        msg.extract_answer(QueryType::A)
-                            .into_iter()
-                            .nth(0)
-                            .map(|mut ip| {
-                                ip.push_str(":53");
-                                ip
-                            })
-                            .and_then(|ip| {
-                                ip.parse::<SocketAddr>()
-                                    .map_err(|e| {
-                                        error!("Invalid IP({:?}): {:?}", ip, e);
-                                        e
-                                    })
-                                    .ok()
-                            })
-                    }),
-                ),
+                NS::Unknown(domain) => match Name::parse(&domain, Some(&Name::root())) {
+                    Err(_) => Box::new(future::err(ResolverError::NameServerNotResolved)),
+                    Ok(domain) => Box::new(
+                        Self::resolve_retry(
+                            client_factory.clone(),
+                            timeout_retries,
+                            domain,
+                            DNSClass::IN,
+                            RecordType::A.into(),
+                        )
+                        .map(|msg| {
+                            msg.extract_answer(RecordType::A.into())
+                                .into_iter()
+                                .nth(0)
+                                .map(|mut ip| {
+                                    ip.push_str(":53");
+                                    ip
+                                })
+                                .and_then(|ip| {
+                                    ip.parse::<SocketAddr>()
+                                        .map_err(|e| {
+                                            error!("Invalid IP({:?}): {:?}", ip, e);
+                                            e
+                                        })
+                                        .ok()
+                                })
+                                .map(NameServer::udp)
+                        }),
+                    ),
+                },
             };
 
         let future = ns_resolve.then(move |result| match result {
             Ok(Some(nameserver)) => Self::resolve_retry(
-                ClientFactory::new(loop_handle, nameserver),
+                client_factory.with_name_server(nameserver),
                 timeout_retries,
                 name.clone(),
                 query_class,
-                record_type,
+                record_type.into(),
             ),
             Ok(None) => future::err(ResolverError::NameServerNotResolved).boxed(),
             Err(err) => future::err(err).boxed(),
@@ -272,30 +1103,46 @@ impl TrustDNSResolver {
         Box::new(future)
     }
 
-    // Retry-on-timeout enabled resolve
+    // Retry-on-timeout enabled resolve, timed out tries go to another healthy server if there is one
     fn resolve_retry(
         client_factory: ClientFactory,
         timeout_retries: u32,
         name: Name,
         query_class: DNSClass,
-        record_type: RecordType,
+        record_type: QueryType,
     ) -> Box<Future<Item = Message, Error = ResolverError>> {
         struct State {
+            client_factory: ClientFactory,
             tries_left: u32,
+            tcp: bool,
             message: Option<Message>,
         };
 
         impl State {
-            fn new(tries: u32) -> Self {
+            fn new(client_factory: ClientFactory, tries: u32) -> Self {
                 State {
+                    tcp: client_factory.is_stream(),
+                    client_factory: client_factory,
                     tries_left: tries,
                     message: None,
                 }
             }
 
+            // Truncated responses are retried over TCP without spending a try
+            fn tcp_fallback(mut self) -> Result<Loop<Self, Self>, ResolverError> {
+                self.tcp = true;
+                Ok(Loop::Continue(self))
+            }
+
             fn next_step(mut self) -> Result<Loop<Self, Self>, ResolverError> {
                 self.tries_left -= 1;
                 if self.tries_left > 0 {
+                    let client_factory = self.client_factory.retry_server();
+                    // Another server is asked over its own transport, not the fallback of the last one
+                    if client_factory.dns() != self.client_factory.dns() {
+                        self.tcp = client_factory.is_stream();
+                    }
+                    self.client_factory = client_factory;
                     Ok(Loop::Continue(self))
                 } else {
                     Ok(Loop::Break(self))
@@ -316,31 +1163,62 @@ impl TrustDNSResolver {
             }
         }
 
-        let state = State::new(timeout_retries);
+        let state = State::new(client_factory, timeout_retries);
 
         let retry_loop = {
             future::loop_fn(state, move |state| {
+                let client_factory = state.client_factory.clone();
+                let name = name.clone();
+                client_factory.charge_attempt();
+                let sent = Instant::now();
                 Self::_resolve(
-                    client_factory.new_client(),
+                    &client_factory,
+                    state.tcp,
                     name.clone(),
                     query_class,
                     record_type,
                 )
                 .then(move |result| match result {
+                    Ok(ref message) if message.truncated() && !state.tcp => {
+                        debug!("Truncated response, retrying over TCP");
+                        client_factory.report_attempt(sent, Some(message));
+                        client_factory.report_tcp_fallback();
+                        state.tcp_fallback()
+                    }
                     Ok(message) => {
                         trace!("Received DNS message: {:?}", message.answers());
+                        client_factory.report_attempt(sent, Some(&message));
+                        client_factory.stats.borrow_mut().last_server = Some(client_factory.dns());
+                        if let Some(nsid) = nsid(&message) {
+                            info!(
+                                "{} answered by {} (NSID {})",
+                                name,
+                                client_factory.dns(),
+                                nsid
+                            );
+                        }
                         Ok(Loop::Break(state.with_message(message)))
                     }
-                    Err(err) => match *err.kind() {
-                        ClientErrorKind::Timeout => state.next_step(),
-                        ClientErrorKind::Canceled(e) => {
-                            if !state.has_next_step() {
-                                error!("{}", e)
+                    Err(err) => {
+                        client_factory.drop_connection();
+                        match *err.kind() {
+                            ClientErrorKind::Timeout => {
+                                client_factory.report_attempt(sent, None);
+                                state.next_step()
+                            }
+                            ClientErrorKind::Canceled(e) => {
+                                client_factory.report_attempt(sent, None);
+                                if !state.has_next_step() {
+                                    error!("{}", e)
+                                }
+                                state.next_step()
+                            }
+                            _ => {
+                                client_factory.report_attempt(sent, None);
+                                Err(ResolverError::DnsClientError(err))
                             }
-                            state.next_step()
                         }
-                        _ => Err(ResolverError::DnsClientError(err)),
-                    },
+                    }
                 })
             })
         };
@@ -360,16 +1238,38 @@ impl TrustDNSResolver {
     }
 
     fn _resolve(
-        mut client: BasicClientHandle,
+        client_factory: &ClientFactory,
+        tcp: bool,
         name: Name,
         query_class: DNSClass,
-        record_type: RecordType,
+        record_type: QueryType,
     ) -> Box<Future<Item = Message, Error = ClientError>> {
-        Box::new(client.query(name, query_class, record_type))
+        // Same as `ClientHandle::query`, but with our own OPT record. Message id is set by the client
+        client_factory.stats.borrow_mut().sent += 1;
+
+        let mut query = Query::new();
+        query
+            .set_name(name)
+            .set_query_class(query_class)
+            .set_query_type(record_type.record_type().unwrap_or(RecordType::NULL));
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(client_factory.recursion_desired)
+            .set_checking_disabled(client_factory.checking_disabled)
+            .set_edns(client_factory.edns())
+            .add_query(query);
+
+        client_factory
+            .new_client(tcp)
+            .send_query(message, record_type)
     }
 }
 
 use trust_dns::rr::RData;
+use trust_dns::serialize::binary::BinEncoder;
 
 trait FromRecord<B>
 where
@@ -386,23 +1286,86 @@ where
     fn from(r: B, qtype: QueryType) -> Option<Self> {
         let r = r.borrow();
 
+        if qtype != RecordType::ANY.into() && qtype != QueryType::from(r) {
+            return None;
+        }
+        if let Some((_, rdata)) = opaque(r) {
+            return Some(generic_rdata(rdata));
+        }
+
         macro_rules! variants_to_string {
             ($($x:tt),*) => {
-                match (qtype, r.rdata()) {
+                match *r.rdata() {
                     $(
-                        (QueryType::$x, &RData::$x(ref data)) => Some(data.to_string()),
+                        RData::$x(ref data) => data.to_string(),
                     )*
-                    _ => None
+                    RData::MX(ref mx) => format!("{} {}", mx.preference(), mx.exchange()),
+                    RData::TXT(ref txt) => txt.txt_data().concat(),
+                    RData::SOA(ref soa) => format!(
+                        "{} {} {} {} {} {} {}",
+                        soa.mname(),
+                        soa.rname(),
+                        soa.serial(),
+                        soa.refresh(),
+                        soa.retry(),
+                        soa.expire(),
+                        soa.minimum()
+                    ),
+                    RData::SRV(ref srv) => format!(
+                        "{} {} {} {}",
+                        srv.priority(),
+                        srv.weight(),
+                        srv.port(),
+                        srv.target()
+                    ),
+                    ref rdata => generic_rdata_to_string(rdata)?,
                 }
             }
         }
 
-        variants_to_string!(A, AAAA, NS, PTR)
+        Some(variants_to_string!(A, AAAA, NS, PTR, CNAME))
+    }
+}
+
+// RFC 3597 generic `\# len hex` representation for types without a presentation format
+fn generic_rdata_to_string(rdata: &RData) -> Option<String> {
+    let mut bytes = Vec::new();
+    rdata
+        .emit(&mut BinEncoder::new(&mut bytes))
+        .map_err(|e| error!("failed to encode {:?} rdata: {}", rdata.to_record_type(), e))
+        .ok()?;
+
+    Some(generic_rdata(&bytes))
+}
+
+fn generic_rdata(bytes: &[u8]) -> String {
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    if hex.is_empty() {
+        format!("\\# {}", bytes.len())
+    } else {
+        format!("\\# {} {}", bytes.len(), hex)
     }
 }
 
 trait ExtractAnswer {
     fn extract_answer(&self, qtype: QueryType) -> Vec<String>;
+    fn extract_resolved(&self, name: &str, qtype: QueryType) -> Vec<Resolved>;
+    fn extract_chained(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        max_chain: usize,
+    ) -> Result<Vec<Resolved>, ResolverError>;
+    fn cname_chain(
+        &self,
+        name: &Name,
+        rtype: QueryType,
+        max_chain: usize,
+    ) -> Result<Vec<Name>, ResolverError>;
 }
 
 impl ExtractAnswer for Message {
@@ -414,33 +1377,158 @@ impl ExtractAnswer for Message {
             .map(Option::unwrap)
             .collect()
     }
+
+    fn extract_resolved(&self, name: &str, qtype: QueryType) -> Vec<Resolved> {
+        let client_subnet = ClientSubnet::from_message(self);
+        self.answers()
+            .iter()
+            .filter_map(|record| {
+                <String as FromRecord<_>>::from(record, qtype).map(|value| (value, record.ttl()))
+            })
+            .map(|(value, ttl)| Resolved {
+                name: name.to_owned(),
+                value: value,
+                ttl: ttl,
+                client_subnet: client_subnet,
+                dnssec: None,
+                cname_chain: vec![],
+            })
+            .collect()
+    }
+
+    // Only records of the last alias are the answer, aliases along the way are kept with them
+    fn extract_chained(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        max_chain: usize,
+    ) -> Result<Vec<Resolved>, ResolverError> {
+        let qname = match self.queries().first() {
+            Some(query) => query.name().clone(),
+            None => return Ok(vec![]),
+        };
+
+        let chain = self.cname_chain(&qname, qtype, max_chain)?;
+        let owner = chain.last().unwrap_or(&qname).clone();
+        let aliases = chain.iter().map(Name::to_string).collect::<Vec<_>>();
+        let client_subnet = ClientSubnet::from_message(self);
+
+        Ok(self
+            .answers()
+            .iter()
+            .filter(|record| *record.name() == owner)
+            .filter_map(|record| {
+                <String as FromRecord<_>>::from(record, qtype).map(|value| (value, record.ttl()))
+            })
+            .map(|(value, ttl)| Resolved {
+                name: name.to_owned(),
+                value: value,
+                ttl: ttl,
+                client_subnet: client_subnet,
+                dnssec: None,
+                cname_chain: aliases.clone(),
+            })
+            .collect())
+    }
+
+    // Aliases of `name` in the answer section in the order they are followed.
+    // CNAME and ANY queries are answered by the alias itself.
+    fn cname_chain(
+        &self,
+        name: &Name,
+        rtype: QueryType,
+        max_chain: usize,
+    ) -> Result<Vec<Name>, ResolverError> {
+        let mut chain: Vec<Name> = Vec::new();
+        if rtype == RecordType::CNAME.into() || rtype == RecordType::ANY.into() {
+            return Ok(chain);
+        }
+
+        loop {
+            let target = {
+                let owner = chain.last().unwrap_or(name);
+                self.answers()
+                    .iter()
+                    .filter(|record| record.name() == owner)
+                    .filter_map(|record| match *record.rdata() {
+                        RData::CNAME(ref target) => Some(target.clone()),
+                        _ => None,
+                    })
+                    .next()
+            };
+
+            match target {
+                None => return Ok(chain),
+                Some(ref target) if target == name || chain.contains(target) => {
+                    return Err(ResolverError::CnameLoop)
+                }
+                Some(_) if chain.len() >= max_chain => {
+                    return Err(ResolverError::CnameChainTooLong)
+                }
+                Some(target) => chain.push(target),
+            }
+        }
+    }
+}
+
+fn answer_record(record: &Record) -> AnswerRecord {
+    AnswerRecord {
+        name: record.name().to_string(),
+        rtype: QueryType::from(record).to_string(),
+        ttl: record.ttl(),
+        rdata: <String as FromRecord<_>>::from(record, RecordType::ANY.into()).unwrap_or_default(),
+    }
+}
+
+// Reason the response holds no records of the queried type
+fn empty_answer_error(message: &Message) -> ResolverError {
+    match message.response_code() {
+        ResponseCode::NoError => ResolverError::NoData,
+        ResponseCode::NXDomain => ResolverError::NxDomain,
+        ResponseCode::ServFail => ResolverError::ServFail,
+        ResponseCode::Refused => ResolverError::Refused,
+        rcode => ResolverError::Rcode(rcode),
+    }
 }
 
 trait ReportStatus {
-    fn report_status(self, name: &str, status_tx: StatusTx) -> Self;
+    fn status(&self) -> ResolveStatus;
+    fn report_status(self, name: &str, status_tx: Option<StatusTx>) -> Self;
 }
 
 impl<T> ReportStatus for Result<Vec<T>, ResolverError> {
-    fn report_status(self, name: &str, status_tx: StatusTx) -> Self {
-        match self.as_ref() {
-            Ok(vec) => {
-                if vec.is_empty() {
-                    status_tx.send(ResolveStatus::Failure).unwrap();
-                } else {
-                    status_tx.send(ResolveStatus::Success).unwrap();
-                }
-            }
-            Err(error) => match *error {
-                ResolverError::ConnectionTimeout | ResolverError::NameServerNotResolved => {
-                    debug!("failed to resolve {:?}: {}", name, error);
-                    status_tx.send(ResolveStatus::Failure).unwrap();
-                }
-                _ => {
-                    error!("failed to resolve {:?}: {}", name, error);
-                    status_tx.send(ResolveStatus::Error).unwrap();
-                }
+    // Progress status of the outcome for a name
+    fn status(&self) -> ResolveStatus {
+        match *self {
+            Ok(ref vec) if vec.is_empty() => ResolveStatus::Failure,
+            Ok(_) => ResolveStatus::Success,
+            Err(ref error) => match *error {
+                ResolverError::ConnectionTimeout
+                | ResolverError::NameServerNotResolved
+                | ResolverError::Rcode(_) => ResolveStatus::Failure,
+                ResolverError::NxDomain => ResolveStatus::NxDomain,
+                ResolverError::NoData => ResolveStatus::NoData,
+                ResolverError::ServFail => ResolveStatus::ServFail,
+                ResolverError::Refused => ResolveStatus::Refused,
+                _ => ResolveStatus::Error,
             },
         }
+    }
+
+    fn report_status(self, name: &str, status_tx: Option<StatusTx>) -> Self {
+        let status_tx = match status_tx {
+            Some(status_tx) => status_tx,
+            None => return self,
+        };
+        let status = self.status();
+        if let Err(ref error) = self {
+            if status == ResolveStatus::Error {
+                error!("failed to resolve {:?}: {}", name, error);
+            } else {
+                debug!("failed to resolve {:?}: {}", name, error);
+            }
+        }
+        status_tx.send(status).unwrap();
         self
     }
 }
@@ -454,7 +1542,18 @@ impl<T> PartialOk<T> for Result<Vec<T>, ResolverError> {
         match self {
             Err(ResolverError::ConnectionTimeout)
             | Err(ResolverError::NameServerNotResolved)
-            | Err(ResolverError::NotFound) => Ok(vec![]),
+            | Err(ResolverError::NotFound)
+            | Err(ResolverError::InvalidAddress)
+            | Err(ResolverError::InvalidName)
+            | Err(ResolverError::InvalidClientSubnet(_))
+            | Err(ResolverError::CnameLoop)
+            | Err(ResolverError::CnameChainTooLong)
+            | Err(ResolverError::NxDomain)
+            | Err(ResolverError::NoData)
+            | Err(ResolverError::ServFail)
+            | Err(ResolverError::Refused)
+            | Err(ResolverError::Rcode(_))
+            | Err(ResolverError::DnsClientError(_)) => Ok(vec![]),
             Ok(vec) => Ok(vec),
             Err(err) => Err(err),
         }
@@ -463,7 +1562,7 @@ impl<T> PartialOk<T> for Result<Vec<T>, ResolverError> {
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 enum NS {
-    Known(SocketAddr),
+    Known(NameServer),
     Unknown(String),
 }
 
@@ -488,8 +1587,147 @@ impl NS {
     }
 }
 
-impl From<SocketAddr> for NS {
-    fn from(addr: SocketAddr) -> NS {
-        NS::Known(addr)
+impl From<NameServer> for NS {
+    fn from(ns: NameServer) -> NS {
+        NS::Known(ns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::iter;
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslAcceptorBuilder;
+    use openssl::x509::X509Generator;
+    use tokio_core::reactor::Core;
+
+    use trust_dns::client::ClientHandle;
+
+    const ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn self_signed(name: &str) -> (PKey, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let cert = X509Generator::new()
+            .set_valid_period(1)
+            .add_name("CN".to_owned(), name.to_owned())
+            .set_sign_hash(MessageDigest::sha256())
+            .sign(&key)
+            .unwrap();
+        (key, cert)
+    }
+
+    // Answers every length-prefixed query on the connection with the same A record
+    fn serve<S: Read + Write>(mut stream: S) {
+        let mut len = [0; 2];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut query = vec![0; (len[0] as usize) << 8 | len[1] as usize];
+            stream.read_exact(&mut query).unwrap();
+            let query = Message::from_vec(&query).unwrap();
+
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Query);
+            for q in query.queries() {
+                response.add_query(q.clone()).add_answer(Record::from_rdata(
+                    q.name().clone(),
+                    60,
+                    RecordType::A,
+                    RData::A(ANSWER),
+                ));
+            }
+            let response = response.to_vec().unwrap();
+            stream
+                .write_all(&[(response.len() >> 8) as u8, response.len() as u8])
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    }
+
+    // Local DNS-over-TLS stand-in with a self-signed certificate, counts accepted connections
+    fn tls_server(key: PKey, cert: X509) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let acceptor = SslAcceptorBuilder::mozilla_intermediate(
+            SslMethod::tls(),
+            &key,
+            &cert,
+            iter::empty::<X509>(),
+        )
+        .unwrap()
+        .build();
+        let acceptor = Arc::new(acceptor);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                let stream: TcpStream = stream.unwrap();
+                thread::spawn(move || serve(acceptor.accept(stream).unwrap()));
+            }
+        });
+        (addr, connections)
+    }
+
+    #[test]
+    fn tls_queries_share_one_connection_to_a_self_signed_server() {
+        let (key, cert) = self_signed("dns.test");
+        let (addr, connections) = tls_server(key, cert.clone());
+
+        let mut core = Core::new().unwrap();
+        let (status_tx, _status_rx) = mpsc::channel();
+        let factory = ClientFactory::new(
+            core.handle(),
+            NameServer {
+                addr: addr,
+                transport: Transport::Tls("dns.test".to_owned()),
+            },
+            None,
+            false,
+            Rc::new(vec![cert]),
+            make_tls_connector(None, &[b"h2"]).unwrap(),
+            Rc::new(RefCell::new(HashMap::new())),
+            Edns::new(),
+            status_tx,
+            Upstreams::shared(&[]),
+        );
+
+        for name in &["a.example.", "b.example."] {
+            let name = Name::parse(name, None).unwrap();
+            let response = core
+                .run(
+                    factory
+                        .new_client(false)
+                        .query(name.clone(), DNSClass::IN, RecordType::A),
+                )
+                .unwrap();
+            assert_eq!(response.answers().len(), 1);
+            assert_eq!(response.answers()[0].name(), &name);
+            assert_eq!(response.answers()[0].rdata(), &RData::A(ANSWER));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tls_ca_from_config_goes_along_with_system_certificates() {
+        let (_, cert) = self_signed("dns.test");
+        let pem = cert.to_pem().unwrap();
+        let certs = tls_ca_certs(Some(&pem)).unwrap();
+        assert_eq!(
+            certs.last().unwrap().to_der().unwrap(),
+            cert.to_der().unwrap()
+        );
     }
 }
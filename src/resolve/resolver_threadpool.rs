@@ -1,10 +1,8 @@
-use std::cell::Cell;
-use std::net::SocketAddr;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use futures::future;
-use futures::stream;
+use futures::sink::Wait;
 use futures::sync::mpsc as future_mpsc;
 use futures::Future;
 use futures::Sink;
@@ -15,21 +13,22 @@ use crossbeam;
 use num_cpus;
 
 use config::CONFIG;
-use resolve::batch::QueryType;
-use resolve::batch::ResolvedTx;
-use resolve::batch::StatusTx;
+use resolve::batch::{DisagreementTx, Failed, FailedTx, ResolvedTx, Response};
+use resolve::batch::{ResolveStatus, StatusTx};
+use resolve::consensus;
 use resolve::error::ResolverError;
+use resolve::iterative::{DelegationCache, SharedDelegationCache};
+use resolve::query_type::QueryType;
 use resolve::resolver::TrustDNSResolver;
+use resolve::upstream::{InFlight, SharedUpstreams, UpstreamPool, Upstreams};
 
 pub struct ResolverThreadPool {
-    tasks: Vec<ResolveTask>,
     workers_cnt: usize,
 }
 
 impl ResolverThreadPool {
     pub fn new(num_cpus: usize) -> Self {
         ResolverThreadPool {
-            tasks: vec![],
             workers_cnt: num_cpus,
         }
     }
@@ -38,76 +37,112 @@ impl ResolverThreadPool {
         Self::new(num_cpus::get())
     }
 
-    pub fn spawn(&mut self, task: ResolveTask) {
-        self.tasks.push(task)
-    }
+    /// Resolve tasks as they are taken from `tasks`, the iterator is consumed at the configured QPS
+    pub fn start<I: Iterator<Item = ResolveTask>>(self, tasks: I, status: StatusTx) {
+        let (qps, max_qps, servers_per_name) = {
+            let config = CONFIG.read().unwrap();
+            let qps = config.qps() as usize;
+            // Adaptive rate may get up to the ceiling for every server
+            let max_qps = config.adaptive().map_or(qps, |bounds| {
+                qps.max(bounds.ceiling as usize * config.dns_list().len())
+            });
+            let servers_per_name = config.consensus().map_or(1, |consensus| consensus.servers);
+            (qps, max_qps, servers_per_name)
+        };
+        let worker_qps = (max_qps as f32 / self.workers_cnt as f32).ceil() as usize;
 
-    pub fn start(self, status: StatusTx) {
-        let tasks_cnt = self.tasks.len();
-        let chunk_size = tasks_cnt / self.workers_cnt + 1;
-        let qps = CONFIG.read().unwrap().qps() as usize;
-        let worker_qps = (qps as f32 / self.workers_cnt as f32).ceil() as usize;
+        // Delegations discovered in iterative mode are shared by all workers
+        let delegations = DelegationCache::shared();
+        // So are the health and shares of the servers, retries go around the unhealthy ones
+        let upstreams = Upstreams::shared(CONFIG.read().unwrap().dns_list());
 
         crossbeam::scope(|scope| {
             scope.defer(|| debug!("Exiting crosspbeam scope"));
-            let mut trigger = TriggerTimer::new(tasks_cnt, worker_qps);
+            let mut limiter = RateLimiter::new(worker_qps, servers_per_name);
 
-            for chunk in self.tasks.chunks(chunk_size).map(|chunk| chunk.to_vec()) {
-                let trigger_handle = trigger.get_handle();
+            for _ in 0..self.workers_cnt {
+                let task_rx = limiter.get_handle();
                 let status = status.clone();
+                let delegations = delegations.clone();
+                let upstreams = upstreams.clone();
 
                 scope.spawn(move || {
                     let thread = thread::current();
                     let tname = thread.name().unwrap_or("Unknown");
 
                     debug!("Started worker thread ({})", tname);
-                    ResolverThread::thread_main(chunk, status, trigger_handle, worker_qps);
+                    ResolverThread::thread_main(
+                        task_rx,
+                        status,
+                        worker_qps,
+                        delegations,
+                        upstreams,
+                    );
                     debug!("Terminated worker thread: ({})", tname);
                 });
             }
 
-            let dns = CONFIG.read().unwrap().dns_list().to_vec();
-            scope.spawn(move || {
-                debug!("Started qps trigger thread");
-                trigger.thread_main(dns);
-                debug!("Terminated qps trigger thread");
-            });
-        })
+            // Input is read on this thread, workers get tasks through bounded channels
+            // so only a second worth of tasks is held in memory
+            let pool = UpstreamPool::new(
+                upstreams.clone(),
+                qps as u64,
+                CONFIG.read().unwrap().adaptive(),
+            );
+            debug!("Started dispatching tasks");
+            limiter.dispatch(tasks, pool, status.clone());
+            debug!("Finished dispatching tasks");
+        });
+
+        upstreams.health().report();
     }
 }
 
-type TriggerTx = future_mpsc::Sender<SocketAddr>;
-type TriggerRx = future_mpsc::Receiver<SocketAddr>;
+// Task with the servers to resolve it with
+type TaskTx = future_mpsc::Sender<(ResolveTask, Vec<InFlight>)>;
+type TaskRx = future_mpsc::Receiver<(ResolveTask, Vec<InFlight>)>;
 
-struct TriggerTimer {
-    handles: Vec<TriggerTx>,
+struct RateLimiter {
+    handles: Vec<Wait<TaskTx>>,
     worker_qps: usize,
-    triggered: Cell<usize>,
-    tasks_cnt: usize,
+    // Distinct servers every name is sent to, more than one in consensus mode
+    servers_per_name: usize,
+    next_worker: usize,
 }
 
-impl TriggerTimer {
-    pub fn new(tasks_cnt: usize, worker_qps: usize) -> Self {
-        TriggerTimer {
+impl RateLimiter {
+    pub fn new(worker_qps: usize, servers_per_name: usize) -> Self {
+        RateLimiter {
             handles: vec![],
             worker_qps: worker_qps,
-            triggered: Cell::new(0),
-            tasks_cnt: tasks_cnt,
+            servers_per_name: servers_per_name,
+            next_worker: 0,
         }
     }
 
-    pub fn get_handle(&mut self) -> TriggerRx {
+    pub fn get_handle(&mut self) -> TaskRx {
         let (tx, rx) = future_mpsc::channel(self.worker_qps);
-        self.handles.push(tx);
+        self.handles.push(tx.wait());
         rx
     }
 
-    pub fn thread_main(mut self, dns_list: Vec<SocketAddr>) {
+    /// Send tasks to the workers until the iterator is over, workers finish once handles are dropped
+    pub fn dispatch<I: Iterator<Item = ResolveTask>>(
+        mut self,
+        mut tasks: I,
+        mut upstreams: UpstreamPool,
+        status: StatusTx,
+    ) {
         let duration_second = Duration::from_secs(1);
 
-        while self.triggered.get() < self.tasks_cnt {
+        loop {
             let start = Instant::now();
-            self.trigger_qps(&dns_list);
+            let qps = upstreams.start_second();
+            debug!("Dispatching {} requests", qps);
+            status.send(ResolveStatus::Qps(qps)).unwrap();
+            if !self.dispatch_second(&mut tasks, &mut upstreams, start + duration_second) {
+                break;
+            }
             let end = Instant::now();
 
             let diff = end - start;
@@ -117,46 +152,71 @@ impl TriggerTimer {
         }
     }
 
-    fn trigger_qps(&mut self, dns_list: &[SocketAddr]) {
-        debug!("Triggering {} requests per thread", self.worker_qps);
-
-        // Futures sending request triggers
-        let mut send_list = vec![];
-
-        for i in 0..self.worker_qps {
-            // Round-Robin dns rotation
-            let dns = dns_list[i as usize % dns_list.len()];
-
-            for handle in self.handles.clone() {
-                let future = handle.send(dns).and_then(|_| {
-                    let old_triggered = self.triggered.get();
-                    self.triggered.set(old_triggered + 1);
-                    Ok(())
-                });
-                send_list.push(future);
-            }
+    // Send a second worth of tasks, blocks while workers are busy. False when tasks are over.
+    fn dispatch_second<I: Iterator<Item = ResolveTask>>(
+        &mut self,
+        tasks: &mut I,
+        upstreams: &mut UpstreamPool,
+        deadline: Instant,
+    ) -> bool {
+        while !upstreams.exhausted() {
+            let servers = match upstreams.next_group(self.servers_per_name) {
+                Some(servers) => servers,
+                // Servers with free slots are out of this second's share, the rest are busy
+                None if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                None => break,
+            };
+
+            let task = match tasks.next() {
+                Some(task) => task,
+                None => return false,
+            };
+
+            // Round-Robin worker rotation
+            let worker = self.next_worker;
+            self.next_worker = (worker + 1) % self.handles.len();
+            self.handles[worker].send((task, servers)).unwrap();
         }
 
-        future::join_all(send_list).wait().unwrap();
+        true
     }
 }
 
 struct ResolverThread;
 impl ResolverThread {
-    fn thread_main(tasks: Vec<ResolveTask>, status: StatusTx, task_trigger: TriggerRx, qps: usize) {
+    fn thread_main(
+        task_rx: TaskRx,
+        status: StatusTx,
+        qps: usize,
+        delegations: SharedDelegationCache,
+        upstreams: SharedUpstreams,
+    ) {
         let mut core = Core::new().unwrap();
         let handle = core.handle();
 
-        let future = {
-            let resolver = TrustDNSResolver::new(handle.clone(), status);
+        let consensus = CONFIG.read().unwrap().consensus();
 
-            // Zipping with stream of triggering messages binds each resolve task launch time
-            // to the triggering timer.
-            stream::iter::<_, _, _>(tasks.into_iter().map(Ok))
-                .zip(task_trigger)
-                .map(move |(task, dns)| task.resolve(&resolver, dns).map_err(|_| ()))
+        let future = {
+            let resolver =
+                TrustDNSResolver::new(handle.clone(), status.clone(), delegations, upstreams)
+                    .unwrap_or_else(|err| {
+                        error!("failed to set up the resolver: {}", err);
+                        std::process::exit(1);
+                    });
+
+            // Tasks arrive at the rate limiter pace, each one is bound to the servers it was sent with
+            task_rx
+                .map(move |(task, servers)| {
+                    let quorum = consensus.map_or(1, |consensus| consensus.quorum);
+                    // A failed name is in the failures file already, the worker goes on
+                    task.resolve(&resolver, servers, quorum, &status)
+                        .then(|_| Ok(()))
+                })
                 .buffer_unordered(qps)
-                .collect()
+                .for_each(|_| Ok(()))
         };
 
         core.run(future).unwrap();
@@ -166,28 +226,110 @@ impl ResolverThread {
 #[derive(Clone)]
 pub struct ResolveTask {
     pub tx: ResolvedTx,
+    pub failed_tx: Option<FailedTx>,
+    pub disagreement_tx: Option<DisagreementTx>,
     pub name: String,
     pub qtype: QueryType,
 }
 
 impl ResolveTask {
+    /// Resolve the name and send the response. With several servers the answer is the one at least
+    /// `quorum` of them agree on, or a disagreement is reported. Servers are released once it is done.
     pub fn resolve(
         &self,
         resolver: &TrustDNSResolver,
-        dns: SocketAddr,
+        mut servers: Vec<InFlight>,
+        quorum: usize,
+        status: &StatusTx,
     ) -> Box<Future<Item = (), Error = ResolverError>> {
         let tx = self.tx.clone();
+        let failed_tx = self.failed_tx.clone();
         let name = self.name.clone();
+        let qtype = self.qtype;
+
+        let response: Box<Future<Item = Response, Error = ResolverError>> = if servers.len() == 1 {
+            // The server is released when the name is done
+            let in_flight = servers.remove(0);
+            Box::new(
+                resolver
+                    .resolve(&in_flight, &self.name, self.qtype)
+                    .then(move |rv| {
+                        drop(in_flight);
+                        rv
+                    }),
+            )
+        } else {
+            self.resolve_consensus(resolver, servers, quorum, status.clone())
+        };
 
-        let future = resolver
-            .resolve(dns, &self.name, self.qtype)
-            .and_then(move |results| {
-                for result in results {
-                    tx.send((name.clone(), result)).unwrap()
-                }
-                Ok(())
-            });
+        let future = response.then(move |rv| {
+            if let (Some(reason), Some(failed_tx)) = (failure_reason(&rv), failed_tx) {
+                failed_tx
+                    .send(Failed {
+                        name: name,
+                        qtype: qtype,
+                        reason: reason,
+                    })
+                    .unwrap()
+            }
+            rv.map(|response| tx.send(response).unwrap())
+        });
 
         Box::new(future)
     }
+
+    // Every server is asked in parallel, progress is reported for the name as a whole
+    fn resolve_consensus(
+        &self,
+        resolver: &TrustDNSResolver,
+        servers: Vec<InFlight>,
+        quorum: usize,
+        status: StatusTx,
+    ) -> Box<Future<Item = Response, Error = ResolverError>> {
+        status.send(ResolveStatus::Started).unwrap();
+
+        let votes = servers
+            .into_iter()
+            .map(|in_flight| {
+                let dns = in_flight.server().clone();
+                resolver
+                    .resolve_unreported(&in_flight, &self.name, self.qtype)
+                    .then(move |rv| {
+                        drop(in_flight);
+                        Ok((dns, rv))
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let name = self.name.clone();
+        let qtype = self.qtype;
+        let disagreement_tx = self.disagreement_tx.clone();
+
+        Box::new(future::join_all(votes).map(move |votes| {
+            match consensus::agreed(&votes, quorum) {
+                Some((response, agreed_status)) => {
+                    status.send(agreed_status).unwrap();
+                    response
+                }
+                None => {
+                    debug!("Servers disagree on {} {}", name, qtype);
+                    status.send(ResolveStatus::Disagreement).unwrap();
+                    if let Some(disagreement_tx) = disagreement_tx {
+                        disagreement_tx
+                            .send(consensus::disagreement(&name, qtype, &votes))
+                            .unwrap();
+                    }
+                    consensus::no_consensus(&name, qtype, &votes)
+                }
+            }
+        }))
+    }
+}
+
+// Reason of the failure if the name did not resolve
+fn failure_reason(rv: &Result<Response, ResolverError>) -> Option<&'static str> {
+    match *rv {
+        Ok(ref response) => response.error,
+        Err(ref err) => Some(err.reason()),
+    }
 }
@@ -0,0 +1,677 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use trust_dns::op::ResponseCode;
+
+use config::{AdaptiveRate, NameServer, Upstream};
+use resolve::health::{Health, HealthState, PROBE_QUERIES};
+
+// Response codes telling that the server is overloaded, as timeouts do
+const OVERLOAD_RCODES: &[ResponseCode] = &[ResponseCode::ServFail, ResponseCode::Refused];
+
+// Seconds of outcomes the adaptive rate looks at
+const WINDOW_SECONDS: usize = 10;
+// Outcomes needed in the window before the rate is lowered
+const MIN_SAMPLES: u64 = 20;
+// Share of overload failures the rate is lowered at
+const FAILURE_THRESHOLD: f64 = 0.05;
+// Multiplicative decrease
+const DECREASE_FACTOR: f64 = 0.5;
+// Seconds to get from the floor to the ceiling at weight 1
+const RAMP_SECONDS: f64 = 50.0;
+
+// Counters shared with the workers
+#[derive(Default)]
+struct ServerCounters {
+    // Queries left of this second's share, below zero when retries took more than that
+    budget: AtomicIsize,
+    in_flight: AtomicUsize,
+    // Queries sent and the overloaded ones of them since the last adaptive rate step
+    attempts: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// Configured servers with their health and shares of the current second, shared by
+/// the dispatcher and the resolver threads. Servers are known by their position in the list.
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    counters: Vec<ServerCounters>,
+    health: Health,
+}
+
+pub type SharedUpstreams = Arc<Upstreams>;
+
+impl Upstreams {
+    pub fn shared(upstreams: &[Upstream]) -> SharedUpstreams {
+        let servers = upstreams
+            .iter()
+            .map(|upstream| upstream.server.clone())
+            .collect::<Vec<_>>();
+        Arc::new(Upstreams {
+            upstreams: upstreams.to_vec(),
+            counters: upstreams
+                .iter()
+                .map(|_| ServerCounters::default())
+                .collect(),
+            health: Health::new(&servers),
+        })
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Record a query to the server number `idx` for its health and adaptive rate as soon as it is over:
+    /// `rcode` of the response, none if it timed out or failed on the client side
+    pub fn record(&self, idx: usize, latency: Duration, rcode: Option<ResponseCode>) {
+        let failed = rcode.map_or(true, |rcode| OVERLOAD_RCODES.contains(&rcode));
+        self.health.record(idx, rcode.map(|_| latency), failed);
+
+        let counters = &self.counters[idx];
+        counters.attempts.fetch_add(1, Ordering::SeqCst);
+        if failed {
+            counters.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Server to retry a timed out query to the server number `idx` with: the next one that is
+    /// not ejected and has a slot below its `max_in_flight`, preferring ones with queries left this second
+    pub fn retry(upstreams: &SharedUpstreams, idx: usize) -> Option<InFlight> {
+        let count = upstreams.upstreams.len();
+        let others = || {
+            (1..count)
+                .map(move |offset| (idx + offset) % count)
+                .filter(|&other| !upstreams.health.state(other).is_ejected())
+        };
+
+        others()
+            .filter(|&other| upstreams.budget(other) > 0)
+            .filter_map(|other| Self::take(upstreams, other))
+            .next()
+            .or_else(|| {
+                others()
+                    .filter_map(|other| Self::take(upstreams, other))
+                    .next()
+            })
+    }
+
+    // Slot of the server, none if it has `max_in_flight` names being resolved already
+    fn take(upstreams: &SharedUpstreams, idx: usize) -> Option<InFlight> {
+        let in_flight = upstreams.counters[idx]
+            .in_flight
+            .fetch_add(1, Ordering::SeqCst);
+        // Released on drop whether it is taken or not
+        let slot = InFlight {
+            upstreams: upstreams.clone(),
+            idx: idx,
+        };
+        if upstreams.upstreams[idx]
+            .max_in_flight
+            .map_or(false, |max| in_flight >= max)
+        {
+            return None;
+        }
+        Some(slot)
+    }
+
+    fn has_slot(&self, idx: usize) -> bool {
+        let in_flight = self.counters[idx].in_flight.load(Ordering::SeqCst);
+        self.upstreams[idx]
+            .max_in_flight
+            .map_or(true, |max| in_flight < max)
+    }
+
+    /// Take a query to the server number `idx` from its share. Queries besides the first one
+    /// of a name, i.e. retries, TCP fallbacks and follow-up lookups, may overdraw a spent share,
+    /// the next second gets less then.
+    pub fn charge(&self, idx: usize) {
+        self.counters[idx].budget.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn budget(&self, idx: usize) -> isize {
+        self.counters[idx].budget.load(Ordering::SeqCst)
+    }
+
+    // Share of the new second, less what retries took over the last one
+    fn set_budget(&self, idx: usize, share: u64) {
+        let budget = &self.counters[idx].budget;
+        let mut left = budget.load(Ordering::SeqCst);
+        loop {
+            let next = share as isize + left.min(0);
+            match budget.compare_exchange(left, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(current) => left = current,
+            }
+        }
+    }
+}
+
+/// Slot of a server resolving a name or retrying a query of it, released when dropped
+pub struct InFlight {
+    upstreams: SharedUpstreams,
+    idx: usize,
+}
+
+impl InFlight {
+    /// Position of the server in the list
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    pub fn server(&self) -> &NameServer {
+        &self.upstreams.upstreams[self.idx].server
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.upstreams.counters[self.idx]
+            .in_flight
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Dispatcher's view of a server
+struct UpstreamState {
+    health: HealthState,
+    // Queries planned for the current second
+    share: u64,
+    // Smooth weighted round-robin counter
+    current_weight: i64,
+    // Adaptive queries per second and outcomes of the last seconds as (attempts, failed)
+    rate: f64,
+    window: VecDeque<(u64, u64)>,
+}
+
+// Server QPS cap lowers the ceiling
+fn bounds(upstream: &Upstream, bounds: AdaptiveRate) -> (f64, f64) {
+    let ceiling = upstream
+        .qps
+        .map_or(bounds.ceiling, |cap| cap.min(bounds.ceiling));
+    let floor = bounds.floor.min(ceiling);
+    (f64::from(floor), f64::from(ceiling))
+}
+
+/// Picks servers for queries by their weights within the overall and per-server QPS limits,
+/// skipping servers ejected by the health tracking
+pub struct UpstreamPool {
+    upstreams: SharedUpstreams,
+    servers: Vec<UpstreamState>,
+    qps: u64,
+    adaptive: Option<AdaptiveRate>,
+}
+
+impl UpstreamPool {
+    /// Pool sending `qps` queries per second in total, or adapting per-server rates
+    /// between the `adaptive` bounds starting from their share of `qps`
+    pub fn new(upstreams: SharedUpstreams, qps: u64, adaptive: Option<AdaptiveRate>) -> Self {
+        let mut pool = UpstreamPool {
+            servers: upstreams
+                .upstreams
+                .iter()
+                .map(|_| UpstreamState {
+                    health: HealthState::Healthy,
+                    share: 0,
+                    current_weight: 0,
+                    rate: 0.0,
+                    window: VecDeque::new(),
+                })
+                .collect(),
+            upstreams: upstreams,
+            qps: qps,
+            adaptive: adaptive,
+        };
+
+        if let Some(adaptive) = adaptive {
+            let shares = pool.split_qps();
+            for (idx, share) in shares.into_iter().enumerate() {
+                let (floor, ceiling) = bounds(&pool.upstreams.upstreams[idx], adaptive);
+                pool.servers[idx].rate = (share as f64).max(floor).min(ceiling);
+            }
+        }
+
+        pool
+    }
+
+    /// Set shares of the next second, returns the number of queries planned for it
+    pub fn start_second(&mut self) -> u64 {
+        for (idx, server) in self.servers.iter_mut().enumerate() {
+            server.health = self.upstreams.health.state(idx);
+        }
+
+        let shares = match self.adaptive {
+            Some(adaptive) => (0..self.servers.len())
+                .map(|idx| {
+                    self.adapt(idx, adaptive);
+                    self.servers[idx].rate as u64
+                })
+                .collect(),
+            None => self.split_qps(),
+        };
+
+        let mut planned = 0;
+        for (idx, share) in shares.into_iter().enumerate() {
+            // Ejected servers get nothing, ones on probation only a few probe queries
+            let share = match self.servers[idx].health {
+                HealthState::Ejected(_) => 0,
+                HealthState::Probing => share.min(PROBE_QUERIES),
+                HealthState::Healthy => share,
+            };
+            self.servers[idx].share = share;
+            self.upstreams.set_budget(idx, share);
+            planned += share;
+        }
+
+        debug!(
+            "Queries per server for the next second: {:?}",
+            self.upstreams
+                .upstreams
+                .iter()
+                .enumerate()
+                .map(|(idx, upstream)| (upstream.server.to_string(), self.upstreams.budget(idx)))
+                .collect::<Vec<_>>()
+        );
+
+        planned
+    }
+
+    // AIMD step: halve the rate when too many queries fail in the window, raise it by a step otherwise
+    fn adapt(&mut self, idx: usize, adaptive: AdaptiveRate) {
+        let upstream = &self.upstreams.upstreams[idx];
+        let counters = &self.upstreams.counters[idx];
+        let server = &mut self.servers[idx];
+
+        let attempts = counters.attempts.swap(0, Ordering::SeqCst) as u64;
+        let failed = counters.failed.swap(0, Ordering::SeqCst) as u64;
+        server.window.push_back((attempts, failed));
+        if server.window.len() > WINDOW_SECONDS {
+            server.window.pop_front();
+        }
+
+        let (floor, ceiling) = bounds(upstream, adaptive);
+        let (attempts, failed) = server
+            .window
+            .iter()
+            .fold((0, 0), |(a, f), &(attempts, failed)| {
+                (a + attempts, f + failed)
+            });
+
+        if attempts >= MIN_SAMPLES && failed as f64 / attempts as f64 > FAILURE_THRESHOLD {
+            server.rate = (server.rate * DECREASE_FACTOR).max(floor);
+            // Outcomes at the old rate don't tell about the new one
+            server.window.clear();
+            info!(
+                "{} of {} queries to {} failed, lowering rate to {:.0} qps",
+                failed, attempts, upstream.server, server.rate
+            );
+        } else {
+            let step = (ceiling - floor) / RAMP_SECONDS * f64::from(upstream.weight);
+            server.rate = (server.rate + step.max(1.0)).min(ceiling);
+        }
+    }
+
+    // Split `qps` queries between the servers that are not ejected by weight.
+    // Shares over the server QPS cap go to the other servers, so less may be sent when all are capped.
+    fn split_qps(&self) -> Vec<u64> {
+        let upstreams = &self.upstreams.upstreams;
+        let mut shares = vec![0; upstreams.len()];
+        let mut remaining = self.qps;
+        let mut open = (0..upstreams.len())
+            .filter(|&idx| !self.servers[idx].health.is_ejected())
+            .collect::<Vec<_>>();
+
+        while remaining > 0 && !open.is_empty() {
+            let total_weight = open
+                .iter()
+                .map(|&idx| u64::from(upstreams[idx].weight))
+                .sum::<u64>();
+            let share = |upstream: &Upstream| remaining * u64::from(upstream.weight) / total_weight;
+
+            // Capped servers take what they can and leave the rest to the others
+            let capped = open
+                .iter()
+                .cloned()
+                .filter(|&idx| {
+                    let upstream = &upstreams[idx];
+                    upstream
+                        .qps
+                        .map_or(false, |cap| share(upstream) >= u64::from(cap))
+                })
+                .collect::<Vec<_>>();
+
+            if capped.is_empty() {
+                let open_shares = open
+                    .iter()
+                    .map(|&idx| share(&upstreams[idx]))
+                    .collect::<Vec<_>>();
+                let mut leftover = remaining - open_shares.iter().sum::<u64>();
+                for (&idx, share) in open.iter().zip(open_shares) {
+                    // Rounding leftovers go one by one to the first servers
+                    let extra = if leftover > 0 { 1 } else { 0 };
+                    leftover -= extra;
+                    shares[idx] += share + extra;
+                }
+                break;
+            }
+
+            for &idx in &capped {
+                shares[idx] = u64::from(upstreams[idx].qps.unwrap());
+                remaining -= shares[idx];
+            }
+            open.retain(|idx| !capped.contains(idx));
+        }
+
+        shares
+    }
+
+    /// `count` distinct servers for the next name. The group is smaller only when fewer servers
+    /// get queries this second at all, e.g. while others are ejected. None while fewer of them
+    /// have queries left or any of the picked ones has `max_in_flight` names being resolved.
+    pub fn next_group(&mut self, count: usize) -> Option<Vec<InFlight>> {
+        let upstreams = self.upstreams.clone();
+        let count = self
+            .servers
+            .iter()
+            .filter(|server| server.share > 0)
+            .count()
+            .min(count);
+        let with_budget = (0..self.servers.len())
+            .filter(|&idx| upstreams.budget(idx) > 0)
+            .collect::<Vec<_>>();
+        if count == 0 || with_budget.len() < count {
+            return None;
+        }
+        let mut available = with_budget
+            .into_iter()
+            .filter(|&idx| upstreams.has_slot(idx))
+            .collect::<Vec<_>>();
+        if available.len() < count {
+            return None;
+        }
+
+        let mut picked = Vec::with_capacity(count);
+        for _ in 0..count {
+            let total_weight = available
+                .iter()
+                .map(|&idx| i64::from(upstreams.upstreams[idx].weight))
+                .sum::<i64>();
+
+            for &idx in &available {
+                self.servers[idx].current_weight += i64::from(upstreams.upstreams[idx].weight);
+            }
+            let pos = (0..available.len())
+                .max_by_key(|&pos| self.servers[available[pos]].current_weight)
+                .unwrap();
+            // Picked server is out of the running for the rest of the group
+            let idx = available.remove(pos);
+            self.servers[idx].current_weight -= total_weight;
+            picked.push(idx);
+        }
+
+        // A retry may have taken the last slot meanwhile, the slots taken are released on drop
+        let group = picked
+            .into_iter()
+            .map(|idx| Upstreams::take(&upstreams, idx))
+            .collect::<Option<Vec<_>>>()?;
+        for in_flight in &group {
+            upstreams.charge(in_flight.idx);
+        }
+        Some(group)
+    }
+
+    /// Queries of this second are all sent
+    pub fn exhausted(&self) -> bool {
+        (0..self.servers.len()).all(|idx| self.upstreams.budget(idx) <= 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(addr: &str) -> Upstream {
+        Upstream::new(addr.parse().unwrap())
+    }
+
+    fn pool(upstreams: &[Upstream], qps: u64) -> UpstreamPool {
+        UpstreamPool::new(Upstreams::shared(upstreams), qps, None)
+    }
+
+    fn limited(addr: &str, qps: Option<u32>, weight: u32) -> Upstream {
+        Upstream {
+            qps: qps,
+            weight: weight,
+            ..upstream(addr)
+        }
+    }
+
+    #[test]
+    fn qps_is_split_by_weight() {
+        let pool = pool(
+            &[limited("1.1.1.1", None, 3), limited("8.8.8.8", None, 1)],
+            100,
+        );
+        assert_eq!(pool.split_qps(), vec![75, 25]);
+    }
+
+    #[test]
+    fn capped_server_leaves_the_rest_to_others() {
+        let pool = pool(
+            &[
+                limited("1.1.1.1", Some(10), 1),
+                limited("8.8.8.8", None, 1),
+                limited("9.9.9.9", None, 2),
+            ],
+            100,
+        );
+        assert_eq!(pool.split_qps(), vec![10, 30, 60]);
+    }
+
+    #[test]
+    fn qps_over_every_cap_is_not_sent() {
+        let pool = pool(
+            &[
+                limited("1.1.1.1", Some(10), 1),
+                limited("8.8.8.8", Some(20), 1),
+            ],
+            100,
+        );
+        assert_eq!(pool.split_qps(), vec![10, 20]);
+    }
+
+    #[test]
+    fn rounding_leftovers_go_to_the_first_servers() {
+        let pool = pool(
+            &[
+                upstream("1.1.1.1"),
+                upstream("8.8.8.8"),
+                upstream("9.9.9.9"),
+            ],
+            11,
+        );
+        assert_eq!(pool.split_qps(), vec![4, 4, 3]);
+    }
+
+    #[test]
+    fn every_query_to_a_server_is_charged_but_the_dispatched_one() {
+        let mut pool = pool(&[upstream("1.1.1.1")], 10);
+        pool.start_second();
+        let upstreams = pool.upstreams.clone();
+        drop(pool.next_group(1).unwrap());
+        assert_eq!(upstreams.budget(0), 9);
+        // TCP fallback, CNAME target and DNSSEC lookups of the same name
+        for _ in 0..3 {
+            upstreams.charge(0);
+        }
+        assert_eq!(upstreams.budget(0), 6);
+    }
+
+    #[test]
+    fn group_has_distinct_servers() {
+        let mut pool = pool(
+            &[
+                upstream("1.1.1.1"),
+                upstream("8.8.8.8"),
+                upstream("9.9.9.9"),
+            ],
+            30,
+        );
+        pool.start_second();
+        for _ in 0..10 {
+            let group = pool.next_group(3).unwrap();
+            let mut servers = group
+                .iter()
+                .map(|in_flight| in_flight.idx())
+                .collect::<Vec<_>>();
+            servers.sort();
+            servers.dedup();
+            assert_eq!(servers.len(), 3);
+        }
+        assert!(pool.exhausted());
+    }
+
+    #[test]
+    fn group_shrinks_to_servers_that_are_not_ejected() {
+        let mut pool = pool(&[upstream("1.1.1.1"), upstream("8.8.8.8")], 10);
+        let latency = Duration::from_millis(20);
+        for _ in 0..50 {
+            pool.upstreams.record(1, latency, None);
+        }
+        pool.start_second();
+        // The whole share goes to the server left
+        for _ in 0..10 {
+            assert_eq!(pool.next_group(2).unwrap().len(), 1);
+        }
+        assert!(pool.next_group(2).is_none());
+    }
+
+    #[test]
+    fn group_waits_for_servers_with_spent_shares() {
+        let mut pool = pool(
+            &[limited("1.1.1.1", None, 3), limited("8.8.8.8", None, 1)],
+            8,
+        );
+        pool.start_second();
+        for _ in 0..2 {
+            assert_eq!(pool.next_group(2).unwrap().len(), 2);
+        }
+        // The heavier server has queries left, but a name is never asked from it alone
+        assert_eq!(pool.upstreams.budget(0), 4);
+        assert!(pool.next_group(2).is_none());
+
+        pool.start_second();
+        assert_eq!(pool.next_group(2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn group_waits_for_busy_servers() {
+        let mut servers = [upstream("1.1.1.1"), upstream("8.8.8.8")];
+        servers[1].max_in_flight = Some(1);
+        let mut pool = pool(&servers, 10);
+        pool.start_second();
+
+        let busy = pool.next_group(2).unwrap();
+        assert!(pool.next_group(2).is_none());
+        drop(busy);
+        assert_eq!(pool.next_group(2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retry_takes_next_server_share_and_slot() {
+        let mut servers = [
+            upstream("1.1.1.1"),
+            upstream("8.8.8.8"),
+            upstream("9.9.9.9"),
+        ];
+        servers[1].max_in_flight = Some(1);
+        let mut pool = pool(&servers, 30);
+        pool.start_second();
+        let upstreams = pool.upstreams.clone();
+
+        let first = Upstreams::retry(&upstreams, 0).unwrap();
+        assert_eq!(first.idx(), 1);
+        // The second server is at its max_in_flight now
+        let second = Upstreams::retry(&upstreams, 0).unwrap();
+        assert_eq!(second.idx(), 2);
+        drop(first);
+        assert_eq!(Upstreams::retry(&upstreams, 0).unwrap().idx(), 1);
+    }
+
+    #[test]
+    fn queries_over_the_share_are_taken_from_the_next_second() {
+        let mut pool = pool(&[upstream("1.1.1.1"), upstream("8.8.8.8")], 4);
+        pool.start_second();
+        let upstreams = pool.upstreams.clone();
+        while pool.next_group(1).is_some() {}
+        assert!(pool.exhausted());
+
+        // Shares are spent, a retry goes over
+        let retry = Upstreams::retry(&upstreams, 0).unwrap();
+        upstreams.charge(retry.idx());
+        assert_eq!(upstreams.budget(1), -1);
+        assert_eq!(pool.start_second(), 4);
+        assert_eq!(upstreams.budget(0), 2);
+        assert_eq!(upstreams.budget(1), 1);
+    }
+
+    #[test]
+    fn no_retry_when_others_are_busy() {
+        let mut servers = [upstream("1.1.1.1"), upstream("8.8.8.8")];
+        servers[1].max_in_flight = Some(1);
+        let mut pool = pool(&servers, 10);
+        pool.start_second();
+        let upstreams = pool.upstreams.clone();
+
+        let busy = Upstreams::retry(&upstreams, 0).unwrap();
+        assert!(Upstreams::retry(&upstreams, 0).is_none());
+        drop(busy);
+    }
+
+    #[test]
+    fn servers_giving_up_on_queries_are_ejected() {
+        let upstreams = Upstreams::shared(&[upstream("1.1.1.1"), upstream("8.8.8.8")]);
+        let latency = Duration::from_millis(20);
+        for _ in 0..25 {
+            upstreams.record(0, latency, Some(ResponseCode::ServFail));
+            upstreams.record(0, latency, Some(ResponseCode::Refused));
+            upstreams.record(1, latency, Some(ResponseCode::NXDomain));
+            upstreams.record(1, latency, Some(ResponseCode::NoError));
+        }
+
+        assert!(upstreams.health().state(0).is_ejected());
+        assert_eq!(upstreams.health().state(1), HealthState::Healthy);
+    }
+
+    #[test]
+    fn adaptive_rate_follows_every_query() {
+        let adaptive = AdaptiveRate {
+            floor: 10,
+            ceiling: 1000,
+        };
+        let upstreams = Upstreams::shared(&[upstream("1.1.1.1"), upstream("8.8.8.8")]);
+        let mut pool = UpstreamPool::new(upstreams.clone(), 200, Some(adaptive));
+        pool.start_second();
+        let rate = pool.servers[0].rate;
+
+        // Timeouts and overload answers count as soon as the query is over, not the name
+        let latency = Duration::from_millis(20);
+        for _ in 0..15 {
+            upstreams.record(0, latency, Some(ResponseCode::NoError));
+        }
+        for _ in 0..3 {
+            upstreams.record(0, latency, None);
+        }
+        for _ in 0..2 {
+            upstreams.record(0, latency, Some(ResponseCode::ServFail));
+        }
+        for _ in 0..20 {
+            upstreams.record(1, latency, Some(ResponseCode::NXDomain));
+        }
+        pool.start_second();
+
+        assert_eq!(pool.servers[0].rate, rate * DECREASE_FACTOR);
+        assert!(pool.servers[1].rate > rate);
+    }
+}
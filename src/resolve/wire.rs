@@ -0,0 +1,694 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::{Handle, Timeout};
+
+use trust_dns::client::{ClientHandle, ClientStreamHandle};
+use trust_dns::error::{ClientError, ClientErrorKind};
+use trust_dns::op::{Edns, Header, Message, Query, ResponseCode};
+use trust_dns::rr::rdata::NULL;
+use trust_dns::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns::serialize::binary::{BinDecoder, BinEncoder, BinSerializable};
+
+use resolve::query_type::QueryType;
+
+const HEADER_LEN: usize = 12;
+
+/// Time to wait for a response, same as the trust-dns clients do
+const QUERY_TIMEOUT: u64 = 5;
+
+const SIG: u16 = 24;
+const DS: u16 = 43;
+const RRSIG: u16 = 46;
+const NSEC: u16 = 47;
+const DNSKEY: u16 = 48;
+const NSEC3: u16 = 50;
+const NSEC3PARAM: u16 = 51;
+
+// DNSSEC algorithms, DS digest types and NSEC3 hash algorithms the DNS client can decode
+const ALGORITHMS: &[u8] = &[5, 7, 8, 10, 13, 14, 15];
+const DIGEST_TYPES: &[u8] = &[1, 2, 4, 5];
+const NSEC3_SHA1: u8 = 1;
+
+type Response = oneshot::Sender<Result<Message, ClientError>>;
+
+/// Handle of a DNS client made by `make_wire_client`
+#[derive(Clone)]
+pub struct WireClientHandle {
+    requests: mpsc::UnboundedSender<(Message, Option<QueryType>, Response)>,
+}
+
+impl WireClientHandle {
+    /// Send `message` with `query_type` in its question, whether the DNS client has a
+    /// `RecordType` for it or not
+    pub fn send_query(
+        &mut self,
+        message: Message,
+        query_type: QueryType,
+    ) -> Box<Future<Item = Message, Error = ClientError>> {
+        self.request(message, Some(query_type))
+    }
+
+    fn request(
+        &mut self,
+        message: Message,
+        query_type: Option<QueryType>,
+    ) -> Box<Future<Item = Message, Error = ClientError>> {
+        let (response_tx, response) = oneshot::channel();
+        // Request is dropped with the client gone, which cancels the response
+        self.requests
+            .unbounded_send((message, query_type, response_tx))
+            .unwrap_or(());
+
+        Box::new(
+            response
+                .map_err(ClientError::from)
+                .and_then(|result| result),
+        )
+    }
+}
+
+impl ClientHandle for WireClientHandle {
+    fn send(&mut self, message: Message) -> Box<Future<Item = Message, Error = ClientError>> {
+        self.request(message, None)
+    }
+}
+
+/// DNS client over `stream` that can query and read back types it has no `RecordType` for.
+///
+/// Records the client can not decode come back as they were received, with their names
+/// uncompressed: as type 0 records with the type code prepended to their data, see `opaque`.
+/// These are records of unknown types, as well as signatures over them and NSEC and NSEC3
+/// records listing them.
+pub fn make_wire_client<S>(
+    stream: Box<Future<Item = S, Error = io::Error>>,
+    stream_handle: Box<ClientStreamHandle>,
+    loop_handle: Handle,
+) -> WireClientHandle
+where
+    S: Stream<Item = Vec<u8>, Error = io::Error> + 'static,
+{
+    let (requests_tx, requests) = mpsc::unbounded();
+
+    let handle = loop_handle.clone();
+    let client = stream.and_then(move |stream| WireClient {
+        stream: stream,
+        stream_handle: stream_handle,
+        loop_handle: handle,
+        requests: requests,
+        active: HashMap::new(),
+        ids: RandomState::new(),
+        sent: 0,
+    });
+    loop_handle.spawn(client.map_err(|e| debug!("DNS client closed: {}", e)));
+
+    WireClientHandle {
+        requests: requests_tx,
+    }
+}
+
+/// Type code and data of a record the DNS client could not decode
+pub fn opaque(record: &Record) -> Option<(u16, &[u8])> {
+    match *record.rdata() {
+        RData::NULL(ref null) if record.rr_type() == RecordType::NULL => match null.anything() {
+            Some(data) if data.len() >= 2 => Some((read_u16(data, 0)?, &data[2..])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Record data in the wire format without name compression: as received for the records
+/// the DNS client could not decode, encoded anew for the rest
+pub fn record_data(record: &Record) -> Option<Vec<u8>> {
+    if let Some((_, data)) = opaque(record) {
+        return Some(data.to_vec());
+    }
+
+    let mut data = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut data);
+        encoder.set_canonical_names(true);
+        record.rdata().emit(&mut encoder).ok()?;
+    }
+    Some(data)
+}
+
+// Queries sent and responses matched to them by id
+struct WireClient<S> {
+    stream: S,
+    stream_handle: Box<ClientStreamHandle>,
+    loop_handle: Handle,
+    requests: mpsc::UnboundedReceiver<(Message, Option<QueryType>, Response)>,
+    active: HashMap<u16, (Response, Timeout)>,
+    ids: RandomState,
+    sent: u64,
+}
+
+impl<S> WireClient<S> {
+    // Random id no other query waits for, so responses can't be spoofed by guessing it
+    fn next_id(&mut self) -> u16 {
+        loop {
+            let mut hasher = self.ids.build_hasher();
+            hasher.write_u64(self.sent);
+            self.sent += 1;
+            let id = hasher.finish() as u16;
+            if !self.active.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    fn send(
+        &mut self,
+        mut message: Message,
+        query_type: Option<QueryType>,
+        response: Response,
+    ) -> io::Result<()> {
+        let id = self.next_id();
+        message.set_id(id);
+        let query =
+            message
+                .to_vec()
+                .map_err(ClientError::from)
+                .and_then(|query| match query_type {
+                    Some(query_type) => with_query_type(query, query_type).ok_or_else(|| {
+                        ClientErrorKind::Msg(format!("no question to send as {}", query_type))
+                            .into()
+                    }),
+                    None => Ok(query),
+                });
+        let query = match query {
+            Ok(query) => query,
+            Err(e) => {
+                response.send(Err(e)).unwrap_or(());
+                return Ok(());
+            }
+        };
+
+        let timeout = Timeout::new(Duration::from_secs(QUERY_TIMEOUT), &self.loop_handle)?;
+        self.stream_handle.send(query)?;
+        self.active.insert(id, (response, timeout));
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &[u8]) {
+        let message = match decode(response) {
+            Some(message) => message,
+            None => return debug!("undecodable response of {} bytes", response.len()),
+        };
+        match self.active.remove(&message.id()) {
+            Some((response, _)) => response.send(Ok(message)).unwrap_or(()),
+            None => debug!("unexpected response id: {}", message.id()),
+        }
+    }
+
+    // Queries nobody waits for any more and the ones that timed out
+    fn drop_expired(&mut self) {
+        let expired = self
+            .active
+            .iter_mut()
+            .filter_map(|(&id, &mut (ref mut response, ref mut timeout))| {
+                match (response.poll_cancel(), timeout.poll()) {
+                    (Ok(Async::NotReady), Ok(Async::NotReady)) => None,
+                    _ => Some(id),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            if let Some((response, _)) = self.active.remove(&id) {
+                response
+                    .send(Err(ClientErrorKind::Timeout.into()))
+                    .unwrap_or(());
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = Vec<u8>, Error = io::Error>> Future for WireClient<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut closed = false;
+        loop {
+            match self.requests.poll() {
+                Ok(Async::Ready(Some((message, query_type, response)))) => {
+                    self.send(message, query_type, response)?
+                }
+                Ok(Async::Ready(None)) => {
+                    closed = true;
+                    break;
+                }
+                Ok(Async::NotReady) | Err(()) => break,
+            }
+        }
+
+        while let Async::Ready(Some(response)) = self.stream.poll()? {
+            self.receive(&response);
+        }
+
+        // Timeouts are polled last, so the ones of the queries just sent wake the client up
+        self.drop_expired();
+
+        // Done with all handles dropped and every query answered
+        if closed && self.active.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+// Types `RecordType::from_u16` knows, less its NULL that is type 0 rather than NULL type 10
+const DECODABLE: &[u16] = &[
+    1, 2, 5, 6, 12, 15, 16, SIG, 25, 28, 33, 41, 43, RRSIG, NSEC, 48, NSEC3, 51, 252, 255,
+];
+
+/// Type the DNS client can encode and decode
+pub fn decodable(code: u16) -> bool {
+    DECODABLE.contains(&code)
+}
+
+// Query with the type of its question set to `query_type`
+fn with_query_type(mut query: Vec<u8>, query_type: QueryType) -> Option<Vec<u8>> {
+    let at = skip_name(&query, HEADER_LEN)?;
+    let code = query_type.code();
+    query
+        .get_mut(at..at + 2)?
+        .copy_from_slice(&[(code >> 8) as u8, code as u8]);
+    Some(query)
+}
+
+// Response with the records the DNS client can not decode kept as they came, see `opaque`
+fn decode(response: &[u8]) -> Option<Message> {
+    let header = Header::read(&mut BinDecoder::new(response)).ok()?;
+    // Low bits of the response code the DNS client has no `ResponseCode` for
+    if header.response_code() > 10 {
+        return None;
+    }
+
+    let mut message = Message::new();
+    message
+        .set_id(header.id())
+        .set_message_type(header.message_type())
+        .set_op_code(header.op_code())
+        .set_authoritative(header.authoritative())
+        .set_truncated(header.truncated())
+        .set_recursion_desired(header.recursion_desired())
+        .set_recursion_available(header.recursion_available())
+        .set_authentic_data(header.authentic_data())
+        .set_checking_disabled(header.checking_disabled())
+        .set_response_code(ResponseCode::from(0, header.response_code()));
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..header.query_count() {
+        let (query, next) = read_query(response, pos)?;
+        message.add_query(query);
+        pos = next;
+    }
+
+    let sections = [
+        header.answer_count(),
+        header.name_server_count(),
+        header.additional_count(),
+    ];
+    for (section, &count) in sections.iter().enumerate() {
+        for _ in 0..count {
+            let (record, next) = read_record(response, pos)?;
+            pos = next;
+            match section {
+                0 => message.add_answer(record),
+                1 => message.add_name_server(record),
+                _ if record.rr_type() == RecordType::OPT => message.set_edns(Edns::from(&record)),
+                _ => message.add_additional(record),
+            };
+        }
+    }
+
+    Some(message)
+}
+
+fn read_query(message: &[u8], start: usize) -> Option<(Query, usize)> {
+    let mut name = Vec::new();
+    let pos = read_name(message, start, &mut name)?;
+    let fixed = message.get(pos..pos + 4)?;
+    let code = read_u16(fixed, 0)?;
+
+    let mut query = Query::new();
+    query
+        .set_name(Name::read(&mut BinDecoder::new(&name)).ok()?)
+        .set_query_type(
+            QueryType::from(code)
+                .record_type()
+                .unwrap_or(RecordType::NULL),
+        )
+        .set_query_class(dns_class(read_u16(fixed, 2)?)?);
+    Some((query, pos + 4))
+}
+
+// Record decoded by the DNS client, or kept as it came when the client can not decode it
+fn read_record(message: &[u8], start: usize) -> Option<(Record, usize)> {
+    let mut owner = Vec::new();
+    let pos = read_name(message, start, &mut owner)?;
+    // Type, class, TTL and data length
+    let fixed = message.get(pos..pos + 10)?;
+    let rtype = read_u16(fixed, 0)?;
+    let end = pos + 10 + read_u16(fixed, 8)? as usize;
+    if end > message.len() {
+        return None;
+    }
+
+    let mut data = Vec::new();
+    uncompressed_rdata(message, pos + 10, end, rtype, &mut data)?;
+    if data.len() > u16::max_value() as usize {
+        return None;
+    }
+
+    if decodable(rtype) && decodable_rdata(rtype, &data) {
+        let mut wire = owner.clone();
+        wire.extend_from_slice(&fixed[..8]);
+        wire.extend_from_slice(&[(data.len() >> 8) as u8, data.len() as u8]);
+        wire.extend_from_slice(&data);
+        if let Ok(record) = Record::read(&mut BinDecoder::new(&wire)) {
+            return Some((record, end));
+        }
+    }
+
+    let mut opaque = fixed[..2].to_vec();
+    opaque.extend_from_slice(&data);
+    let mut record = Record::new();
+    record
+        .set_name(Name::read(&mut BinDecoder::new(&owner)).ok()?)
+        .set_rr_type(RecordType::NULL)
+        .set_dns_class(dns_class(read_u16(fixed, 2)?)?)
+        .set_ttl((read_u16(fixed, 4)? as u32) << 16 | read_u16(fixed, 6)? as u32)
+        .set_rdata(RData::NULL(NULL::with(opaque)));
+    Some((record, end))
+}
+
+// Classes `DNSClass::from_u16` knows
+fn dns_class(code: u16) -> Option<DNSClass> {
+    match code {
+        1 => Some(DNSClass::IN),
+        3 => Some(DNSClass::CH),
+        4 => Some(DNSClass::HS),
+        254 => Some(DNSClass::NONE),
+        255 => Some(DNSClass::ANY),
+        _ => None,
+    }
+}
+
+// Record data of a decodable type refers only to types and algorithms the DNS client knows
+fn decodable_rdata(rtype: u16, data: &[u8]) -> bool {
+    let known_types = |bitmap: Option<&[u8]>| {
+        bitmap
+            .and_then(bitmap_types)
+            .map_or(false, |types| types.into_iter().all(decodable))
+    };
+
+    match rtype {
+        SIG | RRSIG => {
+            read_u16(data, 0).map_or(false, decodable)
+                && data.get(2).map_or(false, |alg| ALGORITHMS.contains(alg))
+        }
+        DNSKEY => data.get(3).map_or(false, |alg| ALGORITHMS.contains(alg)),
+        DS => {
+            data.get(2).map_or(false, |alg| ALGORITHMS.contains(alg))
+                && data
+                    .get(3)
+                    .map_or(false, |digest| DIGEST_TYPES.contains(digest))
+        }
+        NSEC => known_types(skip_name(data, 0).and_then(|at| data.get(at..))),
+        NSEC3 => data.first() == Some(&NSEC3_SHA1) && known_types(nsec3_bitmap(data)),
+        NSEC3PARAM => data.first() == Some(&NSEC3_SHA1),
+        _ => true,
+    }
+}
+
+/// Type bitmap of NSEC3 record data
+pub fn nsec3_bitmap(data: &[u8]) -> Option<&[u8]> {
+    // Hash algorithm, flags, iterations, salt and next hashed owner name
+    let salt_len = *data.get(4)? as usize;
+    let hash_len = *data.get(5 + salt_len)? as usize;
+    data.get(6 + salt_len + hash_len..)
+}
+
+/// Type codes of an NSEC or NSEC3 type bitmap
+pub fn bitmap_types(bitmap: &[u8]) -> Option<Vec<u16>> {
+    let mut types = Vec::new();
+    let mut pos = 0;
+    while pos < bitmap.len() {
+        let window = *bitmap.get(pos)? as u16;
+        let len = *bitmap.get(pos + 1)? as usize;
+        let bits = bitmap.get(pos + 2..pos + 2 + len)?;
+        for (i, byte) in bits.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(window << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+    Some(types)
+}
+
+// Octets before the names in the record data and the number of names, for the types with
+// names that may be compressed (RFC 3597 section 4)
+fn name_layout(rtype: u16) -> (usize, usize) {
+    match rtype {
+        // NS, MD, MF, CNAME, MB, MG, MR, PTR, NXT, DNAME, NSEC
+        2 | 3 | 4 | 5 | 7 | 8 | 9 | 12 | 30 | 39 | NSEC => (0, 1),
+        // SOA, MINFO, RP
+        6 | 14 | 17 => (0, 2),
+        // MX, AFSDB, RT, KX
+        15 | 18 | 21 | 36 => (2, 1),
+        // PX
+        26 => (2, 2),
+        // SRV
+        33 => (6, 1),
+        SIG | RRSIG => (18, 1),
+        _ => (0, 0),
+    }
+}
+
+// Record data with its names uncompressed, the rest of it as it is
+fn uncompressed_rdata(
+    message: &[u8],
+    start: usize,
+    end: usize,
+    rtype: u16,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let (prefix, names) = name_layout(rtype);
+    out.extend_from_slice(message.get(start..start + prefix)?);
+    let mut pos = start + prefix;
+    for _ in 0..names {
+        pos = read_name(message, pos, out)?;
+    }
+    out.extend_from_slice(message.get(pos..end)?);
+    Some(())
+}
+
+/// Big endian `u16` at `at`
+pub fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    let bytes = bytes.get(at..at + 2)?;
+    Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+/// Position after an uncompressed name
+pub fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// Name at `start` appended to `out` without compression, returns the position after it
+fn read_name(message: &[u8], start: usize, out: &mut Vec<u8>) -> Option<usize> {
+    const MAX_NAME_LEN: usize = 255;
+
+    let mut pos = start;
+    let mut end = None;
+    let mut name_len = 0;
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len & 0xc0 {
+            0xc0 => {
+                let target = (len & 0x3f) << 8 | *message.get(pos + 1)? as usize;
+                // Pointers only lead back, so following them comes to an end
+                if target >= pos {
+                    return None;
+                }
+                end = end.or(Some(pos + 2));
+                pos = target;
+            }
+            0 => {
+                let label = message.get(pos..pos + 1 + len)?;
+                name_len += label.len();
+                if name_len > MAX_NAME_LEN {
+                    return None;
+                }
+                out.extend_from_slice(label);
+                pos += label.len();
+                if len == 0 {
+                    return Some(end.unwrap_or(pos));
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+    use trust_dns::udp::UdpClientStream;
+
+    // Response to `example.com. HTTPS` with the answer owner compressed to the question name
+    fn https_response() -> Vec<u8> {
+        let mut response = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x07example\x03com\x00");
+        response.extend_from_slice(&[0, 65, 0, 1]);
+        response.extend_from_slice(&[0xc0, 12, 0, 65, 0, 1, 0, 0, 0x0e, 0x10, 0, 3, 0, 1, 0]);
+        response
+    }
+
+    #[test]
+    fn query_type_goes_in_the_question() {
+        let mut message = Message::new();
+        let mut query = Query::new();
+        query
+            .set_name(Name::parse("example.com.", None).unwrap())
+            .set_query_type(RecordType::NULL);
+        message.add_query(query);
+
+        let query = with_query_type(message.to_vec().unwrap(), QueryType::from(257)).unwrap();
+        assert_eq!(query[HEADER_LEN + 13..HEADER_LEN + 17], [1, 1, 0, 1]);
+        assert_eq!(
+            with_query_type(vec![0; HEADER_LEN], QueryType::from(257)),
+            None
+        );
+    }
+
+    #[test]
+    fn queries_of_unknown_types_are_answered() {
+        // Server answers every query with a record of the type asked
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            let mut response = buf[..len].to_vec();
+            response[2] |= 0x80;
+            response[6..8].copy_from_slice(&[0, 1]);
+            response[10..12].copy_from_slice(&[0, 0]);
+            response.truncate(HEADER_LEN + 17);
+            let rtype = [response[HEADER_LEN + 13], response[HEADER_LEN + 14]];
+            response.extend_from_slice(&[0xc0, 12, rtype[0], rtype[1], 0, 1, 0, 0, 0, 60, 0, 1, 7]);
+            server.send_to(&response, client).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let (stream, stream_handle) = UdpClientStream::new(addr, core.handle());
+        let mut client = make_wire_client(stream, stream_handle, core.handle());
+
+        let mut query = Query::new();
+        query.set_name(Name::parse("example.com.", None).unwrap());
+        let mut message = Message::new();
+        message.add_query(query);
+        let response = core
+            .run(client.send_query(message, QueryType::from(65)))
+            .unwrap();
+
+        assert_eq!(opaque(&response.answers()[0]), Some((65, &[7][..])));
+    }
+
+    #[test]
+    fn records_of_unknown_types_keep_their_code_and_data() {
+        let message = decode(&https_response()).unwrap();
+
+        assert_eq!(message.id(), 0x1234);
+        let record = &message.answers()[0];
+        assert_eq!(*record.name(), Name::parse("example.com.", None).unwrap());
+        assert_eq!(record.ttl(), 3600);
+        assert_eq!(opaque(record), Some((65, &[0, 1, 0][..])));
+        assert_eq!(record_data(record), Some(vec![0, 1, 0]));
+        assert_eq!(message.queries()[0].query_type(), RecordType::NULL);
+    }
+
+    #[test]
+    fn compressed_names_in_record_data_are_expanded() {
+        let mut response = vec![0, 1, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x07example\x03com\x00\x00\x0f\x00\x01");
+        // MX 10 mail.example.com. with the exchange compressed
+        response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 0, 60, 0, 9, 0, 10]);
+        response.extend_from_slice(b"\x04mail\xc0\x0c");
+
+        let message = decode(&response).unwrap();
+        match *message.answers()[0].rdata() {
+            RData::MX(ref mx) => assert_eq!(mx.exchange().to_string(), "mail.example.com."),
+            ref rdata => panic!("unexpected {:?}", rdata),
+        }
+    }
+
+    #[test]
+    fn dnssec_records_over_unknown_types_are_kept_as_they_came() {
+        let mut response = vec![0, 1, 0x81, 0x80, 0, 1, 0, 0, 0, 1, 0, 0];
+        response.extend_from_slice(b"\x07example\x03com\x00\x00\x2f\x00\x01");
+        // NSEC to next.example.com. with A, RRSIG, NSEC and CAA (257) in windows 0 and 1
+        let bitmap = [0, 6, 0x40, 0, 0, 0, 0, 0x03, 1, 1, 0x40];
+        response.extend_from_slice(&[0xc0, 12, 0, 47, 0, 1, 0, 0, 0, 60, 0, 7 + 11]);
+        response.extend_from_slice(b"\x04next\xc0\x0c");
+        response.extend_from_slice(&bitmap);
+
+        let message = decode(&response).unwrap();
+        let nsec = &message.name_servers()[0];
+        let mut data = b"\x04next\x07example\x03com\x00".to_vec();
+        data.extend_from_slice(&bitmap);
+        assert_eq!(opaque(nsec), Some((47, &data[..])));
+        assert_eq!(bitmap_types(&bitmap), Some(vec![1, 46, 47, 257]));
+        assert_eq!(bitmap_types(&[0, 6, 0x40]), None);
+
+        // Signatures over such types keep the type they cover
+        let mut rrsig = vec![1, 1, 8, 2, 0, 0, 0, 60, 0, 0, 0, 2, 0, 0, 0, 1, 0x12, 0x34];
+        rrsig.extend_from_slice(b"\x07example\x03com\x00\xab");
+        assert!(!decodable_rdata(RRSIG, &rrsig));
+        rrsig[0] = 0;
+        rrsig[1] = 28;
+        assert!(decodable_rdata(RRSIG, &rrsig));
+        // Algorithm 16 is not known either
+        rrsig[2] = 16;
+        assert!(!decodable_rdata(RRSIG, &rrsig));
+    }
+
+    #[test]
+    fn pointer_loops_and_truncated_messages_are_not_decoded() {
+        let mut response = https_response();
+        let len = response.len();
+        response[len - 15] = 0xc0;
+        response[len - 14] = (len - 15) as u8;
+        assert!(decode(&response).is_none());
+
+        let response = https_response();
+        assert!(decode(&response[..response.len() - 1]).is_none());
+    }
+}